@@ -0,0 +1,32 @@
+//! Unpacking of decrypted frames, whole as `pre_process_data` takes them and in pieces as
+//! `PackDecoder` gets them from the direct TUN stream.
+
+#![no_main]
+
+use actor_fuzz::data_pack;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let data_pack = data_pack();
+    let whole = data_pack.pre_process_data(data);
+
+    // split where the first byte says, the decoder has to resume inside any field
+    let split = data.first().map_or(0, |&at| at as usize).min(data.len());
+    let mut decoder = data_pack.decoder();
+    let mut pieces = Vec::new();
+    for chunk in [&data[..split], &data[split..]] {
+        match decoder.push(chunk) {
+            Ok(packets) => pieces.extend(packets),
+            Err(_) => {
+                assert_eq!(decoder.pending(), 0, "decoder kept data after an error");
+                assert!(whole.is_err(), "pieces failed where the whole frame decoded");
+                return;
+            }
+        }
+        assert!(decoder.pending() <= data.len());
+    }
+    if let Ok(packets) = whole {
+        assert_eq!(decoder.pending(), 0);
+        assert_eq!(packets, pieces, "pieces decoded differently from the whole frame");
+    }
+});
@@ -0,0 +1,23 @@
+//! Opening of sealed frames and the system packets carried inside them, with the server's
+//! keys of a session the corpus generator seals frames for.
+
+#![no_main]
+
+use actor::operational::frame_cipher::FrameRole;
+use actor_fuzz::{cipher, data_pack};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut server = cipher(FrameRole::Server);
+    if let Ok(payload) = server.open(data) {
+        assert!(payload.len() <= data.len());
+        let _ = data_pack().pre_process_data(&payload);
+    }
+    let _ = server.handle_system(data);
+
+    // whatever the client seals has to come back out unchanged
+    let mut client = cipher(FrameRole::Client);
+    let sealed = client.seal(data).expect("Failed to seal the frame");
+    let mut server = cipher(FrameRole::Server);
+    assert_eq!(server.open(&sealed).expect("Failed to open a sealed frame"), data);
+});
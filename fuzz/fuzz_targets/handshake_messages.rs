@@ -0,0 +1,68 @@
+//! Handshake and registration messages as either side receives them, through the checks
+//! that run on their fields before anything is trusted. The first byte picks the message.
+
+#![no_main]
+
+use actor::handlers::actor_structure_type::{
+    HandshakeComplete, HandshakeFinish, HandshakeInit, HandshakeResponse, NetworkOptions,
+    RegisterHandlerAnswer, RegisterHandlerRequest,
+};
+use actor::operational::frame_cipher::open_message;
+use actor::util::handshake::{transcript, verify_identity, verify_proof, EphemeralKey};
+use actor_fuzz::session_keys;
+use libfuzzer_sys::fuzz_target;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tfserver::structures::s_type;
+
+fn ephemeral() -> EphemeralKey {
+    EphemeralKey::generate().expect("Failed to generate an ephemeral key")
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&message, body)) = data.split_first() else {
+        return;
+    };
+    match message % 6 {
+        0 => {
+            if let Ok(init) = s_type::from_slice::<HandshakeInit>(body) {
+                let ephemeral = ephemeral();
+                let _ = ephemeral.diffie_hellman(&init.ephemeral);
+                let _ = transcript(&init.username, &init.ephemeral, &ephemeral.public_b64, "");
+            }
+        }
+        1 => {
+            if let Ok(response) = s_type::from_slice::<HandshakeResponse>(body) {
+                let ephemeral = ephemeral();
+                let _ = ephemeral.diffie_hellman(&response.ephemeral);
+                let transcript = transcript("fuzz", &ephemeral.public_b64, &response.ephemeral, &response.salt);
+                verify_identity(&response.ephemeral, &transcript, &response.signature);
+            }
+        }
+        2 => {
+            if let Ok(finish) = s_type::from_slice::<HandshakeFinish>(body) {
+                verify_proof(&[0; 32], b"fuzz", &finish.proof);
+            }
+            if let Ok(complete) = s_type::from_slice::<HandshakeComplete>(body) {
+                verify_proof(&[0; 32], b"fuzz", &complete.proof);
+            }
+        }
+        // registration messages arrive sealed, the input goes through opening them and, as
+        // the plaintext a peer holding the keys could send, through parsing
+        3 => {
+            let _ = open_message(&session_keys().client_to_server, body);
+            if let Ok(request) = s_type::from_slice::<RegisterHandlerRequest>(body) {
+                let _ = request.requested_ipv4.map(|ip| ip.parse::<Ipv4Addr>());
+                let _ = request.requested_ipv6.map(|ip| ip.parse::<Ipv6Addr>());
+            }
+        }
+        4 => {
+            let _ = open_message(&session_keys().server_to_client, body);
+            if let Ok(answer) = s_type::from_slice::<RegisterHandlerAnswer>(body) {
+                let _ = NetworkOptions::decode(&answer.network);
+            }
+        }
+        _ => {
+            let _ = NetworkOptions::decode(body);
+        }
+    }
+});
@@ -0,0 +1,13 @@
+//! Header parsing of packets read from the TUN device or unpacked from a peer's frame.
+
+#![no_main]
+
+use actor::operational::tun_interface::TunInterface;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(meta) = TunInterface::extract_general_ip_header(data) {
+        assert_eq!(meta.version, data[0] >> 4);
+        assert_eq!(meta.identification.is_some(), meta.version == 4);
+    }
+});
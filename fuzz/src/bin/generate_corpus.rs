@@ -0,0 +1,184 @@
+//! Writes seed inputs for every target into `fuzz/corpus/<target>/`, built the way the tunnel
+//! builds them: frames from `post_process_data`, sealed by a client cipher, and handshake
+//! messages encoded as the handlers send them.
+
+use actor::handlers::actor_structure_type::{
+    ActorStructureType, HandshakeFinish, HandshakeInit, HandshakeResponse, NetworkOptions,
+    RegisterHandlerAnswer, RegisterHandlerRequest, NETWORK_OPTIONS_VERSION,
+};
+use actor::operational::frame_cipher::{FrameRole, RekeyMessage};
+use actor::operational::tun_interface::IpPacket;
+use actor::util::handshake::{generate_salt, EphemeralKey};
+use actor_fuzz::{cipher, config, data_pack, TARGETS};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use tfserver::bincode;
+use tfserver::structures::s_type;
+use tfserver::structures::s_type::BINCODE_CFG;
+
+fn main() -> io::Result<()> {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+    for target in TARGETS {
+        fs::create_dir_all(corpus.join(target))?;
+    }
+
+    let packets = ip_packets();
+    let frames = frames(&packets);
+    write_seeds(&corpus.join("ip_header"), &packets)?;
+    write_seeds(&corpus.join("data_pack"), &frames)?;
+
+    let mut client = cipher(FrameRole::Client);
+    let sealed: Vec<Vec<u8>> = frames
+        .iter()
+        .map(|frame| client.seal(frame).expect("Failed to seal a frame"))
+        .collect();
+    write_seeds(&corpus.join("frame_cipher"), &sealed)?;
+
+    write_seeds(&corpus.join("handshake_messages"), &handshake_messages())?;
+    println!("Seed corpora written to {}", corpus.display());
+    Ok(())
+}
+
+fn write_seeds(dir: &Path, seeds: &[Vec<u8>]) -> io::Result<()> {
+    for (n, seed) in seeds.iter().enumerate() {
+        fs::write(dir.join(format!("seed-{}", n)), seed)?;
+    }
+    Ok(())
+}
+
+/// A UDP datagram over IPv4 and over IPv6, and an empty IPv4 packet.
+fn ip_packets() -> Vec<Vec<u8>> {
+    let payload = b"\x30\x39\x00\x35\x00\x0c\x00\x00ping";
+    vec![
+        ipv4(Ipv4Addr::new(10, 0, 8, 2), Ipv4Addr::new(10, 0, 8, 1), 17, payload),
+        ipv6(
+            "2001:db8:3333:4444::2".parse().unwrap(),
+            "2001:db8:3333:4444::1".parse().unwrap(),
+            17,
+            payload,
+        ),
+        ipv4(Ipv4Addr::new(10, 0, 8, 2), Ipv4Addr::new(192, 168, 50, 1), 6, &[]),
+    ]
+}
+
+fn ipv4(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 0, 0x12, 0x34, 0x40, 0, 64, protocol, 0, 0];
+    packet[2..4].copy_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    let checksum = !packet
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .fold(0u32, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xffff) + (sum >> 16)
+        }) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv6(source: Ipv6Addr, destination: Ipv6Addr, next_header: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[next_header, 64]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Frames as `post_process_data` packs them: each packet alone, all of them together, a frame
+/// of garbage only and one carrying a rekey request.
+fn frames(packets: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let data_pack = data_pack();
+    let ip_packet = |data: &Vec<u8>| IpPacket {
+        meta: None,
+        data: data.clone(),
+    };
+    let rekey = RekeyMessage::Request {
+        epoch: 1,
+        ephemeral: EphemeralKey::generate().expect("Failed to generate an ephemeral key").public_b64,
+    };
+    let rekey = bincode::serde::encode_to_vec(&rekey, BINCODE_CFG).expect("Failed to encode the rekey request");
+
+    let mut inputs: Vec<(Vec<IpPacket>, Vec<Vec<u8>>)> = packets
+        .iter()
+        .map(|packet| (vec![ip_packet(packet)], Vec::new()))
+        .collect();
+    inputs.push((packets.iter().map(ip_packet).collect(), Vec::new()));
+    inputs.push((Vec::new(), Vec::new()));
+    inputs.push((vec![ip_packet(&packets[0])], vec![rekey]));
+    inputs
+        .into_iter()
+        .map(|(packets, system_packets)| {
+            data_pack
+                .post_process_data(packets, system_packets)
+                .expect("Failed to pack a frame")
+        })
+        .collect()
+}
+
+/// Every message either side receives, prefixed with the byte the target selects it by.
+fn handshake_messages() -> Vec<Vec<u8>> {
+    let ephemeral = EphemeralKey::generate().expect("Failed to generate an ephemeral key");
+    let network = NetworkOptions {
+        version: NETWORK_OPTIONS_VERSION,
+        ipv4_prefix_len: 24,
+        gateway_ipv4: Ipv4Addr::new(10, 0, 8, 1),
+        dns_servers: vec![IpAddr::V4(Ipv4Addr::new(10, 0, 8, 1))],
+        search_domains: vec!["corp.example".to_string()],
+        mtu: 1400,
+        routes: vec!["10.0.8.0/24".parse().unwrap()],
+        ..Default::default()
+    };
+    let answer = RegisterHandlerAnswer {
+        s_type: ActorStructureType::RegisterHandlerAnswer,
+        ipv4: "10.0.8.2".to_string(),
+        ipv6: "2001:db8:3333:4444::2".to_string(),
+        error: None,
+        network: network.encode(),
+        max_frame_size: config().max_frame_size as u64,
+    };
+    let encode_failed = "Failed to encode a handshake message";
+    let messages = [
+        s_type::to_vec(&HandshakeInit {
+            s_type: ActorStructureType::ClientHandshakeInit,
+            username: "alice".to_string(),
+            ephemeral: ephemeral.public_b64.clone(),
+        })
+        .expect(encode_failed),
+        s_type::to_vec(&HandshakeResponse {
+            s_type: ActorStructureType::ServerHandshakeResponse,
+            ephemeral: ephemeral.public_b64.clone(),
+            salt: generate_salt(),
+            signature: String::new(),
+        })
+        .expect(encode_failed),
+        s_type::to_vec(&HandshakeFinish {
+            s_type: ActorStructureType::ClientHandshakeFinish,
+            proof: "A".repeat(43) + "=",
+        })
+        .expect(encode_failed),
+        s_type::to_vec(&RegisterHandlerRequest {
+            s_type: ActorStructureType::RegisterHandlerRequest,
+            requested_ipv4: Some("10.0.8.2".to_string()),
+            requested_ipv6: None,
+            max_frame_size: config().max_frame_size as u64,
+        })
+        .expect(encode_failed),
+        s_type::to_vec(&answer).expect(encode_failed),
+        network.encode(),
+    ];
+    messages
+        .into_iter()
+        .enumerate()
+        .map(|(selector, message)| {
+            let mut seed = vec![selector as u8];
+            seed.extend(message);
+            seed
+        })
+        .collect()
+}
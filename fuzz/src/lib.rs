@@ -0,0 +1,51 @@
+//! Fixtures shared by the fuzz targets and the corpus generator.
+//!
+//! Seed the corpora once, then run a target on nightly with libFuzzer's memory limits so
+//! an input that makes a decoder allocate without bound fails like a panic does:
+//!
+//! ```text
+//! cargo run --bin generate_corpus
+//! cargo +nightly fuzz run data_pack -- -rss_limit_mb=512 -malloc_limit_mb=64
+//! ```
+
+use actor::metrics::Metrics;
+use actor::operational::data_pack::DataPack;
+use actor::operational::frame_cipher::{FrameCipher, FrameRole};
+use actor::util::handshake::{derive_secrets, SessionKeys};
+use actor::vpn_config::{ConfigRole, RekeyConfig, VpnConfig};
+use base64::engine::general_purpose;
+use base64::Engine;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+pub const TARGETS: [&str; 4] = ["data_pack", "frame_cipher", "handshake_messages", "ip_header"];
+
+/// The example server config, so frame limits and garbage sizes match a real deployment.
+pub fn config() -> &'static VpnConfig {
+    static CONFIG: OnceLock<VpnConfig> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../config/server.example.toml"));
+        VpnConfig::load(path, ConfigRole::Server).expect("Failed to load the example server config")
+    })
+}
+
+pub fn data_pack() -> &'static DataPack {
+    static DATA_PACK: OnceLock<DataPack> = OnceLock::new();
+    DATA_PACK.get_or_init(|| DataPack::new(config().clone(), Arc::new(Metrics::new(false))))
+}
+
+/// Fixed session keys, so frames sealed by the corpus generator open in the targets.
+pub fn session_keys() -> SessionKeys {
+    static KEYS: OnceLock<SessionKeys> = OnceLock::new();
+    KEYS.get_or_init(|| {
+        let user_key = general_purpose::STANDARD.encode([0x5a; 32]);
+        derive_secrets(&user_key, &[7; 32], b"fuzz")
+            .expect("Failed to derive the fuzzing session keys")
+            .keys
+    })
+    .clone()
+}
+
+pub fn cipher(role: FrameRole) -> FrameCipher {
+    FrameCipher::new(&session_keys(), role, RekeyConfig::default()).expect("Failed to create the frame cipher")
+}
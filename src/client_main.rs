@@ -1,133 +1,486 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
-use crate::front_interface::direct_tun::DirectTun;
-use crate::handlers::actor_structure_type::RegisterHandlerAnswer;
-use crate::receivers::auth_receiver::AuthReceiver;
-use crate::receivers::register_receiver::{OnRegisterInfoReceiver, RegisterReceiver};
-use crate::vpn_config::VpnConfig;
-use std::sync::atomic::AtomicBool;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use actor::front_interface::direct_tun::DirectTun;
+use actor::handlers::actor_structure_type::{NetworkOptions, RegisterHandlerAnswer};
+use actor::receivers::auth_receiver::AuthReceiver;
+use actor::metrics::Metrics;
+use actor::netconfig::journal::{self, Journal};
+use actor::netconfig::netlink::Netlink;
+use actor::router_setup::RouteState;
+use actor::receivers::register_receiver::{OnRegisterInfoReceiver, RegisterReceiver};
+use actor::util::handshake::SessionKeys;
+use actor::vpn_config::{ConfigRole, VpnConfig};
+use actor::error::ActorError;
+use std::io;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
 use std::sync::{Arc, Mutex};
-use std::thread::spawn;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
 use tfserver::client::{ClientConnection, Receiver};
-use tfserver::openssl::version::dir;
-use tfserver::tungstenite::{Bytes, Message};
-use tfserver::util::data_cipher::EncryptionType;
-use crate::operational::data_pack::BytesBuff;
-
-pub mod front_interface;
-pub mod handlers;
-pub mod operational;
-pub mod receivers;
-pub mod router_setup;
-pub mod server;
-pub mod util;
-pub mod verbose;
-pub mod vpn_config;
+use tfserver::tungstenite;
+use tfserver::tungstenite::stream::MaybeTlsStream;
+use tfserver::tungstenite::{Bytes, Message, WebSocket};
+use actor::util::poll::wait_readable;
+use actor::util::shutdown;
+use actor::{metrics, router_setup, vpn_config};
+use actor::verbose::logger::Logger;
+use log::{error, info, warn};
+
+
+const TUN_NAME: &str = "actor-tun0";
+
+type ServerStream = Arc<Mutex<WebSocket<MaybeTlsStream<TcpStream>>>>;
+
+/// Longest the TUN side waits for traffic before sending a frame anyway, so an idle
+/// tunnel keeps the server's read loop and the rekey timer moving.
+const IDLE_FRAME_MS: u16 = 1000;
+/// How often the socket side wakes up to check `running` while the server is silent.
+const SOCKET_POLL_MS: u16 = 200;
+/// Bounds a read that started on a partial WebSocket frame, the writer waits on the same lock.
+const SOCKET_READ_TIMEOUT: Duration = Duration::from_millis(5);
 
 struct TunnelThread {
     direct_tun: Option<Arc<Mutex<DirectTun>>>,
     connection: Option<Arc<Mutex<ClientConnection>>>,
+    stream: Option<ServerStream>,
     config: Arc<VpnConfig>,
     ipv4assigned: Option<Ipv4Addr>,
     ipv6assigned: Option<Ipv6Addr>,
-    iv: Option<String>,
     running: Arc<Mutex<AtomicBool>>,
+    workers: Vec<JoinHandle<()>>,
+    route_state: Option<RouteState>,
+    net: Journal<Netlink>,
+    metrics: Arc<Metrics>,
+    /// Set when the server could not be verified or refused us, ending the attempt early.
+    attempt_failed: bool,
+    /// Set when this host cannot carry the tunnel at all, e.g. the TUN device cannot be
+    /// created. Retrying would not help, the client reverts the network and exits.
+    fatal: Option<String>,
 }
 
 impl TunnelThread {
-    pub fn start(&mut self) {
+    /// Takes the socket over from the handshake connection and pumps traffic both ways on
+    /// two threads until [`TunnelThread::stop`] is called or the connection is lost.
+    pub fn start(&mut self) -> Result<(), ActorError> {
+        let stream = self.connection.as_ref().unwrap().lock().unwrap().stop_and_move_stream();
+        // kept before anything can fail, stop() closes it either way
+        self.stream = Some(stream.clone());
+        let socket_fd = match stream.lock().unwrap().get_ref() {
+            MaybeTlsStream::Plain(tcp) => {
+                tcp.set_read_timeout(Some(SOCKET_READ_TIMEOUT))?;
+                tcp.as_raw_fd()
+            }
+            _ => {
+                return Err(ActorError::Io(io::Error::new(ErrorKind::Unsupported, "unsupported server stream")));
+            }
+        };
+        self.running.lock().unwrap().store(true, Relaxed);
+
         let direct_tun = self.direct_tun.as_ref().unwrap().clone();
-        let connection = self.connection.as_ref().unwrap().lock().unwrap().stop_and_move_stream();
-        spawn(move || {
-            let packets = direct_tun.lock().unwrap().get_packets();
-            if !packets.is_empty(){
-                connection.lock().unwrap().send(Message::Binary(Bytes::from(packets))).unwrap();
+        let running = self.running.clone();
+        let uplink_stream = stream.clone();
+        let metrics = self.metrics.clone();
+        self.workers.push(spawn(move || {
+            let tun_fd = direct_tun.lock().unwrap().tun_fd();
+            while running.lock().unwrap().load(Relaxed) {
+                wait_readable(tun_fd, IDLE_FRAME_MS);
+                let frame = match direct_tun.lock().unwrap().get_packets() {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        error!("Failed to pack outgoing traffic: {}", err);
+                        metrics.session_errors.inc();
+                        running.lock().unwrap().store(false, Relaxed);
+                        break;
+                    }
+                };
+                metrics.bytes_sent.add(frame.len() as u64);
+                metrics.frames_sent.inc();
+                let sent = uplink_stream.lock().unwrap().send(Message::Binary(Bytes::from(frame)));
+                if let Err(err) = sent {
+                    warn!("Failed to send to server: {}", err);
+                    running.lock().unwrap().store(false, Relaxed);
+                }
             }
-            let answer = connection.lock().unwrap().read();
-            match answer {
-                Ok(message) => {
-                    match message {
-                        Message::Text(_) => {}
-                        Message::Binary(data) => {
-                            let data = tfserver::server::tcp_server_new::bytes_into_vec(data);
-                            direct_tun.lock().unwrap().write_data(data);
+        }));
+
+        let direct_tun = self.direct_tun.as_ref().unwrap().clone();
+        let running = self.running.clone();
+        let metrics = self.metrics.clone();
+        self.workers.push(spawn(move || {
+            // tungstenite may hold further messages from the last read in its buffer
+            let mut maybe_buffered = false;
+            while running.lock().unwrap().load(Relaxed) {
+                if !wait_readable(socket_fd, SOCKET_POLL_MS) && !maybe_buffered {
+                    continue;
+                }
+                let answer = stream.lock().unwrap().read();
+                maybe_buffered = answer.is_ok();
+                match answer {
+                    Ok(Message::Binary(data)) => {
+                        let data = tfserver::server::tcp_server_new::bytes_into_vec(data);
+                        metrics.bytes_received.add(data.len() as u64);
+                        metrics.frames_received.inc();
+                        match direct_tun.lock().unwrap().write_data(data) {
+                            Ok(()) => {}
+                            Err(err) if err.closes_session() => {
+                                // reconnecting gets fresh keys and a clean state on both ends
+                                warn!("Closing the tunnel: {}", err);
+                                metrics.session_errors.inc();
+                                running.lock().unwrap().store(false, Relaxed);
+                            }
+                            Err(err) => warn!("Dropped frame: {}", err),
                         }
-                        Message::Ping(_) => {}
-                        Message::Pong(_) => {}
-                        Message::Close(_) => {}
-                        Message::Frame(_) => {}
+                    }
+                    Ok(Message::Close(_)) => {
+                        warn!("Server closed the tunnel");
+                        running.lock().unwrap().store(false, Relaxed);
+                    }
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(err))
+                        if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+                    Err(err) => {
+                        warn!("Connection to server lost: {}", err);
+                        running.lock().unwrap().store(false, Relaxed);
                     }
                 }
-                Err(_) => {}
             }
-        });
+        }));
+        Ok(())
+    }
+
+    fn route_traffic(&mut self, options: &NetworkOptions) {
+        let routing = &self.config.client.routing;
+        let mut include = routing.include.clone();
+        if routing.use_pushed_routes {
+            include.extend(options.routes.iter().cloned());
+        }
+        let server_addrs: Vec<IpAddr> = (self.config.hostname.as_str(), self.config.port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+            .unwrap_or_default();
+        match router_setup::route_traffic_through_tun(&mut self.net, TUN_NAME, routing.mode, &include, &routing.exclude, &server_addrs) {
+            Ok(state) => self.route_state = Some(state),
+            Err(err) => error!("Failed to set up routes: {}", err),
+        }
+    }
+
+    /// Puts the routing table back the way it was before the tunnel came up.
+    pub fn restore_routes(&mut self) {
+        if let Some(state) = self.route_state.take() {
+            if let Err(err) = router_setup::restore_routes(&mut self.net, state) {
+                error!("Failed to restore routes: {}", err);
+            }
+        }
+    }
+
+    /// Restores the routes and reverts whatever else is still journaled, before exiting.
+    pub fn revert_network(&mut self) -> bool {
+        self.restore_routes();
+        if let Err(err) = self.net.revert() {
+            error!("Failed to revert network changes, run `cleanup` to retry: {}", err);
+            return false;
+        }
+        true
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.lock().unwrap().load(Relaxed)
+    }
+
+    /// True once the pump has been started and one of its threads gave up.
+    pub fn has_stopped(&self) -> bool {
+        !self.workers.is_empty() && !self.running.lock().unwrap().load(Relaxed)
+    }
+
+    /// Stops the pump, or the handshake connection if it never got that far, so nothing
+    /// from this attempt reaches the next one.
+    pub fn stop(&mut self) {
+        self.running.lock().unwrap().store(false, Relaxed);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        if let Some(connection) = self.connection.take() {
+            if self.stream.is_none() {
+                self.stream = Some(connection.lock().unwrap().stop_and_move_stream());
+            }
+        }
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.lock().unwrap().close(None);
+            let _ = stream.lock().unwrap().flush();
+        }
     }
 }
 
 
 impl OnRegisterInfoReceiver for TunnelThread {
-    fn info_received(&mut self, iv: String, reg_info: RegisterHandlerAnswer) {
+    fn info_received(&mut self, session: SessionKeys, reg_info: RegisterHandlerAnswer) -> Result<(), String> {
         let info = reg_info;
-        self.iv = Some(iv.clone());
-        self.ipv4assigned = Some(info.ipv4.parse().unwrap());
-        self.ipv6assigned = Some(info.ipv6.parse().unwrap());
-
-        self.direct_tun = Some(Arc::new(Mutex::new(DirectTun::new(
-            self.config.as_ref().clone(),
-            iv,
-            info.ipv4.clone().to_string(),
-            Some("actor-tun0".to_string()),
-        ))));
-        self.start();
+        let ipv4: Ipv4Addr = info.ipv4.parse().map_err(|_| format!("server assigned an invalid IPv4 address {:?}", info.ipv4))?;
+        let ipv6: Ipv6Addr = info.ipv6.parse().map_err(|_| format!("server assigned an invalid IPv6 address {:?}", info.ipv6))?;
+        let options = if info.network.is_empty() {
+            NetworkOptions::default()
+        } else {
+            NetworkOptions::decode(&info.network).unwrap_or_else(|err| {
+                warn!("Ignoring malformed network options: {}", err);
+                NetworkOptions::default()
+            })
+        };
+        options.validate()?;
+
+        let resumed = self.direct_tun.is_some() && self.ipv4assigned == Some(ipv4);
+        if resumed {
+            info!("Session resumed on {}", ipv4);
+            self.direct_tun
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .resume(session)
+                .map_err(|err| err.to_string())?;
+        } else {
+            if let Some(previous) = self.ipv4assigned {
+                warn!("Server moved us from {} to {}, connections in the tunnel are lost", previous, ipv4);
+                // the old device has to go before one with the same name can be created
+                self.direct_tun = None;
+            }
+            let direct_tun = DirectTun::new(
+                self.config.as_ref().clone(),
+                session,
+                ipv4,
+                &options,
+                Some(TUN_NAME.to_string()),
+                self.metrics.clone(),
+            );
+            match direct_tun {
+                Ok(direct_tun) => self.direct_tun = Some(Arc::new(Mutex::new(direct_tun))),
+                Err(err) => {
+                    let reason = format!("failed to create {}: {}", TUN_NAME, err);
+                    self.fatal = Some(reason.clone());
+                    return Err(reason);
+                }
+            }
+        }
+        let direct_tun = self.direct_tun.as_ref().unwrap();
+        direct_tun.lock().unwrap().set_peer_max_frame_size(info.max_frame_size as usize);
+        if let Err(err) = router_setup::apply_network_options(&mut self.net, TUN_NAME, ipv6, &options) {
+            error!("Failed to apply network options: {}", err);
+        }
+        if !resumed || self.route_state.is_none() {
+            self.restore_routes();
+            self.route_traffic(&options);
+        }
+        self.ipv4assigned = Some(ipv4);
+        self.ipv6assigned = Some(ipv6);
+        self.start().map_err(|err| format!("failed to start the tunnel: {}", err))
+    }
+
+    fn handshake_failed(&mut self, reason: String) {
+        // retried with backoff like a lost connection, a server that fails verification once
+        // gets nothing from us and may be a passing man-in-the-middle
+        error!("Handshake aborted: {}", reason);
+        self.attempt_failed = true;
+    }
+
+    fn register_failed(&mut self, reason: String) {
+        warn!("Registration failed: {}", reason);
+        self.attempt_failed = true;
     }
 }
 
 pub fn main() {
-    let config = Arc::new(VpnConfig {
-        key: "HelloWorldEncKey".to_string(),
-        hostname: "192.168.88.247".to_string(),
-        encryption_type: EncryptionType::Aes256Ctr,
-        port: 8090,
-        garbage_packet_min_size: 1,
-        garbage_packet_max_size: 25,
-        max_garbage_packets_amount: 20,
-        min_garbage_packets_amount: 5,
-        max_packets_in_flight: 2,
-        mtu_min: 9000,
-        mtu_max: 1000,
-        resyncer_timeout_ms: 3,
-    });
+    let mut config = Arc::new(vpn_config::load_from_args_or_exit("actor-client", ConfigRole::Client));
+    let command = vpn_config::command_from_args(&std::env::args().collect::<Vec<String>>());
+    let cleanup = match command.first().map(String::as_str) {
+        None => false,
+        Some("cleanup") => true,
+        Some(other) => {
+            eprintln!("unknown command {}, expected cleanup", other);
+            std::process::exit(2);
+        }
+    };
+    if let Err(err) = Logger::init(&config.log) {
+        eprintln!("Failed to set up logging: {}", err);
+        std::process::exit(1);
+    }
+    if cleanup {
+        if let Err(err) = journal::run_cleanup(&config.client.journal_file) {
+            error!("Cleanup failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    shutdown::install_handlers().expect("Failed to install signal handlers");
+    let net = Netlink::open()
+        .and_then(|netlink| Journal::open(netlink, &config.client.journal_file))
+        .unwrap_or_else(|err| {
+            error!("Failed to open the network journal: {}", err);
+            std::process::exit(1);
+        });
+    let metrics = Arc::new(Metrics::new(false));
+    // restart-only like on the server, a reload does not move the exporter
+    if let Some(listen) = &config.metrics.listen {
+        if let Err(err) = metrics::http::start(listen, metrics.clone()) {
+            warn!("Metrics disabled, failed to bind {}: {}", listen, err);
+        }
+    }
     let tunnel_thread = Arc::new(Mutex::new(TunnelThread {
         direct_tun: None,
         connection: None,
         config: config.clone(),
         ipv4assigned: None,
         ipv6assigned: None,
-        iv: None,
+        stream: None,
         running: Arc::new(Mutex::new(Default::default())),
+        workers: Vec::new(),
+        route_state: None,
+        net,
+        metrics: metrics.clone(),
+        attempt_failed: false,
+        fatal: None,
     }));
-    let register_receiver = Arc::new(Mutex::new(RegisterReceiver {
-        iv_current: None,
-        reg_info: None,
-        data_send: AtomicBool::new(false),
-        config: config.clone(),
-        on_register_info: tunnel_thread.clone(),
-    }));
-    let auth_receiver = Arc::new(Mutex::new(AuthReceiver {
-        auth_passed: AtomicBool::new(false),
-        challenge_answer: None,
-        config: config.clone(),
-        iv_result: None,
-        register_receiver: register_receiver.clone(),
-    }));
-    let mut receivers: Vec<Arc<Mutex<dyn Receiver>>> = Vec::new();
-    receivers.push(auth_receiver);
-    receivers.push(register_receiver);
-    let mut connection = Arc::new(Mutex::new(ClientConnection::new(
-        "ws://127.0.0.1:8090".to_string(),
-        receivers,
-    )));
-    tunnel_thread.lock().unwrap().connection = Some(connection.clone());
-    connection.lock().unwrap().start();
-
-    loop {}
+
+    let mut failed_attempts: u32 = 0;
+    let mut reloaded: Option<VpnConfig> = None;
+    // the last assignment, requested again so connections inside the tunnel survive
+    let mut reg_info: Option<RegisterHandlerAnswer> = None;
+    loop {
+        // fresh receivers for every connection, a late answer on an abandoned one has
+        // nothing left to act on
+        let register_receiver = Arc::new(Mutex::new(RegisterReceiver {
+            session_current: None,
+            reg_info: reg_info.clone(),
+            data_send: AtomicBool::new(false),
+            config: config.clone(),
+            on_register_info: tunnel_thread.clone(),
+        }));
+        let auth_receiver = Arc::new(Mutex::new(AuthReceiver {
+            auth_passed: AtomicBool::new(false),
+            ephemeral: None,
+            finish: None,
+            session: None,
+            server_confirm: None,
+            config: config.clone(),
+            register_receiver: register_receiver.clone(),
+        }));
+        let mut receivers: Vec<Arc<Mutex<dyn Receiver>>> = Vec::new();
+        receivers.push(auth_receiver.clone());
+        receivers.push(register_receiver.clone());
+        let connection = Arc::new(Mutex::new(ClientConnection::new(
+            format!("ws://{}:{}", config.hostname, config.port),
+            receivers,
+        )));
+        let mut tunnel = tunnel_thread.lock().unwrap();
+        tunnel.connection = Some(connection.clone());
+        tunnel.attempt_failed = false;
+        drop(tunnel);
+        metrics.handshakes_started.inc();
+        connection.lock().unwrap().start();
+
+        if wait_for_tunnel(&tunnel_thread, Duration::from_millis(config.client.handshake_timeout_ms)) {
+            failed_attempts = 0;
+            metrics.handshakes_completed.inc();
+            metrics.sessions_active.set(1);
+            while !shutdown::stop_requested() && !tunnel_thread.lock().unwrap().has_stopped() {
+                if shutdown::take_reload_request() {
+                    reloaded = reload_config();
+                    if reloaded.is_some() {
+                        break;
+                    }
+                }
+                sleep(Duration::from_millis(200));
+            }
+        } else if !shutdown::stop_requested() {
+            if !tunnel_thread.lock().unwrap().attempt_failed {
+                warn!("Handshake did not complete within {} ms", config.client.handshake_timeout_ms);
+            }
+            metrics.handshakes_failed.inc();
+            failed_attempts = failed_attempts.saturating_add(1);
+        }
+        tunnel_thread.lock().unwrap().stop();
+        if let Some(answer) = register_receiver.lock().unwrap().reg_info.take() {
+            reg_info = Some(answer);
+        }
+        if metrics.sessions_active.get() > 0 {
+            metrics.sessions_active.set(0);
+            metrics.sessions_closed.inc();
+        }
+        if let Some(reason) = &tunnel_thread.lock().unwrap().fatal {
+            error!("Giving up: {}", reason);
+            break;
+        }
+        if shutdown::stop_requested() {
+            break;
+        }
+
+        if let Some(new_config) = reloaded.take() {
+            // routes follow the new routing settings once the tunnel is back
+            config = Arc::new(new_config);
+            // the log file and format stay as they were opened
+            Logger::reconfigure(&config.log);
+            let mut tunnel = tunnel_thread.lock().unwrap();
+            tunnel.restore_routes();
+            tunnel.config = config.clone();
+            drop(tunnel);
+            failed_attempts = 0;
+            info!("Configuration reloaded, reconnecting");
+            continue;
+        }
+        metrics.reconnects.inc();
+        let delay = reconnect_delay(&config, failed_attempts);
+        info!("Reconnecting in {} ms", delay.as_millis());
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline && !shutdown::stop_requested() {
+            sleep(Duration::from_millis(100));
+        }
+        if shutdown::stop_requested() {
+            break;
+        }
+    }
+    info!("Shutting down");
+    let mut tunnel = tunnel_thread.lock().unwrap();
+    // closing the TUN device removes it along with its addresses
+    tunnel.direct_tun = None;
+    let reverted = tunnel.revert_network();
+    std::process::exit(if reverted && tunnel.fatal.is_none() { 0 } else { 1 });
+}
+
+/// Reads the config again on SIGHUP, `None` when it fails to load and the current one stays.
+fn reload_config() -> Option<VpnConfig> {
+    match vpn_config::reload_from_args(ConfigRole::Client) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            error!("Keeping the current configuration: {}", err);
+            None
+        }
+    }
+}
+
+/// Waits until the handshake on the current connection has started the pump, false on
+/// timeout or once the attempt failed.
+fn wait_for_tunnel(tunnel_thread: &Arc<Mutex<TunnelThread>>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline && !shutdown::stop_requested() {
+        let tunnel = tunnel_thread.lock().unwrap();
+        if tunnel.is_running() {
+            return true;
+        }
+        if tunnel.attempt_failed {
+            return false;
+        }
+        drop(tunnel);
+        sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+/// Exponential backoff with full jitter, so clients dropped together don't come back in lockstep.
+fn reconnect_delay(config: &VpnConfig, failed_attempts: u32) -> Duration {
+    let ceiling = config
+        .client
+        .reconnect_min_ms
+        .saturating_mul(1u64 << failed_attempts.min(20))
+        .min(config.client.reconnect_max_ms);
+    Duration::from_millis(rand::random_range(config.client.reconnect_min_ms..=ceiling))
 }
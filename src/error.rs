@@ -0,0 +1,101 @@
+use crate::operational::address_pool::PoolError;
+use crate::operational::frame_cipher::FrameError;
+use std::fmt;
+use std::io;
+
+/// Errors of the data path, from the TUN device through packing and sealing to the
+/// address pool. Module specific errors are wrapped rather than flattened.
+#[derive(Debug)]
+pub enum ActorError {
+    Io(io::Error),
+    Frame(FrameError),
+    /// A frame that opened fine but whose packets do not parse.
+    MalformedPack(String),
+    /// A sealed frame larger than `max_frame_size` allows, rejected before decrypting it.
+    FrameTooLarge { size: usize, max: usize },
+    Encode(String),
+    /// Encryption failed or the frame counter ran out.
+    Seal(String),
+    /// Session keys the frame cipher cannot be built from.
+    Keys(String),
+    Pool(PoolError),
+}
+
+impl ActorError {
+    /// Whether the session the error came from has to be closed. Frames from retired keys
+    /// and replays are expected around rekeys and reconnects and only cost that frame,
+    /// anything else means the peer or its connection can no longer be trusted.
+    pub fn closes_session(&self) -> bool {
+        !matches!(self, ActorError::Frame(FrameError::Replayed | FrameError::UnknownEpoch))
+    }
+}
+
+impl fmt::Display for ActorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActorError::Io(err) => write!(f, "{}", err),
+            ActorError::Frame(err) => write!(f, "{}", err),
+            ActorError::MalformedPack(what) => write!(f, "malformed packet data: {}", what),
+            ActorError::FrameTooLarge { size, max } => {
+                write!(f, "frame of {} bytes exceeds the maximum of {}", size, max)
+            }
+            ActorError::Encode(what) => write!(f, "failed to encode packet: {}", what),
+            ActorError::Seal(what) => write!(f, "failed to seal frame: {}", what),
+            ActorError::Keys(what) => write!(f, "malformed session keys: {}", what),
+            ActorError::Pool(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ActorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ActorError::Io(err) => Some(err),
+            ActorError::Frame(err) => Some(err),
+            ActorError::Pool(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ActorError {
+    fn from(err: io::Error) -> Self {
+        ActorError::Io(err)
+    }
+}
+
+impl From<FrameError> for ActorError {
+    fn from(err: FrameError) -> Self {
+        ActorError::Frame(err)
+    }
+}
+
+impl From<PoolError> for ActorError {
+    fn from(err: PoolError) -> Self {
+        ActorError::Pool(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_stale_frames_keep_the_session() {
+        assert!(!ActorError::Frame(FrameError::Replayed).closes_session());
+        assert!(!ActorError::Frame(FrameError::UnknownEpoch).closes_session());
+
+        let closing = [
+            ActorError::Frame(FrameError::Truncated),
+            ActorError::Frame(FrameError::Tampered),
+            ActorError::Frame(FrameError::CounterExhausted),
+            ActorError::MalformedPack(String::new()),
+            ActorError::FrameTooLarge { size: 2, max: 1 },
+            ActorError::Io(io::Error::other("tun gone")),
+            ActorError::Keys(String::new()),
+        ];
+        for err in closing {
+            assert!(err.closes_session(), "{} should close the session", err);
+        }
+    }
+}
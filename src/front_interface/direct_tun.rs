@@ -1,92 +1,122 @@
-use std::mem;
-use std::sync::atomic::{AtomicBool, Ordering};
-use crate::operational::data_pack::{DataPack, DataPacket};
-use crate::operational::tun_interface::{IpPacket, TunInterface, TunInterfaceCreateInfo};
+use log::warn;
+use crate::operational::data_pack::{DataPack, DATA_PACKET, PACKET_OVERHEAD, SYSTEM_PACKET};
+use crate::operational::tun_interface::{IpPacket, TunInterface, TunInterfaceCreateInfo, MAX_PACKET_LEN};
 use crate::vpn_config::VpnConfig;
-use std::thread::sleep;
-use std::time::Duration;
-use tfserver::util::data_cipher::DataCipher;
-use crate::util::semaphore::Semaphore;
+use std::net::Ipv4Addr;
+use std::os::fd::RawFd;
+use ipnet::Ipv4Net;
+use crate::handlers::actor_structure_type::NetworkOptions;
+use crate::operational::frame_cipher::{FrameCipher, FrameRole, FrameStats, FRAME_OVERHEAD};
+use crate::util::handshake::SessionKeys;
+use crate::metrics::Metrics;
+use crate::error::ActorError;
+use std::sync::Arc;
 
 pub struct DirectTun {
     vpn_config: VpnConfig,
     data_pack: DataPack,
-    data_cipher: DataCipher,
-    iv: String,
-    write_buffer: Vec<DataPacket>,
-    receive_buffer: Vec<u8>,
+    frame_cipher: FrameCipher,
     tun_interface: TunInterface,
-    write_semaphore: Semaphore,
-    read_semaphore: Semaphore,
-    running: AtomicBool,
+    metrics: Arc<Metrics>,
 }
 
 impl DirectTun {
-    pub fn new(vpn_config: VpnConfig, iv: String, ip_assigned: String, iff_name: Option<String>) -> DirectTun {
-        let data_pack = DataPack::new(vpn_config.clone());
-        let data_cipher = DataCipher::new_init(vpn_config.encryption_type, vpn_config.key.clone());
+    pub fn new(vpn_config: VpnConfig, keys: SessionKeys, ip_assigned: Ipv4Addr, options: &NetworkOptions, iff_name: Option<String>, metrics: Arc<Metrics>) -> Result<DirectTun, ActorError> {
+        let data_pack = DataPack::new(vpn_config.clone(), metrics.clone());
+        let frame_cipher = FrameCipher::new(&keys, FrameRole::Client, vpn_config.rekey.clone())
+            .map_err(|err| ActorError::Keys(err.to_string()))?;
         let mut tun_info = TunInterfaceCreateInfo::default();
-        let netmask = "255.255.255.0".to_string();
+        let netmask = Ipv4Net::new(ip_assigned, options.ipv4_prefix_len)
+            .map(|net| net.netmask())
+            .unwrap_or(Ipv4Addr::new(255, 255, 255, 0));
         tun_info.set_iff_ip(&ip_assigned);
         tun_info.set_iff_netmask(&netmask);
+        tun_info.set_iff_mtu(options.mtu.min(u16::MAX as u32) as u16);
         if iff_name.is_some() {
             tun_info.set_iff_name(iff_name.unwrap());
         }
-        let tun_interface = TunInterface::new(&tun_info);
-        Self {
+        let tun_interface = TunInterface::new(&tun_info)?;
+        Ok(Self {
             vpn_config,
             data_pack,
-            data_cipher,
-            iv,
-            write_buffer: Vec::new(),
-            receive_buffer: Vec::new(),
+            frame_cipher,
             tun_interface,
-            write_semaphore: Semaphore::new(1),
-            read_semaphore: Semaphore::new(1),
-            running: AtomicBool::new(true),
-        }
+            metrics,
+        })
     }
     
-    pub fn get_packets(&mut self) -> Vec<u8>{
-        let mut attempts_amount: u32 = 0;
+    /// Drains up to `max_packets_in_flight` packets already queued on the TUN device into one
+    /// sealed frame. Never blocks, wait on [`DirectTun::tun_fd`] for traffic first.
+    pub fn get_packets(&mut self) -> Result<Vec<u8>, ActorError> {
         let mut packets: Vec<IpPacket> = Vec::new();
-        while attempts_amount < self.vpn_config.max_packets_in_flight {
-            let packet = self.tun_interface.read_packet_non_block();
-            if packet.is_some() {
-                packets.push(packet.unwrap());
+        let budget = self.data_pack.payload_budget();
+        let mut used = 0;
+        // stop while the largest possible packet still fits, the frame stays under max_frame_size
+        while (packets.len() as u32) < self.vpn_config.max_packets_in_flight
+            && used + MAX_PACKET_LEN + PACKET_OVERHEAD <= budget
+        {
+            match self.tun_interface.read_packet_non_block() {
+                Some(packet) => {
+                    used += packet.data.len() + PACKET_OVERHEAD;
+                    packets.push(packet);
+                }
+                None => break,
             }
-            sleep(Duration::from_millis(
-                self.vpn_config.resyncer_timeout_ms as u64,
-            ));
-            attempts_amount += 1;
         }
-        let mut data = self.data_pack.post_process_data(packets);
-        let req_len = self.data_cipher.required_buffer_size(data.len());
-        let mut res_buff: Vec<u8> = Vec::with_capacity(req_len);
-        unsafe{
-            res_buff.set_len(req_len);
-        };
-        self.data_cipher.encrypt_block(&mut data, &mut res_buff, self.iv.as_bytes()).unwrap();
-        res_buff
+        self.metrics.packets_from_tun.add(packets.len() as u64);
+        self.frame_cipher.poll_rekey();
+        let system_packets = self.frame_cipher.take_system_packets();
+        let data = self.data_pack.post_process_data(packets, system_packets)?;
+        self.frame_cipher.seal(&data).map_err(|err| ActorError::Seal(err.to_string()))
     }
     
-    pub fn write_data(&mut self, mut data: Vec<u8>) {
-        let mut res_buffer = Vec::with_capacity(data.len());
-        unsafe{
-            res_buffer.set_len(data.len());
+    /// Opens a frame from the server and writes its packets to the TUN device. Errors for
+    /// which [`ActorError::closes_session`] is false only cost this frame, a packet the TUN
+    /// device refuses only costs that packet.
+    pub fn write_data(&mut self, data: Vec<u8>) -> Result<(), ActorError> {
+        let max = self.data_pack.max_frame_size() + FRAME_OVERHEAD;
+        if data.len() > max {
+            return Err(ActorError::FrameTooLarge { size: data.len(), max });
         }
-        let size = self.data_cipher.decrypt_block(data.as_slice(), &mut res_buffer,self.iv.as_bytes()).expect("Failed to decrypt data");
-        res_buffer.truncate(size);
-        let mut data = self.data_pack.pre_process_data(res_buffer.as_slice());
-        data.iter().for_each(|x|{
-            self.tun_interface.write(x.data.data.as_slice());
-        })
+        let res_buffer = self.frame_cipher.open(data.as_slice())?;
+        // every frame is packed whole, one ending inside a packet is malformed
+        let data = self.data_pack.pre_process_data(res_buffer.as_slice())?;
+        for x in data.iter() {
+            if x.packet_type == SYSTEM_PACKET {
+                if let Err(err) = self.frame_cipher.handle_system(x.data.data.as_slice()) {
+                    warn!("Bad system packet: {}", err);
+                }
+            } else if x.packet_type == DATA_PACKET {
+                match self.tun_interface.write(x.data.data.as_slice()) {
+                    Ok(()) => self.metrics.packets_to_tun.inc(),
+                    Err(err) => {
+                        warn!("Dropping a packet the TUN device refused: {}", err);
+                        self.metrics.packets_dropped.inc();
+                    }
+                }
+            }
+        }
+        Ok(())
     }
     
 
     
-    pub fn stop(&mut self) {
-        self.running.store(false, Ordering::Relaxed);
+    /// Switches to the keys of a new session after a reconnect, keeping the TUN device as it is.
+    pub fn resume(&mut self, keys: SessionKeys) -> Result<(), ActorError> {
+        self.frame_cipher = FrameCipher::new(&keys, FrameRole::Client, self.vpn_config.rekey.clone())
+            .map_err(|err| ActorError::Keys(err.to_string()))?;
+        Ok(())
     }
 
+    pub fn set_peer_max_frame_size(&mut self, max: usize) {
+        self.data_pack.set_peer_max_frame_size(max);
+    }
+
+    pub fn tun_fd(&self) -> RawFd {
+        self.tun_interface.raw_fd()
+    }
+
+    pub fn frame_stats(&self) -> &FrameStats {
+        self.frame_cipher.stats()
+    }
 }
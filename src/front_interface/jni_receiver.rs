@@ -1,18 +1,20 @@
+use log::warn;
 use std::any::Any;
-use crate::operational::data_pack::{DATA_PACKET, DataPack};
+use crate::operational::data_pack::{DATA_PACKET, DataPack, SYSTEM_PACKET};
 use crate::operational::packet_router::PacketReceiver;
 use crate::util::semaphore::Semaphore;
 use crate::operational::tun_interface::{IpPacket, TunInterface};
 use crate::vpn_config::VpnConfig;
+use crate::operational::frame_cipher::{FrameCipher, FrameRole, FrameStats, FRAME_OVERHEAD};
+use crate::util::handshake::SessionKeys;
 use std::mem;
-use tfserver::util::data_cipher::DataCipher;
+use std::sync::Arc;
+use crate::metrics::Metrics;
+use crate::error::ActorError;
 
 pub struct JniReceiver {
     data_pack: DataPack,
-    data_cipher: DataCipher,
-    vpn_config: VpnConfig,
-    receive_buffer: Vec<u8>,
-    iv: String,
+    frame_cipher: FrameCipher,
     write_buffer: Vec<IpPacket>,
     write_semaphore: Semaphore,
     pending_packets: Vec<IpPacket>,
@@ -20,55 +22,53 @@ pub struct JniReceiver {
 }
 
 impl JniReceiver {
-    pub fn new(vpn_config: &VpnConfig, iv: String) -> JniReceiver {
-        let data_pack = DataPack::new(vpn_config.clone());
-        let data_cipher = DataCipher::new_init(vpn_config.encryption_type, vpn_config.key.clone());
-        Self {
+    pub fn new(vpn_config: &VpnConfig, keys: SessionKeys, metrics: Arc<Metrics>) -> Result<JniReceiver, ActorError> {
+        let data_pack = DataPack::new(vpn_config.clone(), metrics);
+        let frame_cipher = FrameCipher::new(&keys, FrameRole::Server, vpn_config.rekey.clone())
+            .map_err(|err| ActorError::Keys(err.to_string()))?;
+        Ok(Self {
             data_pack,
-            data_cipher,
-            vpn_config: vpn_config.clone(),
-            receive_buffer: Vec::new(),
-            iv,
+            frame_cipher,
             write_buffer: Vec::new(),
             write_semaphore: Semaphore::new(1),
             pending_packets: Vec::new(),
             pending_packets_semaphore: Semaphore::new(1),
-        }
+        })
     }
 
-    pub fn get_data(&mut self) -> Vec<u8> {
+    pub fn get_data(&mut self) -> Result<Vec<u8>, ActorError> {
         self.pending_packets_semaphore.acquire();
-        if self.pending_packets.is_empty() {
+        // a rekey answer has to go out even when no traffic is queued
+        if self.pending_packets.is_empty() && !self.frame_cipher.has_system_packets() {
             self.pending_packets_semaphore.release();
-            return Vec::new();
+            return Ok(Vec::new());
         }
-        let start = self.receive_buffer.len();
-        let packets = mem::replace(&mut self.pending_packets, Vec::new());
-        let mut data = self.data_pack.post_process_data(packets);
+        let packets = self.data_pack.take_frame_packets(&mut self.pending_packets);
+        let system_packets = self.frame_cipher.take_system_packets();
+        let data = self.data_pack.post_process_data(packets, system_packets);
         self.pending_packets_semaphore.release();
-        let req_len =  self.data_cipher.required_buffer_size(data.len());
-        let mut res_buff = Vec::with_capacity(req_len);
-        unsafe{
-            res_buff.set_len(req_len);
-        }
-        self.data_cipher.encrypt_block(&mut data, &mut res_buff, self.iv.as_bytes()).expect("Failed to encrypt data");
-
-        res_buff
+        self.frame_cipher.seal(&data?).map_err(|err| ActorError::Seal(err.to_string()))
     }
 
-    pub fn write_data(&mut self, data: &mut [u8]) {
-        let mut data_buff: Vec<u8> = Vec::with_capacity(data.len());
-        unsafe{
-            data_buff.set_len(data.len());
+    /// Opens a frame from the client and queues its packets for the router. Errors for
+    /// which [`ActorError::closes_session`] is false only cost this frame.
+    pub fn write_data(&mut self, data: &mut [u8]) -> Result<(), ActorError> {
+        let max = self.data_pack.max_frame_size() + FRAME_OVERHEAD;
+        if data.len() > max {
+            return Err(ActorError::FrameTooLarge { size: data.len(), max });
         }
-        let size = self.data_cipher.decrypt_block(data, &mut data_buff, self.iv.as_bytes()).expect("Error decrypting data");
-        data_buff.truncate(size);
-        let mut packets = self.data_pack.pre_process_data(&mut data_buff);
+        let mut data_buff = self.frame_cipher.open(data)?;
+        // every frame is packed whole, one ending inside a packet is malformed
+        let mut packets = self.data_pack.pre_process_data(&data_buff)?;
         data_buff.clear();
         let mut ip_packets: Vec<IpPacket> = Vec::new();
         while !packets.is_empty() {
             let packet = packets.pop().unwrap();
-            if packet.packet_type == DATA_PACKET {
+            if packet.packet_type == SYSTEM_PACKET {
+                if let Err(err) = self.frame_cipher.handle_system(packet.data.data.as_slice()) {
+                    warn!("Bad system packet: {}", err);
+                }
+            } else if packet.packet_type == DATA_PACKET {
                 ip_packets.push(IpPacket {
                     meta: TunInterface::extract_general_ip_header(packet.data.data.as_slice()),
                     data: packet.data.data,
@@ -78,6 +78,17 @@ impl JniReceiver {
         self.write_semaphore.acquire();
         self.write_buffer.append(&mut ip_packets);
         self.write_semaphore.release();
+        Ok(())
+    }
+}
+
+impl JniReceiver {
+    pub fn set_peer_max_frame_size(&mut self, max: usize) {
+        self.data_pack.set_peer_max_frame_size(max);
+    }
+
+    pub fn frame_stats(&self) -> &FrameStats {
+        self.frame_cipher.stats()
     }
 }
 
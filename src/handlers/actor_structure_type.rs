@@ -1,17 +1,21 @@
 use std::any::{Any, TypeId};
 use std::hash::{DefaultHasher, Hash, Hasher};
 use num_enum::TryFromPrimitive;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use tfserver::structures::s_type::{StrongType, StructureType};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tfserver::bincode;
+use tfserver::structures::s_type::{StrongType, StructureType, BINCODE_CFG};
 
 #[repr(u8)]
 #[derive(Serialize, Deserialize, PartialEq, Clone, Hash, Eq, TryFromPrimitive, Copy)]
 pub enum ActorStructureType {
-    ClientChallengeReq,
-    ClientAuthAnswer,
-    ServerAuthChallenge,
+    ClientHandshakeInit,
+    ClientHandshakeFinish,
+    ServerHandshakeResponse,
     RegisterHandlerRequest,
-    RegisterHandlerAnswer
+    RegisterHandlerAnswer,
+    ServerHandshakeComplete,
 }
 
 impl ActorStructureType {
@@ -32,9 +36,10 @@ impl ActorStructureType {
 impl StructureType for ActorStructureType {
     fn get_type_id(&self) -> TypeId {
         match self {
-            ActorStructureType::ClientChallengeReq => TypeId::of::<ChallengeAuthReq>(),
-            ActorStructureType::ServerAuthChallenge => TypeId::of::<ServerAuthoriChallenge>(),
-            ActorStructureType::ClientAuthAnswer => TypeId::of::<ClientAnswerChallenge>(),
+            ActorStructureType::ClientHandshakeInit => TypeId::of::<HandshakeInit>(),
+            ActorStructureType::ServerHandshakeResponse => TypeId::of::<HandshakeResponse>(),
+            ActorStructureType::ClientHandshakeFinish => TypeId::of::<HandshakeFinish>(),
+            ActorStructureType::ServerHandshakeComplete => TypeId::of::<HandshakeComplete>(),
 
             ActorStructureType::RegisterHandlerRequest => TypeId::of::<RegisterHandlerRequest>(),
             ActorStructureType::RegisterHandlerAnswer => TypeId::of::<RegisterHandlerAnswer>(),
@@ -73,32 +78,137 @@ impl StructureType for ActorStructureType {
     }
 }
 
+/// Opens the key exchange with the client's ephemeral X25519 public key.
 #[derive(Serialize, Deserialize)]
-pub struct ChallengeAuthReq{
+pub struct HandshakeInit{
     pub s_type: ActorStructureType,
+    pub username: String,
+    pub ephemeral: String,
 }
 
+/// Server's ephemeral public key and the salt the client stretches its password with, along
+/// with, when it has an identity key, its signature over the transcript. Nothing in it depends
+/// on the user key, so a client that has not proved itself gets nothing to guess passwords against.
 #[derive(Serialize, Deserialize)]
-pub struct ServerAuthoriChallenge{
+pub struct HandshakeResponse{
     pub s_type: ActorStructureType,
-    pub challenge: String,
+    pub ephemeral: String,
+    pub salt: String,
+    pub signature: String,
 }
+
+/// Proves the client derived the same secrets, which requires knowing the user key.
+#[derive(Serialize, Deserialize)]
+pub struct HandshakeFinish{
+    pub s_type: ActorStructureType,
+    pub proof: String,
+}
+
+/// The server's proof of the shared secret, only sent once the client's proof checked out.
 #[derive(Serialize, Deserialize)]
-pub struct ClientAnswerChallenge{
+pub struct HandshakeComplete{
     pub s_type: ActorStructureType,
-    pub answer: String,
+    pub proof: String,
 }
 
+/// Asks for a tunnel address, a reconnecting client passes the addresses it held before
+/// so connections running inside the tunnel survive the outage.
 #[derive(Serialize, Deserialize)]
 pub struct RegisterHandlerRequest{
     pub s_type: ActorStructureType,
+    pub requested_ipv4: Option<String>,
+    pub requested_ipv6: Option<String>,
+    /// Largest frame the client accepts, the server packs what it sends to fit.
+    pub max_frame_size: u64,
 }
 
+/// Assigned tunnel addresses, or why none could be assigned when `error` is set.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RegisterHandlerAnswer{
     pub s_type: ActorStructureType,
     pub ipv4: String,
     pub ipv6: String,
+    pub error: Option<String>,
+    /// Encoded [`NetworkOptions`], empty when the server pushes none.
+    pub network: Vec<u8>,
+    /// Largest frame the server accepts, the client packs what it sends to fit.
+    pub max_frame_size: u64,
+}
+
+pub const NETWORK_OPTIONS_VERSION: u32 = 1;
+/// Smallest MTU IPv6 works with, a pushed MTU below it breaks the tunnel for both families.
+pub const MIN_PUSHED_MTU: u32 = 1280;
+
+/// Network settings the server pushes to clients. New fields are only ever appended and
+/// decoding ignores trailing bytes, so older clients read the fields they know and skip the rest.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkOptions {
+    pub version: u32,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+    pub gateway_ipv4: Ipv4Addr,
+    pub gateway_ipv6: Ipv6Addr,
+    pub dns_servers: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+    pub mtu: u32,
+    /// Networks to reach through the tunnel.
+    pub routes: Vec<IpNet>,
+}
+
+/// What servers that push no options imply: a /24, a /64 and nothing else to configure.
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 64,
+            gateway_ipv4: Ipv4Addr::UNSPECIFIED,
+            gateway_ipv6: Ipv6Addr::UNSPECIFIED,
+            dns_servers: Vec::new(),
+            search_domains: Vec::new(),
+            mtu: 1500,
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl NetworkOptions {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serde::encode_to_vec(self, BINCODE_CFG.clone()).expect("Failed to serialize network options")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<NetworkOptions, Box<dyn std::error::Error>> {
+        Ok(bincode::serde::decode_from_slice(data, BINCODE_CFG.clone())?.0)
+    }
+
+    /// Checks what the client would hand to the kernel and to `resolvectl` as it is, the
+    /// server is not trusted to push only sane values.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_PUSHED_MTU..=u16::MAX as u32).contains(&self.mtu) {
+            return Err(format!("pushed MTU {} is outside {}..={}", self.mtu, MIN_PUSHED_MTU, u16::MAX));
+        }
+        if let Some(domain) = self.search_domains.iter().find(|domain| !is_search_domain(domain)) {
+            return Err(format!("pushed search domain {:?} is not a hostname", domain));
+        }
+        Ok(())
+    }
+}
+
+/// A hostname, optionally with a trailing dot, or one prefixed with `~` as systemd-resolved
+/// takes routing-only domains. Never starts with `-`, so it cannot pass as an option.
+/// The server checks its config against this too, so it never pushes what clients refuse.
+pub fn is_search_domain(domain: &str) -> bool {
+    let name = domain.strip_prefix('~').unwrap_or(domain);
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
 }
 
 impl StrongType for RegisterHandlerRequest {
@@ -113,21 +223,48 @@ impl StrongType for RegisterHandlerAnswer {
     }
 }
 
-impl StrongType for ChallengeAuthReq {
+impl StrongType for HandshakeInit {
+    fn get_s_type(&self) -> &dyn StructureType {
+        &self.s_type
+    }
+}
+
+impl StrongType for HandshakeResponse {
     fn get_s_type(&self) -> &dyn StructureType {
         &self.s_type
     }
 }
 
-impl StrongType for ClientAnswerChallenge {
+impl StrongType for HandshakeFinish {
     fn get_s_type(&self) -> &dyn StructureType {
         &self.s_type
     }
 }
 
-impl StrongType for ServerAuthoriChallenge {
+impl StrongType for HandshakeComplete {
     fn get_s_type(&self) -> &dyn StructureType {
         &self.s_type
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_pushed_options_unsafe_to_apply() {
+        let options = |mtu: u32, domain: &str| NetworkOptions {
+            mtu,
+            search_domains: vec![domain.to_string()],
+            ..Default::default()
+        };
+        assert!(options(1400, "corp.example").validate().is_ok());
+        assert!(options(1400, "~corp.example.").validate().is_ok());
+        assert!(options(1279, "corp.example").validate().is_err());
+        assert!(options(70000, "corp.example").validate().is_err());
+        for domain in ["--set-dns=1.1.1.1", "-corp.example", "corp..example", "corp.example; reboot", ""] {
+            assert!(options(1400, domain).validate().is_err(), "{:?} accepted", domain);
+        }
+    }
+}
@@ -1,25 +1,54 @@
 use crate::handlers::actor_structure_type::{
-    ActorStructureType, ClientAnswerChallenge, ServerAuthoriChallenge,
+    ActorStructureType, HandshakeComplete, HandshakeFinish, HandshakeInit, HandshakeResponse,
 };
-use crate::util::challenge_util::generate_challenge_and_encrypt;
+use crate::util::handshake::{
+    decoy_salt, derive_secrets, prove, transcript, verify_proof, EphemeralKey, ServerIdentity, SessionKeys,
+};
+use crate::util::shutdown;
 use crate::vpn_config::VpnConfig;
 use std::collections::HashMap;
 use std::net::{SocketAddr, TcpStream};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tfserver::server::handler::Handler;
 use tfserver::structures::s_type;
 use tfserver::structures::s_type::{StrongType, StructureType};
 use tfserver::tungstenite::WebSocket;
-use crate::handlers::register_handler::RegisterHandler;
+use crate::handlers::register_handler::{AuthorizedClient, RegisterHandler};
+use crate::metrics::Metrics;
+use crate::server::user_store::UserStore;
+use base64::{engine::general_purpose, Engine as _};
+use log::warn;
+
+/// How long a client has between `HandshakeInit` and `HandshakeFinish`.
+const PENDING_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Handshakes waiting for their `HandshakeFinish` at once; further clients are turned away.
+const MAX_PENDING_HANDSHAKES: usize = 1024;
+
+/// Server side state between `HandshakeResponse` and the client's `HandshakeFinish`.
+pub struct PendingHandshake {
+    pub user: String,
+    pub transcript: Vec<u8>,
+    pub client_confirm: Vec<u8>,
+    /// Proves the server side in `HandshakeComplete`, kept back until the client proved itself.
+    pub server_confirm: Vec<u8>,
+    pub keys: SessionKeys,
+    pub started: Instant,
+}
 
 pub struct AuthHandler {
-    pub pending_challenges: HashMap<SocketAddr, String>,
+    pub pending_handshakes: HashMap<SocketAddr, PendingHandshake>,
+    /// Keys the salts answered for unknown names, see `decoy_salt`.
+    pub decoy_secret: [u8; 32],
+    pub users: Arc<Mutex<UserStore>>,
+    pub identity: Option<Arc<ServerIdentity>>,
     pub config: Arc<VpnConfig>,
     pub register_handler: Arc<Mutex<RegisterHandler>>,
+    pub metrics: Arc<Metrics>,
 }
 
-impl Handler for AuthHandler {
-    fn serve_route(
+impl AuthHandler {
+    fn handshake_step(
         &mut self,
         client_meta: SocketAddr,
         s_type: Box<dyn StructureType>,
@@ -30,45 +59,161 @@ impl Handler for AuthHandler {
             .downcast_ref::<ActorStructureType>()
             .unwrap()
         {
-            ActorStructureType::ClientChallengeReq => {
-                let challenge = generate_challenge_and_encrypt(self.config.key.as_str()).unwrap();
-                self.pending_challenges.insert(client_meta, challenge.0);
-                let challenge = ServerAuthoriChallenge {
-                    s_type: ActorStructureType::ServerAuthChallenge,
-                    challenge: challenge.1,
+            ActorStructureType::ClientHandshakeInit => {
+                if shutdown::stop_requested() {
+                    return Err(String::from("server is shutting down").into_bytes());
+                }
+                let request: Result<HandshakeInit, String> = s_type::from_slice(data.as_slice());
+                if request.is_err() {
+                    return Err(request.err().unwrap().to_string().into_bytes());
+                }
+                let request = request.unwrap();
+                self.pending_handshakes
+                    .retain(|_, pending| pending.started.elapsed() < PENDING_HANDSHAKE_TIMEOUT);
+                self.register_handler.lock().unwrap().sweep_abandoned();
+                if self.pending_handshakes.len() >= MAX_PENDING_HANDSHAKES
+                    && !self.pending_handshakes.contains_key(&client_meta)
+                {
+                    warn!("Too many pending handshakes, turning {} away", client_meta);
+                    return Err(String::from("server is busy").into_bytes());
+                }
+                // unknown and disabled names get a made up salt and key and fail at the proof,
+                // exactly like a wrong password, so they cannot be told apart from real users
+                let (user_name, user_salt, user_key) =
+                    match self.users.lock().unwrap().find_active(&request.username) {
+                        Some(user) => (user.name, user.salt, user.key),
+                        None => {
+                            let salt = match decoy_salt(&self.decoy_secret, &request.username) {
+                                Ok(salt) => salt,
+                                Err(err) => {
+                                    warn!("Failed to derive a decoy salt: {}", err);
+                                    return Err(String::from("handshake failed!").into_bytes());
+                                }
+                            };
+                            let key = general_purpose::STANDARD.encode(rand::random::<[u8; 32]>());
+                            (request.username.clone(), salt, key)
+                        }
+                    };
+                let ephemeral = match EphemeralKey::generate() {
+                    Ok(ephemeral) => ephemeral,
+                    Err(err) => {
+                        warn!("Failed to generate an ephemeral key: {}", err);
+                        return Err(String::from("handshake failed!").into_bytes());
+                    }
+                };
+                let shared = ephemeral.diffie_hellman(&request.ephemeral);
+                if shared.is_err() {
+                    return Err(String::from("bad ephemeral key!").into_bytes());
+                }
+                let transcript = transcript(
+                    &user_name,
+                    &request.ephemeral,
+                    &ephemeral.public_b64,
+                    &user_salt,
+                );
+                let secrets = match derive_secrets(&user_key, &shared.unwrap(), &transcript) {
+                    Ok(secrets) => secrets,
+                    Err(err) => {
+                        warn!("Malformed key for user {} in the users file: {}", user_name, err);
+                        return Err(String::from("authentication failed!").into_bytes());
+                    }
                 };
-                return Ok(s_type::to_vec(&challenge).unwrap());
+                let signature = match self.identity.as_ref().map(|identity| identity.sign(&transcript)) {
+                    Some(Ok(signature)) => signature,
+                    Some(Err(err)) => {
+                        warn!("Failed to sign the handshake transcript: {}", err);
+                        return Err(String::from("handshake failed!").into_bytes());
+                    }
+                    None => String::new(),
+                };
+                self.pending_handshakes.insert(
+                    client_meta,
+                    PendingHandshake {
+                        user: user_name,
+                        transcript,
+                        client_confirm: secrets.client_confirm,
+                        server_confirm: secrets.server_confirm,
+                        keys: secrets.keys,
+                        started: Instant::now(),
+                    },
+                );
+                let response = HandshakeResponse {
+                    s_type: ActorStructureType::ServerHandshakeResponse,
+                    ephemeral: ephemeral.public_b64.clone(),
+                    salt: user_salt,
+                    signature,
+                };
+                return Ok(s_type::to_vec(&response).unwrap());
             }
-            ActorStructureType::ClientAuthAnswer => {
-                let answer_real = self.pending_challenges.get(&client_meta);
-                if answer_real.is_none() {
+            ActorStructureType::ClientHandshakeFinish => {
+                let pending = self.pending_handshakes.remove(&client_meta);
+                if pending.is_none() {
                     return Err(String::from("no such pending client!").into_bytes());
                 }
-                let answer_real = answer_real.unwrap();
-                let client_answer: Result<ClientAnswerChallenge, String> =
-                    s_type::from_slice(data.as_slice());
-                if client_answer.is_err() {
-                    return Err(client_answer.err().unwrap().to_string().into_bytes());
+                let pending = pending.unwrap();
+                if pending.started.elapsed() >= PENDING_HANDSHAKE_TIMEOUT {
+                    return Err(String::from("handshake timed out!").into_bytes());
                 }
-                let client_answer = client_answer.unwrap();
-                if client_answer.answer == answer_real.clone() {
-                    let challenge =
-                        generate_challenge_and_encrypt(self.config.key.as_str()).unwrap();
-                    self.register_handler.lock().unwrap().addresses_iv.lock().unwrap().insert(client_meta, challenge.clone());
-                    let challenge = ServerAuthoriChallenge {
-                        s_type: ActorStructureType::ServerAuthChallenge,
-                        challenge: challenge.1,
-                    };
-
-                    return Ok(s_type::to_vec(&challenge).unwrap());
+                let finish: Result<HandshakeFinish, String> = s_type::from_slice(data.as_slice());
+                if finish.is_err() {
+                    return Err(finish.err().unwrap().to_string().into_bytes());
                 }
-                return Err(String::from("challenge failed!").into_bytes());
+                let finish = finish.unwrap();
+                if !verify_proof(&pending.client_confirm, &pending.transcript, &finish.proof) {
+                    return Err(String::from("handshake failed!").into_bytes());
+                }
+                // the user may have been disabled while the handshake was pending
+                if self.users.lock().unwrap().find_active(&pending.user).is_none() {
+                    return Err(String::from("authentication failed!").into_bytes());
+                }
+                let proof = match prove(&pending.server_confirm, &pending.transcript) {
+                    Ok(proof) => proof,
+                    Err(err) => {
+                        warn!("Failed to prove the handshake to {}: {}", client_meta, err);
+                        return Err(String::from("handshake failed!").into_bytes());
+                    }
+                };
+                self.register_handler.lock().unwrap().addresses_iv.lock().unwrap().insert(
+                    client_meta,
+                    AuthorizedClient {
+                        user: pending.user,
+                        keys: pending.keys,
+                        authorized_at: Instant::now(),
+                        registered: false,
+                    },
+                );
+                let complete = HandshakeComplete {
+                    s_type: ActorStructureType::ServerHandshakeComplete,
+                    proof,
+                };
+                return Ok(s_type::to_vec(&complete).unwrap());
             }
             _ => {
                 return Err(String::from("no such structure type!").into_bytes());
             }
         }
     }
+}
+
+impl Handler for AuthHandler {
+    fn serve_route(
+        &mut self,
+        client_meta: SocketAddr,
+        s_type: Box<dyn StructureType>,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, Vec<u8>> {
+        let step = s_type.as_any().downcast_ref::<ActorStructureType>().copied();
+        if step == Some(ActorStructureType::ClientHandshakeInit) {
+            self.metrics.handshakes_started.inc();
+        }
+        let result = self.handshake_step(client_meta, s_type, data);
+        match (&result, step) {
+            (Err(_), _) => self.metrics.handshakes_failed.inc(),
+            (Ok(_), Some(ActorStructureType::ClientHandshakeFinish)) => self.metrics.handshakes_completed.inc(),
+            _ => {}
+        }
+        result
+    }
 
     fn request_to_move_stream(&self) -> Option<Vec<SocketAddr>> {
         None
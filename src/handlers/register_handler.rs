@@ -1,52 +1,213 @@
+use log::{info, warn};
 use std::collections::HashMap;
 use crate::front_interface::jni_receiver::JniReceiver;
-use crate::operational::packet_router::{AddressTuple, PacketRouter};
+use crate::operational::packet_router::{AddressRequest, AddressTuple, PacketReceiver, PacketRouter};
 use crate::server::receiver_info::ReceiverInfo;
 use crate::vpn_config::VpnConfig;
-use std::net::{SocketAddr, TcpStream};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 use tfserver::server::handler::Handler;
 use tfserver::structures::s_type;
 use tfserver::structures::s_type::StructureType;
 use tfserver::tungstenite::WebSocket;
-use tfserver::util::data_cipher::DataCipher;
-use crate::handlers::actor_structure_type::{ActorStructureType, RegisterHandlerAnswer};
+use crate::metrics::Metrics;
+use crate::handlers::actor_structure_type::{ActorStructureType, NetworkOptions, RegisterHandlerAnswer, RegisterHandlerRequest, NETWORK_OPTIONS_VERSION};
+use crate::server::lease_store::LeaseStore;
 use crate::server::proxy_internal_server::ProxyServerInternal;
+use crate::server::user_store::UserStore;
+use crate::operational::frame_cipher::{open_message, seal_message};
+use crate::util::handshake::SessionKeys;
+use crate::util::shutdown;
+
+/// How long a client has from completing the handshake until its stream reaches the proxy.
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client that completed the handshake and may register, with its session traffic keys.
+#[derive(Clone)]
+pub struct AuthorizedClient {
+    pub user: String,
+    pub keys: SessionKeys,
+    pub authorized_at: Instant,
+    /// Set once it registered, from then on the session teardown forgets it.
+    pub registered: bool,
+}
 
 pub struct RegisterHandler {
-    pub(crate) addresses_iv: Arc<Mutex<HashMap<SocketAddr, (String, String)>>>,
-    pub(crate) router: Arc<Mutex<PacketRouter>>,
-    pub(crate) config: Arc<VpnConfig>,
-    pub(crate) pending_receivers: Arc<Mutex<HashMap<SocketAddr, ReceiverInfo>>>,
-    pub(crate) proxy_server: Arc<Mutex<ProxyServerInternal>>,
+    pub addresses_iv: Arc<Mutex<HashMap<SocketAddr, AuthorizedClient>>>,
+    pub router: Arc<Mutex<PacketRouter>>,
+    pub config: Arc<VpnConfig>,
+    pub pending_receivers: Arc<Mutex<HashMap<SocketAddr, ReceiverInfo>>>,
+    pub proxy_server: Arc<Mutex<ProxyServerInternal>>,
+    pub users: Arc<Mutex<UserStore>>,
+    pub leases: Arc<Mutex<LeaseStore>>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl RegisterHandler {
+    /// Forgets clients that did not register within `REGISTRATION_TIMEOUT` of their handshake,
+    /// and registrations whose stream never reached the proxy, releasing their addresses.
+    pub fn sweep_abandoned(&self) {
+        let mut receivers = self.pending_receivers.lock().unwrap();
+        let mut clients = self.addresses_iv.lock().unwrap();
+        let abandoned: Vec<SocketAddr> = receivers
+            .iter()
+            .filter(|(_, info)| info.connected_at.elapsed() >= REGISTRATION_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in abandoned {
+            let info = receivers.remove(&peer).unwrap();
+            warn!("{} registered from {} but its stream never arrived, releasing {}", info.user, peer, info.ipv4addr);
+            self.router.lock().unwrap().deregister(info.ipv4addr);
+            clients.remove(&peer);
+        }
+        clients.retain(|_, client| client.registered || client.authorized_at.elapsed() < REGISTRATION_TIMEOUT);
+    }
+
+    /// Serializes `answer` and seals it for the client, it carries the DNS servers and routes
+    /// the client installs and must not be open to tampering.
+    fn seal_answer(keys: &SessionKeys, answer: &RegisterHandlerAnswer) -> Result<Vec<u8>, Vec<u8>> {
+        let data = s_type::to_vec(answer).map_err(String::into_bytes)?;
+        seal_message(&keys.server_to_client, &data).map_err(|err| {
+            warn!("Failed to seal the registration answer: {}", err);
+            String::from("registration failed!").into_bytes()
+        })
+    }
+
+    fn network_options(&self) -> NetworkOptions {
+        let server = &self.config.server;
+        NetworkOptions {
+            version: NETWORK_OPTIONS_VERSION,
+            ipv4_prefix_len: server.ipv4_cidr.prefix_len(),
+            ipv6_prefix_len: server.ipv6_prefix.prefix_len(),
+            gateway_ipv4: server.ipv4_cidr.addr(),
+            gateway_ipv6: server.ipv6_prefix.addr(),
+            dns_servers: server.push.dns_servers.clone(),
+            search_domains: server.push.search_domains.clone(),
+            mtu: server.push.mtu,
+            routes: server.push.routes.clone(),
+        }
+    }
+
+    /// Picks the address for `user`: their static reservation, else their lease, else the one
+    /// the client asks for. Also refreshes which addresses the pool has to keep for others.
+    fn address_request(&self, user: &str, requested: Option<Ipv4Addr>) -> AddressRequest {
+        let (reservation, reserved) = {
+            let mut users = self.users.lock().unwrap();
+            let reservation = users
+                .find_active(user)
+                .and_then(|entry| entry.static_ipv4.map(|ipv4| (ipv4, entry.static_ipv6)));
+            (reservation, users.reservations())
+        };
+        let mut leases = self.leases.lock().unwrap();
+        leases.expire();
+        // a reservation made since the lease was handed out wins over it
+        let lease = leases
+            .lease_for(user)
+            .map(|lease| lease.ipv4)
+            .filter(|ipv4| !reserved.contains(ipv4));
+        let held: Vec<Ipv4Addr> = reserved.into_iter().chain(leases.leased_addresses()).collect();
+        self.router.lock().unwrap().pool_mut().set_held(held);
+        match (reservation, lease, requested) {
+            (Some((ipv4, ipv6)), _, _) => AddressRequest::Fixed(ipv4, ipv6),
+            (None, Some(ipv4), _) => AddressRequest::Fixed(ipv4, None),
+            (None, None, Some(ipv4)) => AddressRequest::Preferred(ipv4),
+            (None, None, None) => AddressRequest::Any,
+        }
+    }
 }
 
 impl Handler for RegisterHandler {
     fn serve_route(&mut self, client_meta: SocketAddr, s_type: Box<dyn StructureType>, data: Vec<u8>) -> Result<Vec<u8>, Vec<u8>> {
+        if shutdown::stop_requested() {
+            return Err("server is shutting down".as_bytes().to_vec());
+        }
+        self.sweep_abandoned();
         let binding = self.addresses_iv.lock().unwrap();
-        let iv = binding.get(&client_meta);
-        if iv.is_none() {
+        let client = binding.get(&client_meta);
+        if client.is_none() {
             return Err("No such authorized address!".as_bytes().to_vec());
         }
-        let iv = iv.unwrap().clone();
+        let client = client.unwrap().clone();
         drop(binding);
 
-        let receiver = JniReceiver::new(self.config.clone().deref(), iv.0.clone());
-        let reg_data1 = self.router.lock().unwrap().register(Arc::new(Mutex::new(receiver)));
+        let data = match open_message(&client.keys.client_to_server, &data) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Dropping registration request from {}: {}", client_meta, err);
+                return Err(String::from("malformed registration request").into_bytes());
+            }
+        };
+        let request: Result<RegisterHandlerRequest, String> = s_type::from_slice(data.as_slice());
+        if request.is_err() {
+            return Err(request.err().unwrap().to_string().into_bytes());
+        }
+        let request = request.unwrap();
+        let requested: Option<Ipv4Addr> = request.requested_ipv4.and_then(|ip| ip.parse().ok());
+        let address_request = self.address_request(&client.user, requested);
+        let target = match &address_request {
+            AddressRequest::Fixed(ipv4, _) | AddressRequest::Preferred(ipv4) => Some(*ipv4),
+            AddressRequest::Any => None,
+        };
+        if let Some(target) = target {
+            if self.proxy_server.lock().unwrap().evict_stale_session(&client.user, target) {
+                info!("{} resumed its session on {}", client.user, target);
+            }
+        }
+
+        let mut jni_receiver = match JniReceiver::new(self.config.clone().deref(), client.keys.clone(), self.metrics.clone()) {
+            Ok(jni_receiver) => jni_receiver,
+            Err(err) => {
+                warn!("Refusing registration of {}: {}", client.user, err);
+                self.metrics.registrations_failed.inc();
+                self.addresses_iv.lock().unwrap().remove(&client_meta);
+                return Err(String::from("registration failed!").into_bytes());
+            }
+        };
+        jni_receiver.set_peer_max_frame_size(request.max_frame_size as usize);
+        let receiver: Arc<Mutex<dyn PacketReceiver>> = Arc::new(Mutex::new(jni_receiver));
+        let fixed = matches!(address_request, AddressRequest::Fixed(..));
+        let mut registered = self.router.lock().unwrap().register(receiver.clone(), address_request);
+        if let (Err(err), true) = (&registered, fixed) {
+            warn!("Reserved or leased address of {} unavailable, assigning another: {}", client.user, err);
+            registered = self.router.lock().unwrap().register(receiver, AddressRequest::Any);
+        }
+        let reg_data1 = match registered {
+            Ok(reg_data1) => reg_data1,
+            Err(err) => {
+                warn!("Refusing registration of {}: {}", client.user, err);
+                self.metrics.registrations_failed.inc();
+                self.addresses_iv.lock().unwrap().remove(&client_meta);
+                let answer = RegisterHandlerAnswer{s_type: ActorStructureType::RegisterHandlerAnswer, ipv4: String::new(),
+                    ipv6: String::new(), error: Some(err.to_string()), network: Vec::new(),
+                    max_frame_size: self.config.max_frame_size as u64};
+                return Self::seal_answer(&client.keys, &answer);
+            }
+        };
+        self.leases.lock().unwrap().renew(&client.user, reg_data1.0, reg_data1.1);
+        self.metrics.registrations.inc();
         let reg_data = RegisterHandlerAnswer{s_type: ActorStructureType::RegisterHandlerAnswer, ipv4: reg_data1.0.to_string(),
-            ipv6: reg_data1.1.to_string()};
-        let cipher = DataCipher::new_init(self.config.encryption_type, self.config.key.clone());
-        let data = s_type::to_vec_encrypted(&reg_data, self.config.encryption_type, self.config.key.clone(), iv.0.clone().as_bytes()).unwrap();
+            ipv6: reg_data1.1.to_string(), error: None, network: self.network_options().encode(),
+            max_frame_size: self.config.max_frame_size as u64};
+        let data = Self::seal_answer(&client.keys, &reg_data)?;
         let receiver_info = ReceiverInfo {
-            cipher,
             ipv4addr: reg_data1.0,
             ipv6addr: reg_data1.1,
-            iv: iv.0.clone(),
+            user: client.user.clone(),
             receiver_handle: reg_data1.2,
+            peer: client_meta,
+            connected_at: Instant::now(),
+            last_seen: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            disconnect_reason: None,
         };
         self.pending_receivers.lock().unwrap().insert(client_meta, receiver_info);
+        if let Some(client) = self.addresses_iv.lock().unwrap().get_mut(&client_meta) {
+            client.registered = true;
+        }
         Ok(data)
     }
 
@@ -68,9 +229,125 @@ impl Handler for RegisterHandler {
         let mut streams_lock = binding.streams_in_handle.lock().unwrap();
         while !stream.is_empty(){
             let stream_c = stream.pop().unwrap();
-            let data = receivers_lock.remove(stream_c.lock().unwrap().get_ref().peer_addr().as_ref().unwrap()).unwrap();
+            let peer = match stream_c.lock().unwrap().get_ref().peer_addr() {
+                Ok(peer) => peer,
+                Err(err) => {
+                    // gone before it was handed over, there is nothing to match it to its registration by
+                    warn!("Dropping a registered stream without a peer address: {}", err);
+                    continue;
+                }
+            };
+            let data = match receivers_lock.remove(&peer) {
+                Some(data) => data,
+                None => {
+                    warn!("Dropping stream of {}, it never registered", peer);
+                    continue;
+                }
+            };
+            // the proxy workers poll every stream, they must never park on a silent one
+            if let Err(err) = stream_c.lock().unwrap().get_ref().set_nonblocking(true) {
+                warn!("Dropping stream of {}: {}", peer, err);
+                self.router.lock().unwrap().deregister(data.ipv4addr);
+                continue;
+            }
             streams_lock.insert(AddressTuple::new_full(data.ipv4addr.clone(), data.ipv6addr.clone()),
                                 ((stream_c, Arc::new(Mutex::new(data))), Arc::new(Mutex::new(AtomicBool::new(false)))));
+            self.metrics.sessions_active.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operational::packet_router::PacketRouterCreateInfo;
+    use crate::operational::tun_interface::TunInterface;
+    use crate::util::handshake::derive_secrets;
+    use crate::util::testing::{example_config, TempPath};
+    use base64::{engine::general_purpose, Engine as _};
+    use tfserver::util::thread_pool::ThreadPool;
+
+    /// The handler along with the directory holding its users and leases files.
+    fn register_handler(name: &str) -> (RegisterHandler, TempPath) {
+        let dir = TempPath::dir(&format!("register-{}", name));
+        let config = Arc::new(example_config());
+        let metrics = Arc::new(Metrics::new(false));
+        let router = Arc::new(Mutex::new(PacketRouter::new(PacketRouterCreateInfo {
+            ipv4_cidr: config.server.ipv4_cidr,
+            ipv6_prefix: config.server.ipv6_prefix,
+            tun_interface: Arc::new(Mutex::new(TunInterface::closed())),
+            resyncer_timeout: Duration::from_millis(100),
+            max_packets_attempts_amount: 1,
+            client_to_client: false,
+            metrics: metrics.clone(),
+        })));
+        let leases = Arc::new(Mutex::new(LeaseStore::open(&dir.join("leases.toml"), Duration::from_secs(3600)).unwrap()));
+        let addresses_iv = Arc::new(Mutex::new(HashMap::new()));
+        let proxy_server = ProxyServerInternal::new(
+            config.clone(),
+            router.clone(),
+            ThreadPool::new(1),
+            addresses_iv.clone(),
+            leases.clone(),
+            metrics.clone(),
+        );
+        let handler = RegisterHandler {
+            addresses_iv,
+            router,
+            config,
+            pending_receivers: Arc::new(Mutex::new(HashMap::new())),
+            proxy_server: Arc::new(Mutex::new(proxy_server)),
+            users: Arc::new(Mutex::new(UserStore::open(&dir.join("users.toml")).unwrap())),
+            leases,
+            metrics,
+        };
+        (handler, dir)
+    }
+
+    fn keys() -> SessionKeys {
+        let user_key = general_purpose::STANDARD.encode([0x5a; 32]);
+        derive_secrets(&user_key, &[7; 32], b"test").unwrap().keys
+    }
+
+    fn client(authorized_at: Instant, registered: bool) -> AuthorizedClient {
+        AuthorizedClient { user: String::from("alice"), keys: keys(), authorized_at, registered }
+    }
+
+    #[test]
+    fn abandoned_clients_are_swept() {
+        let (handler, _dir) = register_handler("sweep");
+        let expired = Instant::now().checked_sub(REGISTRATION_TIMEOUT).unwrap();
+        let peer = |port: u16| SocketAddr::from(([192, 0, 2, 1], port));
+        {
+            let mut clients = handler.addresses_iv.lock().unwrap();
+            clients.insert(peer(1), client(expired, false));
+            clients.insert(peer(2), client(Instant::now(), false));
+            clients.insert(peer(3), client(expired, true));
+            clients.insert(peer(4), client(expired, true));
         }
+        // registered, but its stream never reached the proxy
+        let receiver: Arc<Mutex<dyn PacketReceiver>> =
+            Arc::new(Mutex::new(JniReceiver::new(&handler.config, keys(), handler.metrics.clone()).unwrap()));
+        let (ipv4addr, ipv6addr, receiver_handle) =
+            handler.router.lock().unwrap().register(receiver, AddressRequest::Any).unwrap();
+        handler.pending_receivers.lock().unwrap().insert(peer(4), ReceiverInfo {
+            ipv4addr,
+            ipv6addr,
+            user: String::from("alice"),
+            receiver_handle,
+            peer: peer(4),
+            connected_at: expired,
+            last_seen: expired,
+            bytes_in: 0,
+            bytes_out: 0,
+            disconnect_reason: None,
+        });
+
+        handler.sweep_abandoned();
+        let mut left: Vec<SocketAddr> = handler.addresses_iv.lock().unwrap().keys().copied().collect();
+        left.sort();
+        assert_eq!(left, [peer(2), peer(3)]);
+        assert!(handler.pending_receivers.lock().unwrap().is_empty());
+        assert_eq!(handler.router.lock().unwrap().pool().in_use(), 0);
     }
 }
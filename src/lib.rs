@@ -0,0 +1,15 @@
+//! Everything but argument handling and the main loops, shared by `actor-server`,
+//! `actor-client` and the fuzz targets under `fuzz/`.
+
+pub mod error;
+pub mod front_interface;
+pub mod handlers;
+pub mod metrics;
+pub mod netconfig;
+pub mod operational;
+pub mod receivers;
+pub mod router_setup;
+pub mod server;
+pub mod util;
+pub mod verbose;
+pub mod vpn_config;
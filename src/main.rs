@@ -1,93 +1,269 @@
-use crate::handlers::actor_structure_type::ActorStructureType;
-use crate::handlers::auth_handler::AuthHandler;
-use crate::handlers::register_handler::RegisterHandler;
-use crate::operational::packet_router::{PacketRouter, PacketRouterCreateInfo};
-use crate::operational::tun_interface::{TunInterface, TunInterfaceCreateInfo};
-use crate::vpn_config::VpnConfig;
+use actor::handlers::actor_structure_type::ActorStructureType;
+use actor::handlers::auth_handler::AuthHandler;
+use actor::handlers::register_handler::RegisterHandler;
+use actor::metrics::Metrics;
+use actor::netconfig::journal::{self, Journal};
+use actor::netconfig::netlink::Netlink;
+use actor::netconfig::{NetBackend, NetError};
+use actor::operational::address_pool::AddressPool;
+use actor::operational::packet_router::{PacketRouter, PacketRouterCreateInfo};
+use actor::operational::tun_interface::{TunInterface, TunInterfaceCreateInfo};
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::thread::sleep;
 use std::time::Duration;
 use tfserver::server::server_router::TcpServerRouter;
 use tfserver::server::tcp_server_new::TcpServer;
-use tfserver::util::data_cipher::EncryptionType;
 use tfserver::util::thread_pool::ThreadPool;
-use crate::server::proxy_internal_server::ProxyServerInternal;
+use actor::server::admin::AdminServer;
+use actor::server::proxy_internal_server::ProxyServerInternal;
+use actor::server::lease_store::LeaseStore;
+use actor::server::user_store::{run_user_command, UserStore};
+use actor::util::handshake::ServerIdentity;
+use actor::util::shutdown;
+use actor::{metrics, router_setup, vpn_config};
+use actor::verbose::logger::Logger;
+use log::{error, info, warn};
+use actor::vpn_config::{ConfigRole, ServerConfig, VpnConfig};
 
-pub mod front_interface;
-pub mod handlers;
-pub mod operational;
-pub mod router_setup;
-pub mod server;
-pub mod util;
-pub mod verbose;
-pub mod vpn_config;
 
 fn main() {
-    let config = Arc::new(VpnConfig {
-        key: "HelloWorldEncKey".to_string(),
-        hostname: "192.168.88.247".to_string(),
-        encryption_type: EncryptionType::Aes256Ctr,
-        port: 8090,
-        garbage_packet_min_size: 1,
-        garbage_packet_max_size: 25,
-        max_garbage_packets_amount: 20,
-        min_garbage_packets_amount: 5,
-        max_packets_in_flight: 2,
-        mtu_min: 9000,
-        mtu_max: 1000,
-        resyncer_timeout_ms: 3,
+    let config = Arc::new(vpn_config::load_from_args_or_exit("actor-server", ConfigRole::Server));
+    let command = vpn_config::command_from_args(&std::env::args().collect::<Vec<String>>());
+    match command.first().map(String::as_str) {
+        None | Some("cleanup") => {}
+        Some("user") => {
+            let pool = AddressPool::new(config.server.ipv4_cidr, config.server.ipv6_prefix);
+            match run_user_command(&config.server.users_file, &pool, &command[1..]) {
+                Ok(()) => return,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(other) => {
+            eprintln!("unknown command {}, expected user or cleanup", other);
+            std::process::exit(2);
+        }
+    }
+    if let Err(err) = Logger::init(&config.log) {
+        eprintln!("Failed to set up logging: {}", err);
+        std::process::exit(1);
+    }
+    if command.first().map(String::as_str) == Some("cleanup") {
+        if let Err(err) = journal::run_cleanup(&config.server.journal_file) {
+            error!("Cleanup failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    shutdown::install_handlers().expect("Failed to install signal handlers");
+    let identity = config.server.identity_key_file.as_ref().map(|path| {
+        match ServerIdentity::load_or_generate(path) {
+            Ok(identity) => {
+                // printed rather than logged, it is meant to be copied into client configs and
+                // the log would redact it
+                println!("Server identity public key: {}", identity.public_b64);
+                Arc::new(identity)
+            }
+            Err(err) => {
+                error!("Failed to load identity key from {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
     });
+    let users = match UserStore::open(&config.server.users_file) {
+        Ok(users) => Arc::new(Mutex::new(users)),
+        Err(err) => {
+            error!("Failed to load users from {}: {}", config.server.users_file.display(), err);
+            std::process::exit(1);
+        }
+    };
+    let leases = match LeaseStore::open(&config.server.leases_file, Duration::from_secs(config.server.lease_expiry_secs)) {
+        Ok(leases) => Arc::new(Mutex::new(leases)),
+        Err(err) => {
+            error!("Failed to load leases from {}: {}", config.server.leases_file.display(), err);
+            std::process::exit(1);
+        }
+    };
+    let server_config = &config.server;
     let mut tun_info = TunInterfaceCreateInfo::default();
-    let netmask = "255.255.255.0".to_string();
-    let addr = Ipv4Addr::new(10, 0, 8, 1);
-    tun_info.set_iff_ip(&addr);
-    tun_info.set_iff_netmask(&netmask);
-    tun_info.set_iff_name("tun0".to_string());
-    let tun_interface = Arc::new(Mutex::new(TunInterface::new(&tun_info)));
+    tun_info.set_iff_ip(&server_config.ipv4_cidr.addr());
+    tun_info.set_iff_netmask(&server_config.ipv4_cidr.netmask());
+    tun_info.set_iff_name(server_config.tun_name.clone());
+    let tun_interface = match TunInterface::new(&tun_info) {
+        Ok(tun_interface) => Arc::new(Mutex::new(tun_interface)),
+        Err(err) => {
+            error!("Failed to create {}: {}", server_config.tun_name, err);
+            std::process::exit(1);
+        }
+    };
+    let mut journal = match configure_network(server_config) {
+        Ok(journal) => journal,
+        Err(err) => {
+            error!("Failed to configure the host network: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let metrics = Arc::new(Metrics::new(config.metrics.per_session));
     let create_info = PacketRouterCreateInfo {
-        router_subnet: Ipv4Addr::from_str("10.0.8.1").unwrap(),
-        router_subnet_ipv6: Ipv6Addr::from_str("2001:db8:3333:4444:5555:6666:7777:1").unwrap(),
-        tun_interface,
+        ipv4_cidr: server_config.ipv4_cidr,
+        ipv6_prefix: server_config.ipv6_prefix,
+        tun_interface: tun_interface.clone(),
         resyncer_timeout: Duration::from_millis(config.resyncer_timeout_ms as u64),
         max_packets_attempts_amount: config.max_packets_in_flight,
+        client_to_client: server_config.client_to_client,
+        metrics: metrics.clone(),
     };
     let packet_router = Arc::new(Mutex::new(PacketRouter::new(create_info)));
-    let mut router = TcpServerRouter::new(Box::from(ActorStructureType::ClientChallengeReq));
-    let proxy_server = Arc::new(Mutex::new(ProxyServerInternal::new(config.clone(), packet_router.clone(), Arc::new(Mutex::new(ThreadPool::new(15))))));
+    let mut router = TcpServerRouter::new(Box::from(ActorStructureType::ClientHandshakeInit));
+    let authorized_clients = Arc::new(Mutex::new(HashMap::new()));
+    let proxy_server = Arc::new(Mutex::new(ProxyServerInternal::new(config.clone(), packet_router.clone(), ThreadPool::new(server_config.proxy_workers), authorized_clients.clone(), leases.clone(), metrics.clone())));
 
     let register_handler = Arc::new(Mutex::new(RegisterHandler {
-        addresses_iv: Arc::new(Mutex::new(HashMap::new())),
+        addresses_iv: authorized_clients,
         router: packet_router.clone(),
         config: config.clone(),
         pending_receivers: Arc::new(Mutex::new(HashMap::new())),
         proxy_server: proxy_server.clone(),
+        users: users.clone(),
+        leases: leases.clone(),
+        metrics: metrics.clone(),
+    }));
+    let auth_handler = Arc::new(Mutex::new(AuthHandler {
+        pending_handshakes: HashMap::new(),
+        decoy_secret: rand::random(),
+        users: users.clone(),
+        identity,
+        config: config.clone(),
+        register_handler: register_handler.clone(),
+        metrics: metrics.clone(),
     }));
     router.add_route(
-        Arc::new(Mutex::new(AuthHandler {
-            pending_challenges: HashMap::new(),
-            config: config.clone(),
-            register_handler: register_handler.clone(),
-        })),
+        auth_handler.clone(),
         "AUTH_HANDLER".to_string(),
         vec![
-            Box::from(ActorStructureType::ClientChallengeReq),
-            Box::from(ActorStructureType::ClientAuthAnswer),
+            Box::from(ActorStructureType::ClientHandshakeInit),
+            Box::from(ActorStructureType::ClientHandshakeFinish),
         ],
     );
     router.add_route(
-        register_handler,
+        register_handler.clone(),
         "REGISTER_HANDLER".to_string(),
         vec![Box::from(ActorStructureType::RegisterHandlerRequest)],
     );
     router.commit_routes();
     let router = Arc::new(router);
-    let server = Arc::new(Mutex::new(TcpServer::new("127.0.0.1:8090".to_string(), router, ThreadPool::new(5))));
+    let mut listeners = Vec::new();
+    for address in server_config.bind_addresses(&config) {
+        let server = Arc::new(Mutex::new(TcpServer::new(
+            address,
+            router.clone(),
+            ThreadPool::new(server_config.handshake_workers),
+        )));
+        TcpServer::start(server.clone());
+        listeners.push(server);
+    }
+    ProxyServerInternal::start(proxy_server.clone());
+    let admin = server_config.admin_socket.as_ref().and_then(|path| {
+        let admin = AdminServer {
+            proxy_server: proxy_server.clone(),
+            router: packet_router.clone(),
+            users,
+            leases,
+        };
+        match admin.start(path) {
+            Ok(handle) => Some(handle),
+            Err(err) => {
+                warn!("Admin API disabled, failed to bind {}: {}", path.display(), err);
+                None
+            }
+        }
+    });
+    let exporter = config.metrics.listen.as_ref().and_then(|listen| {
+        match metrics::http::start(listen, metrics.clone()) {
+            Ok(handle) => Some(handle),
+            Err(err) => {
+                warn!("Metrics disabled, failed to bind {}: {}", listen, err);
+                None
+            }
+        }
+    });
+    let mut current = config.clone();
+    while !shutdown::stop_requested() {
+        if shutdown::take_reload_request() {
+            current = reload_config(&current, &packet_router, &proxy_server, &register_handler, &auth_handler);
+        }
+        sleep(Duration::from_millis(200));
+    }
 
-    TcpServer::start(server);
-    ProxyServerInternal::start(proxy_server);
-    loop {
+    info!("Shutting down");
+    // stop accepting before the sessions go, a connection taken in the meantime would only hang
+    for listener in &listeners {
+        listener.lock().unwrap().stop();
+    }
+    ProxyServerInternal::stop(&proxy_server);
+    if let Some(admin) = admin {
+        let _ = admin.join();
+    }
+    if let Some(exporter) = exporter {
+        let _ = exporter.join();
+    }
+    tun_interface.lock().unwrap().close();
+    let mut status = 0;
+    if let Err(err) = journal.revert() {
+        error!("Failed to revert network changes, run `cleanup` to retry: {}", err);
+        status = 1;
+    }
+    std::process::exit(status);
+}
 
+/// Reads the config again on SIGHUP and hands it to everything holding a copy, keeping the
+/// settings that need a restart as they are. A config that fails to load changes nothing.
+fn reload_config(
+    current: &Arc<VpnConfig>,
+    packet_router: &Arc<Mutex<PacketRouter>>,
+    proxy_server: &Arc<Mutex<ProxyServerInternal>>,
+    register_handler: &Arc<Mutex<RegisterHandler>>,
+    auth_handler: &Arc<Mutex<AuthHandler>>,
+) -> Arc<VpnConfig> {
+    let reloaded = match vpn_config::reload_from_args(ConfigRole::Server) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Keeping the current configuration: {}", err);
+            return current.clone();
+        }
+    };
+    let (reloaded, pending) = current.server_reload(reloaded);
+    for field in pending {
+        warn!("{} changed, restart to apply it", field);
+    }
+    let reloaded = Arc::new(reloaded);
+    Logger::reconfigure(&reloaded.log);
+    packet_router.lock().unwrap().set_client_to_client(reloaded.server.client_to_client);
+    proxy_server.lock().unwrap().set_config(reloaded.clone());
+    register_handler.lock().unwrap().config = reloaded.clone();
+    auth_handler.lock().unwrap().config = reloaded.clone();
+    info!("Configuration reloaded");
+    reloaded
+}
+
+/// Forwards and masquerades client traffic, journaling every change so shutdown can take it back.
+fn configure_network(server_config: &ServerConfig) -> Result<Journal<Netlink>, NetError> {
+    let mut journal = Journal::open(Netlink::open()?, &server_config.journal_file)?;
+    let egress = match &server_config.egress_interface {
+        Some(interface) => interface.clone(),
+        None => journal
+            .default_routes(false)?
+            .into_iter()
+            .find_map(|route| route.interface)
+            .ok_or(NetError::NoDefaultRoute)?,
+    };
+    let result = router_setup::setup_tun_interface(&mut journal, &server_config.tun_name, server_config.ipv6_prefix, &egress, server_config.firewall);
+    if let Err(err) = result {
+        let _ = journal.revert();
+        return Err(err);
     }
+    Ok(journal)
 }
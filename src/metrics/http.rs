@@ -0,0 +1,83 @@
+use log::{error, warn};
+use crate::metrics::Metrics;
+use crate::util::deadline::DeadlineReader;
+use crate::util::poll::wait_readable;
+use crate::util::semaphore::Semaphore;
+use crate::util::shutdown;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A scrape that has not sent its whole request by then is dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Request line and headers together, a scrape needs far less.
+const MAX_REQUEST_LEN: u64 = 8192;
+/// Scrapes served at once, further connections are closed right away.
+const MAX_CONNECTIONS: usize = 8;
+
+/// Serves `GET /metrics` on `listen` until shutdown, every connection on its own thread so a
+/// slow client does not hold up the others, up to `MAX_CONNECTIONS` of them. Meant for a
+/// loopback address, there is no authentication.
+pub fn start(listen: &str, metrics: Arc<Metrics>) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(listen)?;
+    listener.set_nonblocking(true)?;
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    Ok(spawn(move || {
+        while !shutdown::stop_requested() {
+            if !wait_readable(listener.as_raw_fd(), 200) {
+                continue;
+            }
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if !connections.try_acquire() {
+                        warn!("Too many metrics connections, turning {} away", peer);
+                        continue;
+                    }
+                    let (metrics, connections) = (metrics.clone(), connections.clone());
+                    spawn(move || {
+                        if let Err(err) = serve(stream, &metrics) {
+                            warn!("Metrics request failed: {}", err);
+                        }
+                        connections.release();
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => error!("Failed to accept metrics connection: {}", err),
+            }
+        }
+    }))
+}
+
+fn serve(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    // past the cap reads hit end of input, which ends the request line and the headers
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    let mut reader = BufReader::new(DeadlineReader::new(stream.try_clone()?, deadline).take(MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers carry nothing we need, but are read so the client sees an orderly close
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "only GET is supported\n".to_string()),
+    };
+    let mut writer = stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
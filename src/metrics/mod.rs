@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering::Relaxed};
+use std::sync::Mutex;
+
+pub mod http;
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Relaxed)
+    }
+}
+
+#[derive(Default, Clone)]
+struct SessionCounters {
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// Counters of one process, shared by everything that moves traffic or handles handshakes
+/// and rendered in the Prometheus text format. Fields a binary has no use for stay at zero.
+#[derive(Default)]
+pub struct Metrics {
+    pub handshakes_started: Counter,
+    pub handshakes_completed: Counter,
+    /// Unknown users, bad keys and wrong proofs on the server, aborted handshakes on the client.
+    pub handshakes_failed: Counter,
+    pub registrations: Counter,
+    pub registrations_failed: Counter,
+    pub sessions_active: Gauge,
+    pub sessions_closed: Counter,
+    /// Sessions closed because of a frame that failed to open or parse, or failed I/O.
+    pub session_errors: Counter,
+    /// Client reconnect attempts after the connection dropped.
+    pub reconnects: Counter,
+    /// Bytes of sealed frames read from and written to the WebSocket connections.
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    pub frames_received: Counter,
+    pub frames_sent: Counter,
+    /// IP packets read from the TUN device and written to it.
+    pub packets_from_tun: Counter,
+    pub packets_to_tun: Counter,
+    /// Server only: packets delivered from one client straight to another.
+    pub packets_between_clients: Counter,
+    /// Packets the TUN device refused, and on the server packets with no session to deliver them
+    /// to or between clients when that is off.
+    pub packets_dropped: Counter,
+    /// Server only: packets a session sent from an address it was not assigned, dropped.
+    pub packets_spoofed: Counter,
+    /// Garbage packets `DataPack` mixed into outgoing frames.
+    pub garbage_packets: Counter,
+    /// Packets collected from the sessions in the router's last pass, waiting for delivery.
+    pub router_queue_depth: Gauge,
+    /// Label every session's byte counters with its user and address, one series per session.
+    per_session: bool,
+    sessions: Mutex<BTreeMap<(String, Ipv4Addr), SessionCounters>>,
+}
+
+impl Metrics {
+    pub fn new(per_session: bool) -> Metrics {
+        Self {
+            per_session,
+            ..Default::default()
+        }
+    }
+
+    /// Adds to the byte counters of one session, a no-op unless per-session series are enabled.
+    pub fn session_traffic(&self, user: &str, ipv4: Ipv4Addr, bytes_in: u64, bytes_out: u64) {
+        if !self.per_session {
+            return;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        let counters = sessions.entry((user.to_string(), ipv4)).or_default();
+        counters.bytes_in += bytes_in;
+        counters.bytes_out += bytes_out;
+    }
+
+    /// Drops the series of a closed session so they do not pile up.
+    pub fn session_closed(&self, user: &str, ipv4: Ipv4Addr) {
+        self.sessions_closed.inc();
+        self.sessions_active.dec();
+        if self.per_session {
+            self.sessions.lock().unwrap().remove(&(user.to_string(), ipv4));
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters: [(&str, &str, &Counter); 18] = [
+            ("handshakes_started_total", "Handshakes begun.", &self.handshakes_started),
+            ("handshakes_completed_total", "Handshakes that proved both sides.", &self.handshakes_completed),
+            ("handshakes_failed_total", "Handshakes rejected or aborted.", &self.handshakes_failed),
+            ("registrations_total", "Tunnel addresses assigned.", &self.registrations),
+            ("registrations_failed_total", "Registrations refused.", &self.registrations_failed),
+            ("sessions_closed_total", "Sessions torn down.", &self.sessions_closed),
+            ("session_errors_total", "Sessions closed because of an error.", &self.session_errors),
+            ("reconnects_total", "Reconnect attempts.", &self.reconnects),
+            ("received_bytes_total", "Bytes of frames received.", &self.bytes_received),
+            ("sent_bytes_total", "Bytes of frames sent.", &self.bytes_sent),
+            ("received_frames_total", "Frames received.", &self.frames_received),
+            ("sent_frames_total", "Frames sent.", &self.frames_sent),
+            ("tun_read_packets_total", "IP packets read from the TUN device.", &self.packets_from_tun),
+            ("tun_written_packets_total", "IP packets written to the TUN device.", &self.packets_to_tun),
+            ("client_to_client_packets_total", "Packets delivered between clients.", &self.packets_between_clients),
+            ("dropped_packets_total", "Packets with nowhere to go.", &self.packets_dropped),
+            ("spoofed_packets_total", "Packets sent from another address than the session's.", &self.packets_spoofed),
+            ("garbage_packets_total", "Garbage packets mixed into frames.", &self.garbage_packets),
+        ];
+        for (name, help, counter) in counters {
+            let _ = write!(out, "# HELP actor_{name} {help}\n# TYPE actor_{name} counter\nactor_{name} {}\n", counter.get());
+        }
+        let gauges: [(&str, &str, &Gauge); 2] = [
+            ("sessions_active", "Sessions currently connected.", &self.sessions_active),
+            ("router_queue_depth", "Packets waiting for delivery in the router's last pass.", &self.router_queue_depth),
+        ];
+        for (name, help, gauge) in gauges {
+            let _ = write!(out, "# HELP actor_{name} {help}\n# TYPE actor_{name} gauge\nactor_{name} {}\n", gauge.get());
+        }
+        if self.per_session {
+            let sessions = self.sessions.lock().unwrap();
+            for (name, help, pick) in [
+                ("session_received_bytes_total", "Bytes received per session.", (|c: &SessionCounters| c.bytes_in) as fn(&SessionCounters) -> u64),
+                ("session_sent_bytes_total", "Bytes sent per session.", |c: &SessionCounters| c.bytes_out),
+            ] {
+                let _ = write!(out, "# HELP actor_{name} {help}\n# TYPE actor_{name} counter\n");
+                for ((user, ipv4), counters) in sessions.iter() {
+                    let _ = writeln!(out, "actor_{name}{{user=\"{}\",ipv4=\"{}\"}} {}", escape_label(user), ipv4, pick(counters));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Escapes a label value as the text format requires.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let metrics = Metrics::new(false);
+        metrics.handshakes_started.add(3);
+        metrics.sessions_active.inc();
+        metrics.session_traffic("alice", Ipv4Addr::new(10, 0, 8, 2), 10, 20);
+        let out = metrics.render();
+        assert!(out.contains("# TYPE actor_handshakes_started_total counter\nactor_handshakes_started_total 3\n"));
+        assert!(out.contains("# TYPE actor_sessions_active gauge\nactor_sessions_active 1\n"));
+        assert!(!out.contains("actor_session_received_bytes_total"));
+    }
+
+    #[test]
+    fn renders_per_session_series_until_closed() {
+        let metrics = Metrics::new(true);
+        let ipv4 = Ipv4Addr::new(10, 0, 8, 2);
+        metrics.session_traffic("al\"ice", ipv4, 10, 20);
+        metrics.session_traffic("al\"ice", ipv4, 5, 0);
+        let out = metrics.render();
+        assert!(out.contains("actor_session_received_bytes_total{user=\"al\\\"ice\",ipv4=\"10.0.8.2\"} 15\n"));
+        assert!(out.contains("actor_session_sent_bytes_total{user=\"al\\\"ice\",ipv4=\"10.0.8.2\"} 20\n"));
+
+        metrics.session_closed("al\"ice", ipv4);
+        assert!(!metrics.render().contains("user=\"al"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("plain"), "plain");
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
@@ -0,0 +1,446 @@
+use log::{error, info, warn};
+use crate::netconfig::netlink::Netlink;
+use crate::netconfig::{NetBackend, NetError, Route};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How to take back one change made to the host network.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Undo {
+    DeleteAddress { interface: String, address: IpNet },
+    DeleteRoute { route: Route },
+    RestoreRoute { route: Route },
+    SetSysctl { key: String, value: String },
+    Command { program: String, args: Vec<String> },
+}
+
+impl Undo {
+    fn apply(&self, backend: &mut dyn NetBackend) -> Result<(), NetError> {
+        match self {
+            Undo::DeleteAddress { interface, address } => backend.delete_address(interface, *address),
+            Undo::DeleteRoute { route } => backend.delete_route(route),
+            Undo::RestoreRoute { route } => backend.replace_route(route),
+            Undo::SetSysctl { key, value } => backend.set_sysctl(key, value),
+            Undo::Command { program, args } => {
+                // after a reboot the rules and tables are already gone and deleting them fails
+                if let Some(query) = still_in_place(program, args) {
+                    if backend.run(program, &query).is_err() {
+                        return Ok(());
+                    }
+                }
+                backend.run(program, args)
+            }
+        }
+    }
+}
+
+/// Query that only succeeds while there is something left for the undo command `args` to
+/// take back. Deleting addresses and routes that are gone already succeeds.
+fn still_in_place(program: &str, args: &[String]) -> Option<Vec<String>> {
+    let (action, rest) = args.split_first()?;
+    let query = match action.as_str() {
+        "-D" => "-C",
+        "delete" if program == "nft" => "list",
+        "revert" if program == "resolvectl" => "status",
+        _ => return None,
+    };
+    let mut query = vec![query.to_string()];
+    query.extend_from_slice(rest);
+    Some(query)
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct JournalFile {
+    #[serde(default)]
+    undo: Vec<Undo>,
+}
+
+/// Wraps a backend and keeps the undo of every change it makes in a file, written before
+/// the change is made so a crash in between leaves nothing unaccounted for. Changes that
+/// take back an earlier one drop its entry, the journal only holds what is still in place.
+pub struct Journal<B: NetBackend> {
+    backend: B,
+    path: PathBuf,
+    entries: Vec<Undo>,
+}
+
+impl<B: NetBackend> Journal<B> {
+    /// Starts a journal at `path`, first reverting whatever a previous run that did not
+    /// shut down cleanly left behind. Changes that still cannot be reverted stay in the
+    /// journal and are tried again on exit.
+    pub fn open(backend: B, path: &Path) -> Result<Journal<B>, NetError> {
+        let mut journal = Self {
+            backend,
+            path: path.to_path_buf(),
+            entries: Vec::new(),
+        };
+        let stale = read_entries(path)?;
+        if stale.is_empty() {
+            return Ok(journal);
+        }
+        match revert_entries(&mut journal.backend, &stale) {
+            Ok(()) => {
+                info!("Reverted {} network changes left behind by a previous run", stale.len());
+                fs::remove_file(path)?;
+                return Ok(journal);
+            }
+            Err((failed, err)) => {
+                warn!(
+                    "{} of {} network changes left behind by a previous run could not be reverted, retrying on exit: {}",
+                    failed.len(),
+                    stale.len(),
+                    err
+                );
+                journal.entries = failed;
+            }
+        }
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Reverts the journal at `path` if there is one, returning how many changes it held.
+    /// On failure the journal is rewritten with only the changes that are still in place.
+    pub fn cleanup(backend: &mut B, path: &Path) -> Result<usize, NetError> {
+        let entries = read_entries(path)?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        match revert_entries(backend, &entries) {
+            Ok(()) => {
+                fs::remove_file(path)?;
+                Ok(entries.len())
+            }
+            Err((failed, err)) => {
+                write_entries(path, &failed)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Takes back every change still in place, newest first, and removes the journal file.
+    /// Keeps going past failures so one stuck entry does not leave the rest behind, those
+    /// stay in the journal for the next start or `cleanup`.
+    pub fn revert(&mut self) -> Result<(), NetError> {
+        let entries = std::mem::take(&mut self.entries);
+        if let Err((failed, err)) = revert_entries(&mut self.backend, &entries) {
+            self.entries = failed;
+            self.save()?;
+            return Err(err);
+        }
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn entries(&self) -> &[Undo] {
+        &self.entries
+    }
+
+    fn save(&self) -> Result<(), NetError> {
+        write_entries(&self.path, &self.entries)
+    }
+
+    /// Records `undo` unless `change` cancels an entry already there, which is dropped instead.
+    fn record(&mut self, change: &Undo, undo: Undo) -> Result<(), NetError> {
+        match self.entries.iter().rposition(|entry| entry == change) {
+            Some(position) => {
+                self.entries.remove(position);
+            }
+            None if !self.entries.contains(&undo) => self.entries.push(undo),
+            None => return Ok(()),
+        }
+        self.save()
+    }
+}
+
+/// Handles the `cleanup` command of both binaries, putting the network back after a crash.
+pub fn run_cleanup(path: &Path) -> Result<(), NetError> {
+    let mut backend = Netlink::open()?;
+    match Journal::cleanup(&mut backend, path)? {
+        0 => info!("Nothing to clean up, {} does not exist", path.display()),
+        count => info!("Reverted {} network changes recorded in {}", count, path.display()),
+    }
+    Ok(())
+}
+
+fn read_entries(path: &Path) -> Result<Vec<Undo>, NetError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    let file: JournalFile = toml::from_str(&content).map_err(|_| NetError::Malformed("journal file"))?;
+    Ok(file.undo)
+}
+
+fn write_entries(path: &Path, entries: &[Undo]) -> Result<(), NetError> {
+    let file = JournalFile { undo: entries.to_vec() };
+    let content = toml::to_string(&file).map_err(|_| NetError::Malformed("journal entry"))?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Takes back `entries` newest first, going on past failures. On failure returns the
+/// entries that are still in place, in journal order, along with the first error.
+fn revert_entries(backend: &mut dyn NetBackend, entries: &[Undo]) -> Result<(), (Vec<Undo>, NetError)> {
+    let mut failed = Vec::new();
+    let mut first_error = None;
+    for entry in entries.iter().rev() {
+        if let Err(err) = entry.apply(backend) {
+            error!("Failed to revert {:?}: {}", entry, err);
+            failed.insert(0, entry.clone());
+            first_error.get_or_insert(err);
+        }
+    }
+    match first_error {
+        Some(err) => Err((failed, err)),
+        None => Ok(()),
+    }
+}
+
+/// What takes back `program args`, for the commands that change state: appended firewall
+/// rules and per-link resolver settings.
+fn undo_command(program: &str, args: &[String]) -> Option<Undo> {
+    match args.split_first() {
+        Some((action, rule)) if action == "-A" => {
+            let mut args = vec!["-D".to_string()];
+            args.extend_from_slice(rule);
+            Some(Undo::Command { program: program.to_string(), args })
+        }
+        Some((action, rest)) if program == "resolvectl" && (action == "dns" || action == "domain") => {
+            let interface = rest.first()?.clone();
+            Some(Undo::Command {
+                program: program.to_string(),
+                args: vec!["revert".to_string(), interface],
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Tables an nft script creates are deleted as a whole, whatever it put into them.
+fn undo_script(program: &str, input: &str) -> Vec<Undo> {
+    if program != "nft" {
+        return Vec::new();
+    }
+    let mut undo = Vec::new();
+    for line in input.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let table = match words.as_slice() {
+            ["table", family, name] | ["table", family, name, "{"] | ["add", "table", family, name] => (family, name),
+            _ => continue,
+        };
+        let entry = Undo::Command {
+            program: program.to_string(),
+            args: vec!["delete".to_string(), "table".to_string(), table.0.to_string(), table.1.to_string()],
+        };
+        if !undo.contains(&entry) {
+            undo.push(entry);
+        }
+    }
+    undo
+}
+
+impl<B: NetBackend> NetBackend for Journal<B> {
+    fn set_link(&mut self, interface: &str, up: bool, mtu: Option<u32>) -> Result<(), NetError> {
+        // the links configured here are the TUN devices the binaries own, gone when they exit
+        self.backend.set_link(interface, up, mtu)
+    }
+
+    fn add_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError> {
+        let undo = Undo::DeleteAddress { interface: interface.to_string(), address };
+        if !self.entries.contains(&undo) {
+            self.entries.push(undo);
+            self.save()?;
+        }
+        self.backend.add_address(interface, address)
+    }
+
+    fn delete_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError> {
+        let change = Undo::DeleteAddress { interface: interface.to_string(), address };
+        self.backend.delete_address(interface, address)?;
+        if let Some(position) = self.entries.iter().rposition(|entry| *entry == change) {
+            self.entries.remove(position);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn replace_route(&mut self, route: &Route) -> Result<(), NetError> {
+        let change = Undo::RestoreRoute { route: route.clone() };
+        self.record(&change, Undo::DeleteRoute { route: route.clone() })?;
+        self.backend.replace_route(route)
+    }
+
+    fn delete_route(&mut self, route: &Route) -> Result<(), NetError> {
+        let change = Undo::DeleteRoute { route: route.clone() };
+        self.record(&change, Undo::RestoreRoute { route: route.clone() })?;
+        self.backend.delete_route(route)
+    }
+
+    fn default_routes(&mut self, ipv6: bool) -> Result<Vec<Route>, NetError> {
+        self.backend.default_routes(ipv6)
+    }
+
+    fn sysctl(&mut self, key: &str) -> Result<String, NetError> {
+        self.backend.sysctl(key)
+    }
+
+    fn set_sysctl(&mut self, key: &str, value: &str) -> Result<(), NetError> {
+        let previous = self.backend.sysctl(key)?;
+        if previous == value {
+            return Ok(());
+        }
+        let change = Undo::SetSysctl { key: key.to_string(), value: value.to_string() };
+        self.record(&change, Undo::SetSysctl { key: key.to_string(), value: previous })?;
+        self.backend.set_sysctl(key, value)
+    }
+
+    fn run(&mut self, program: &str, args: &[String]) -> Result<(), NetError> {
+        let change = Undo::Command { program: program.to_string(), args: args.to_vec() };
+        if let Some(position) = self.entries.iter().rposition(|entry| *entry == change) {
+            self.backend.run(program, args)?;
+            self.entries.remove(position);
+            return self.save();
+        }
+        if let Some(undo) = undo_command(program, args) {
+            if !self.entries.contains(&undo) {
+                self.entries.push(undo);
+                self.save()?;
+            }
+        }
+        self.backend.run(program, args)
+    }
+
+    fn run_with_input(&mut self, program: &str, args: &[String], input: &str) -> Result<(), NetError> {
+        let undo: Vec<Undo> = undo_script(program, input)
+            .into_iter()
+            .filter(|undo| !self.entries.contains(undo))
+            .collect();
+        if !undo.is_empty() {
+            self.entries.extend(undo);
+            self.save()?;
+        }
+        self.backend.run_with_input(program, args, input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netconfig::mock::MockBackend;
+    use crate::netconfig::NetOp;
+    use crate::router_setup;
+    use crate::util::testing::TempPath;
+    use crate::vpn_config::{FirewallBackend, TunnelMode};
+
+    fn journal_path(name: &str) -> TempPath {
+        TempPath::file(&format!("journal-{}.toml", name))
+    }
+
+    fn backend() -> MockBackend {
+        let mut net = MockBackend::with_links(&["eth0", "tun0"]);
+        net.routes.push(Route {
+            destination: "0.0.0.0/0".parse().unwrap(),
+            gateway: Some("192.168.1.1".parse().unwrap()),
+            interface: Some("eth0".to_string()),
+            metric: None,
+        });
+        net
+    }
+
+    #[test]
+    fn revert_takes_back_server_setup() {
+        let path = journal_path("server");
+        let mut journal = Journal::open(backend(), &path).unwrap();
+        router_setup::setup_tun_interface(&mut journal, "tun0", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Iptables).unwrap();
+        assert!(path.exists());
+        assert_eq!(journal.backend.rules.len(), 5);
+
+        journal.revert().unwrap();
+        assert!(journal.backend.rules.is_empty());
+        assert!(journal.backend.addresses.is_empty());
+        assert_eq!(journal.backend.sysctls.get("net.ipv4.ip_forward").map(String::as_str), Some("0"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn revert_deletes_nftables_table() {
+        let path = journal_path("nft");
+        let mut journal = Journal::open(backend(), &path).unwrap();
+        router_setup::setup_tun_interface(&mut journal, "tun0", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Nftables).unwrap();
+        router_setup::setup_tun_interface(&mut journal, "tun0", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Nftables).unwrap();
+        let delete_table = Undo::Command {
+            program: "nft".to_string(),
+            args: vec!["delete".to_string(), "table".to_string(), "inet".to_string(), "actor".to_string()],
+        };
+        assert_eq!(journal.entries().iter().filter(|entry| **entry == delete_table).count(), 1);
+
+        journal.revert().unwrap();
+        if let Undo::Command { program, args } = delete_table {
+            assert!(journal.backend.ops.contains(&NetOp::Command { program, args }));
+        }
+    }
+
+    #[test]
+    fn restored_routes_leave_nothing_to_revert() {
+        let path = journal_path("routes");
+        let mut journal = Journal::open(backend(), &path).unwrap();
+        let state = router_setup::route_traffic_through_tun(&mut journal, "tun0", TunnelMode::Full, &[], &[], &[]).unwrap();
+        // the IPv4 default it displaced, plus a tunnel default for each family
+        assert_eq!(journal.entries().len(), 3);
+
+        router_setup::restore_routes(&mut journal, state).unwrap();
+        assert!(journal.entries().is_empty());
+        journal.revert().unwrap();
+    }
+
+    #[test]
+    fn cleanup_reverts_journal_of_crashed_run() {
+        let path = journal_path("crash");
+        let mut journal = Journal::open(backend(), &path).unwrap();
+        router_setup::route_traffic_through_tun(&mut journal, "tun0", TunnelMode::Full, &[], &[], &[]).unwrap();
+        let mut crashed = journal.backend;
+
+        assert_eq!(Journal::cleanup(&mut crashed, &path).unwrap(), 3);
+        assert_eq!(crashed.routes, backend().routes);
+        assert!(!path.exists());
+        assert_eq!(Journal::cleanup(&mut crashed, &path).unwrap(), 0);
+    }
+
+    #[test]
+    fn cleanup_after_reboot_keeps_only_what_failed() {
+        let path = journal_path("reboot");
+        let command = |program: &str, args: &[&str]| Undo::Command {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        // a route through a link that no longer exists is the one change that cannot be undone
+        let stuck = Undo::RestoreRoute {
+            route: Route::via_interface("10.1.0.0/16".parse().unwrap(), "gone0"),
+        };
+        let entries = vec![
+            command("iptables", &["-D", "FORWARD", "-i", "tun0", "-j", "ACCEPT"]),
+            command("nft", &["delete", "table", "inet", "actor"]),
+            stuck.clone(),
+            Undo::DeleteAddress { interface: "tun0".to_string(), address: "10.0.8.1/24".parse().unwrap() },
+        ];
+        write_entries(&path, &entries).unwrap();
+
+        // the rules and tables went away with the reboot
+        let mut rebooted = backend();
+        assert!(Journal::cleanup(&mut rebooted, &path).is_err());
+        assert!(rebooted.changes().iter().all(|op| !matches!(op, NetOp::Command { args, .. } if args[0] == "-D" || args[0] == "delete")));
+        assert_eq!(read_entries(&path).unwrap(), std::slice::from_ref(&stuck));
+
+        // the binaries still start and retry the rest on exit
+        let journal = Journal::open(rebooted, &path).unwrap();
+        assert_eq!(journal.entries(), [stuck]);
+        let _ = fs::remove_file(&path);
+    }
+}
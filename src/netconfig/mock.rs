@@ -0,0 +1,157 @@
+use crate::netconfig::{NetBackend, NetError, NetOp, Route};
+use ipnet::IpNet;
+use std::collections::{HashMap, HashSet};
+
+/// Records every operation and keeps just enough state to answer like the kernel would:
+/// known links, installed routes and addresses, sysctls and iptables-style rules.
+#[derive(Default)]
+pub struct MockBackend {
+    pub ops: Vec<NetOp>,
+    pub links: HashSet<String>,
+    pub addresses: Vec<(String, IpNet)>,
+    pub routes: Vec<Route>,
+    pub sysctls: HashMap<String, String>,
+    /// Program followed by the rule, without the `-A`/`-C`/`-D` action.
+    pub rules: HashSet<Vec<String>>,
+    /// Input given to programs through stdin, in order.
+    pub scripts: Vec<String>,
+    /// Family and name of the nft tables scripts created.
+    pub tables: HashSet<(String, String)>,
+    /// Programs whose every invocation fails.
+    pub failing: HashSet<String>,
+}
+
+impl MockBackend {
+    pub fn with_links(links: &[&str]) -> Self {
+        Self {
+            links: links.iter().map(|link| link.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn check_link(&self, interface: &str) -> Result<(), NetError> {
+        if self.links.contains(interface) {
+            Ok(())
+        } else {
+            Err(NetError::NoSuchInterface(interface.to_string()))
+        }
+    }
+
+    /// Operations other than `-C` rule checks, which are queries rather than changes.
+    pub fn changes(&self) -> Vec<&NetOp> {
+        self.ops
+            .iter()
+            .filter(|op| !matches!(op, NetOp::Command { args, .. } if args.first().map(String::as_str) == Some("-C")))
+            .collect()
+    }
+}
+
+impl NetBackend for MockBackend {
+    fn set_link(&mut self, interface: &str, up: bool, mtu: Option<u32>) -> Result<(), NetError> {
+        self.check_link(interface)?;
+        self.ops.push(NetOp::SetLink { interface: interface.to_string(), up, mtu });
+        Ok(())
+    }
+
+    fn add_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError> {
+        self.check_link(interface)?;
+        self.ops.push(NetOp::AddAddress { interface: interface.to_string(), address });
+        let entry = (interface.to_string(), address);
+        if !self.addresses.contains(&entry) {
+            self.addresses.push(entry);
+        }
+        Ok(())
+    }
+
+    fn delete_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError> {
+        self.ops.push(NetOp::DeleteAddress { interface: interface.to_string(), address });
+        self.addresses.retain(|(name, existing)| name != interface || *existing != address);
+        Ok(())
+    }
+
+    fn replace_route(&mut self, route: &Route) -> Result<(), NetError> {
+        if let Some(interface) = &route.interface {
+            self.check_link(interface)?;
+        }
+        self.ops.push(NetOp::ReplaceRoute(route.clone()));
+        self.routes.retain(|existing| existing.destination != route.destination);
+        self.routes.push(route.clone());
+        Ok(())
+    }
+
+    fn delete_route(&mut self, route: &Route) -> Result<(), NetError> {
+        self.ops.push(NetOp::DeleteRoute(route.clone()));
+        self.routes.retain(|existing| existing != route);
+        Ok(())
+    }
+
+    fn default_routes(&mut self, ipv6: bool) -> Result<Vec<Route>, NetError> {
+        Ok(self
+            .routes
+            .iter()
+            .filter(|route| route.is_ipv6() == ipv6 && route.destination.prefix_len() == 0)
+            .cloned()
+            .collect())
+    }
+
+    fn sysctl(&mut self, key: &str) -> Result<String, NetError> {
+        Ok(self.sysctls.get(key).cloned().unwrap_or_else(|| "0".to_string()))
+    }
+
+    fn set_sysctl(&mut self, key: &str, value: &str) -> Result<(), NetError> {
+        self.ops.push(NetOp::SetSysctl { key: key.to_string(), value: value.to_string() });
+        self.sysctls.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn run(&mut self, program: &str, args: &[String]) -> Result<(), NetError> {
+        self.ops.push(NetOp::Command { program: program.to_string(), args: args.to_vec() });
+        let failed = NetError::CommandFailed {
+            command: format!("{} {}", program, args.join(" ")),
+            status: Some(1),
+        };
+        if self.failing.contains(program) {
+            return Err(failed);
+        }
+        let (action, rule) = match args.split_first() {
+            Some((action, rule)) => (action.as_str(), rule),
+            None => return Ok(()),
+        };
+        if program == "nft" {
+            let table = match rule {
+                [kind, family, name] if kind == "table" => (family.clone(), name.clone()),
+                _ => return Ok(()),
+            };
+            return match action {
+                "list" if !self.tables.contains(&table) => Err(failed),
+                "delete" if !self.tables.remove(&table) => Err(failed),
+                _ => Ok(()),
+            };
+        }
+        let mut key = vec![program.to_string()];
+        key.extend_from_slice(rule);
+        match action {
+            "-C" if !self.rules.contains(&key) => Err(failed),
+            "-A" => {
+                self.rules.insert(key);
+                Ok(())
+            }
+            "-D" if !self.rules.remove(&key) => Err(failed),
+            _ => Ok(()),
+        }
+    }
+
+    fn run_with_input(&mut self, program: &str, args: &[String], input: &str) -> Result<(), NetError> {
+        self.ops.push(NetOp::Script { program: program.to_string(), args: args.to_vec(), input: input.to_string() });
+        if self.failing.contains(program) {
+            return Err(NetError::CommandFailed { command: program.to_string(), status: Some(1) });
+        }
+        for line in input.lines() {
+            if let ["table", family, name, ..] | ["add", "table", family, name, ..] = line.split_whitespace().collect::<Vec<_>>()[..] {
+                self.tables.insert((family.to_string(), name.to_string()));
+            }
+        }
+        self.scripts.push(input.to_string());
+        Ok(())
+    }
+}
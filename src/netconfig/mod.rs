@@ -0,0 +1,128 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+pub mod journal;
+#[cfg(test)]
+pub mod mock;
+pub mod netlink;
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    /// The kernel rejected a netlink request with this errno.
+    Netlink { operation: &'static str, errno: i32 },
+    NoSuchInterface(String),
+    NoDefaultRoute,
+    /// A name that cannot be put into a firewall rule as it is.
+    InvalidName(String),
+    CommandFailed { command: String, status: Option<i32> },
+    Malformed(&'static str),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Io(err) => write!(f, "{}", err),
+            NetError::Netlink { operation, errno } => {
+                write!(f, "{} failed: {}", operation, io::Error::from_raw_os_error(*errno))
+            }
+            NetError::NoSuchInterface(name) => write!(f, "no such interface: {}", name),
+            NetError::InvalidName(name) => write!(f, "{:?} cannot be used in a firewall rule", name),
+            NetError::NoDefaultRoute => write!(f, "no default route to find the uplink interface by"),
+            NetError::CommandFailed { command, status } => match status {
+                Some(status) => write!(f, "{} exited with status {}", command, status),
+                None => write!(f, "{} was killed by a signal", command),
+            },
+            NetError::Malformed(what) => write!(f, "malformed netlink reply: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+impl From<io::Error> for NetError {
+    fn from(err: io::Error) -> Self {
+        NetError::Io(err)
+    }
+}
+
+/// A route in the main table. `interface` and `gateway` are both optional, as in `ip route`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub destination: IpNet,
+    pub gateway: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub metric: Option<u32>,
+}
+
+impl Route {
+    pub fn via_interface(destination: IpNet, interface: &str) -> Route {
+        Self {
+            destination,
+            gateway: None,
+            interface: Some(interface.to_string()),
+            metric: None,
+        }
+    }
+
+    /// Same next hop as `uplink`, for `destination` instead.
+    pub fn via_uplink(destination: IpNet, uplink: &Route) -> Route {
+        Self {
+            destination,
+            gateway: uplink.gateway,
+            interface: uplink.interface.clone(),
+            metric: None,
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self.destination, IpNet::V6(_))
+    }
+}
+
+/// Operations a backend carried out, as recorded by the mock backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetOp {
+    SetLink { interface: String, up: bool, mtu: Option<u32> },
+    AddAddress { interface: String, address: IpNet },
+    DeleteAddress { interface: String, address: IpNet },
+    ReplaceRoute(Route),
+    DeleteRoute(Route),
+    SetSysctl { key: String, value: String },
+    Command { program: String, args: Vec<String> },
+    Script { program: String, args: Vec<String>, input: String },
+}
+
+/// Everything the binaries change on the host network. All operations are idempotent:
+/// adding what exists or deleting what is gone succeeds.
+pub trait NetBackend: Send {
+    fn set_link(&mut self, interface: &str, up: bool, mtu: Option<u32>) -> Result<(), NetError>;
+    fn add_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError>;
+    fn delete_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError>;
+    fn replace_route(&mut self, route: &Route) -> Result<(), NetError>;
+    fn delete_route(&mut self, route: &Route) -> Result<(), NetError>;
+    /// Default routes of the main table, lowest metric first.
+    fn default_routes(&mut self, ipv6: bool) -> Result<Vec<Route>, NetError>;
+    fn sysctl(&mut self, key: &str) -> Result<String, NetError>;
+    fn set_sysctl(&mut self, key: &str, value: &str) -> Result<(), NetError>;
+    /// Runs `program` directly, never through a shell, failing on a non-zero exit status.
+    fn run(&mut self, program: &str, args: &[String]) -> Result<(), NetError>;
+    /// Like [`NetBackend::run`], feeding `input` to the program's stdin.
+    fn run_with_input(&mut self, program: &str, args: &[String], input: &str) -> Result<(), NetError>;
+}
+
+/// Appends an iptables-style `rule` unless `-C` finds it already present, returns whether it was added.
+pub fn ensure_rule(backend: &mut dyn NetBackend, program: &str, rule: &[String]) -> Result<bool, NetError> {
+    let mut check = vec!["-C".to_string()];
+    check.extend_from_slice(rule);
+    if backend.run(program, &check).is_ok() {
+        return Ok(false);
+    }
+    let mut append = vec!["-A".to_string()];
+    append.extend_from_slice(rule);
+    backend.run(program, &append)?;
+    Ok(true)
+}
@@ -0,0 +1,606 @@
+use log::warn;
+use crate::netconfig::{NetBackend, NetError, Route};
+use ipnet::IpNet;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const HEADER_LEN: usize = 16;
+const RECV_BUFFER: usize = 32 * 1024;
+/// `struct rtnexthop`: length, flags, hops and interface index.
+const NEXT_HOP_LEN: usize = 8;
+/// How long to wait for the kernel to answer a request.
+const RECV_TIMEOUT_SECS: libc::time_t = 5;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(ipv6: bool) -> u8 {
+    if ipv6 { libc::AF_INET6 as u8 } else { libc::AF_INET as u8 }
+}
+
+fn address_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// A netlink request under construction: header, fixed family-specific body, then attributes.
+struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    fn new(body: &[u8]) -> Self {
+        let mut buf = vec![0u8; HEADER_LEN];
+        buf.extend_from_slice(body);
+        buf.resize(align(buf.len()), 0);
+        Self { buf }
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) -> &mut Self {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    fn finish(mut self, msg_type: u16, flags: u16, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        self.buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Attributes following the fixed body of a message, or inside a nested attribute.
+fn parse_attrs(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]);
+        if len < 4 || len > data.len() {
+            break;
+        }
+        attrs.push((kind, &data[4..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    attrs
+}
+
+fn parse_ip(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+        16 => {
+            let octets: [u8; 16] = data.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+fn parse_u32(data: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(0..4)?.try_into().ok()?))
+}
+
+/// Gateway and interface index of every next hop in an `RTA_MULTIPATH` attribute.
+fn parse_next_hops(mut data: &[u8]) -> Result<Vec<(Option<IpAddr>, u32)>, NetError> {
+    let mut hops = Vec::new();
+    while data.len() >= NEXT_HOP_LEN {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        if len < NEXT_HOP_LEN || len > data.len() {
+            return Err(NetError::Malformed("multipath next hop"));
+        }
+        let index = u32::from_ne_bytes(data[4..8].try_into().unwrap());
+        let gateway = parse_attrs(&data[NEXT_HOP_LEN..len])
+            .into_iter()
+            .find(|(attr, _)| *attr == libc::RTA_GATEWAY)
+            .and_then(|(_, data)| parse_ip(data));
+        hops.push((gateway, index));
+        data = &data[align(len).min(data.len())..];
+    }
+    Ok(hops)
+}
+
+/// The default routes of the main table in one `RTM_NEWROUTE` dump entry: none, the route
+/// itself, or one per next hop of a multipath route. `link_name` resolves interface indexes.
+fn default_routes_in(
+    reply: &[u8],
+    ipv6: bool,
+    link_name: impl Fn(u32) -> Option<String>,
+) -> Result<Vec<Route>, NetError> {
+    if reply.len() < 12 {
+        return Err(NetError::Malformed("route message"));
+    }
+    let (dst_len, mut table, kind) = (reply[1], reply[4] as u32, reply[7]);
+    let mut route = Route {
+        destination: if ipv6 { "::/0".parse().unwrap() } else { "0.0.0.0/0".parse().unwrap() },
+        gateway: None,
+        interface: None,
+        metric: None,
+    };
+    let mut next_hops = Vec::new();
+    for (attr, data) in parse_attrs(&reply[12..]) {
+        match attr {
+            libc::RTA_TABLE => table = parse_u32(data).unwrap_or(table),
+            libc::RTA_GATEWAY => route.gateway = parse_ip(data),
+            libc::RTA_OIF => route.interface = parse_u32(data).and_then(&link_name),
+            libc::RTA_PRIORITY => route.metric = parse_u32(data),
+            libc::RTA_MULTIPATH => next_hops = parse_next_hops(data)?,
+            _ => {}
+        }
+    }
+    if dst_len != 0 || table != libc::RT_TABLE_MAIN as u32 || kind != libc::RTN_UNICAST {
+        return Ok(Vec::new());
+    }
+    if next_hops.is_empty() {
+        return Ok(vec![route]);
+    }
+    Ok(next_hops
+        .into_iter()
+        .map(|(gateway, index)| Route {
+            gateway,
+            interface: link_name(index),
+            ..route.clone()
+        })
+        .collect())
+}
+
+fn address_request(index: u32, address: IpNet) -> Request {
+    let ipv6 = matches!(address, IpNet::V6(_));
+    let mut body = vec![family(ipv6), address.prefix_len(), 0, libc::RT_SCOPE_UNIVERSE];
+    body.extend_from_slice(&index.to_ne_bytes());
+    let mut request = Request::new(&body);
+    let bytes = address_bytes(address.addr());
+    request.attr(libc::IFA_LOCAL, &bytes).attr(libc::IFA_ADDRESS, &bytes);
+    request
+}
+
+/// `oif` is the index of `route.interface`, resolved by the caller.
+fn route_request(route: &Route, oif: Option<u32>) -> Request {
+    let ipv6 = route.is_ipv6();
+    let scope = if route.gateway.is_none() && route.interface.is_some() {
+        libc::RT_SCOPE_LINK
+    } else {
+        libc::RT_SCOPE_UNIVERSE
+    };
+    let body = [
+        family(ipv6),
+        route.destination.prefix_len(),
+        0,
+        0,
+        libc::RT_TABLE_MAIN,
+        libc::RTPROT_BOOT,
+        scope,
+        libc::RTN_UNICAST,
+        0,
+        0,
+        0,
+        0,
+    ];
+    let mut request = Request::new(&body);
+    if route.destination.prefix_len() > 0 {
+        request.attr(libc::RTA_DST, &address_bytes(route.destination.network()));
+    }
+    if let Some(gateway) = route.gateway {
+        request.attr(libc::RTA_GATEWAY, &address_bytes(gateway));
+    }
+    if let Some(oif) = oif {
+        request.attr(libc::RTA_OIF, &oif.to_ne_bytes());
+    }
+    if let Some(metric) = route.metric {
+        request.attr(libc::RTA_PRIORITY, &metric.to_ne_bytes());
+    }
+    request
+}
+
+/// Talks rtnetlink over a raw `NETLINK_ROUTE` socket for links, addresses and routes.
+/// Sysctls are written to `/proc/sys`, firewall and resolver tools are executed directly.
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> Result<Netlink, NetError> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let res = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // a kernel that never answers must not hang route setup or shutdown
+        let timeout = libc::timeval { tv_sec: RECV_TIMEOUT_SECS, tv_usec: 0 };
+        let res = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self { fd, seq: 0 })
+    }
+
+    pub fn link_index(&self, interface: &str) -> Result<u32, NetError> {
+        let name = CString::new(interface).map_err(|_| NetError::NoSuchInterface(interface.to_string()))?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(NetError::NoSuchInterface(interface.to_string())),
+            index => Ok(index),
+        }
+    }
+
+    fn link_name(&self, index: u32) -> Option<String> {
+        let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+        let res = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+        if res.is_null() {
+            return None;
+        }
+        Some(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned())
+    }
+
+    /// Sends one request and collects the bodies of the replies until the kernel acks it
+    /// or ends the dump. A negative ack becomes [`NetError::Netlink`].
+    fn request(&mut self, request: Request, msg_type: u16, flags: u16, operation: &'static str) -> Result<Vec<Vec<u8>>, NetError> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let msg = request.finish(msg_type, flags | libc::NLM_F_REQUEST as u16, seq);
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; RECV_BUFFER];
+        loop {
+            let received = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if received < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    let message = format!("no answer to {} within {}s", operation, RECV_TIMEOUT_SECS);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, message).into());
+                }
+                return Err(err.into());
+            }
+            let mut data = &buf[..received as usize];
+            while data.len() >= HEADER_LEN {
+                let len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes([data[4], data[5]]);
+                let reply_seq = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+                if len < HEADER_LEN || len > data.len() {
+                    return Err(NetError::Malformed("message length"));
+                }
+                let body = &data[HEADER_LEN..len];
+                data = &data[align(len).min(data.len())..];
+                if reply_seq != seq {
+                    continue;
+                }
+                match kind as libc::c_int {
+                    libc::NLMSG_ERROR => {
+                        let errno = body
+                            .get(0..4)
+                            .map(|code| -i32::from_ne_bytes(code.try_into().unwrap()))
+                            .ok_or(NetError::Malformed("error message"))?;
+                        if errno == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(NetError::Netlink { operation, errno });
+                    }
+                    libc::NLMSG_DONE => return Ok(replies),
+                    _ => replies.push(body.to_vec()),
+                }
+            }
+        }
+    }
+
+    fn route_oif(&self, route: &Route) -> Result<Option<u32>, NetError> {
+        route.interface.as_deref().map(|interface| self.link_index(interface)).transpose()
+    }
+}
+
+fn check_output(program: &str, args: &[String], output: Output) -> Result<(), NetError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        warn!("{}: {}", program, stderr.trim());
+    }
+    Err(NetError::CommandFailed {
+        command: format!("{} {}", program, args.join(" ")),
+        status: output.status.code(),
+    })
+}
+
+fn sysctl_path(key: &str) -> String {
+    format!("/proc/sys/{}", key.replace('.', "/"))
+}
+
+fn tolerate(result: Result<Vec<Vec<u8>>, NetError>, errnos: &[i32]) -> Result<(), NetError> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(NetError::Netlink { errno, .. }) if errnos.contains(&errno) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+impl NetBackend for Netlink {
+    fn set_link(&mut self, interface: &str, up: bool, mtu: Option<u32>) -> Result<(), NetError> {
+        let index = self.link_index(interface)?;
+        let flags = if up { libc::IFF_UP as u32 } else { 0 };
+        let mut body = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+        body.extend_from_slice(&(index as i32).to_ne_bytes());
+        body.extend_from_slice(&flags.to_ne_bytes());
+        body.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+        let mut request = Request::new(&body);
+        if let Some(mtu) = mtu {
+            request.attr(libc::IFLA_MTU, &mtu.to_ne_bytes());
+        }
+        self.request(request, libc::RTM_NEWLINK, libc::NLM_F_ACK as u16, "set link")?;
+        Ok(())
+    }
+
+    fn add_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError> {
+        let request = address_request(self.link_index(interface)?, address);
+        let flags = libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_REPLACE;
+        let result = self.request(request, libc::RTM_NEWADDR, flags as u16, "add address");
+        tolerate(result, &[libc::EEXIST])
+    }
+
+    fn delete_address(&mut self, interface: &str, address: IpNet) -> Result<(), NetError> {
+        let request = match self.link_index(interface) {
+            Ok(index) => address_request(index, address),
+            Err(NetError::NoSuchInterface(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let result = self.request(request, libc::RTM_DELADDR, libc::NLM_F_ACK as u16, "delete address");
+        tolerate(result, &[libc::EADDRNOTAVAIL, libc::ENODEV])
+    }
+
+    fn replace_route(&mut self, route: &Route) -> Result<(), NetError> {
+        let request = route_request(route, self.route_oif(route)?);
+        let flags = libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_REPLACE;
+        self.request(request, libc::RTM_NEWROUTE, flags as u16, "replace route")?;
+        Ok(())
+    }
+
+    fn delete_route(&mut self, route: &Route) -> Result<(), NetError> {
+        let request = match self.route_oif(route) {
+            Ok(oif) => route_request(route, oif),
+            // the interface is gone and its routes with it
+            Err(NetError::NoSuchInterface(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let result = self.request(request, libc::RTM_DELROUTE, libc::NLM_F_ACK as u16, "delete route");
+        tolerate(result, &[libc::ESRCH, libc::ENOENT])
+    }
+
+    fn default_routes(&mut self, ipv6: bool) -> Result<Vec<Route>, NetError> {
+        let body = [family(ipv6), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let replies = self.request(Request::new(&body), libc::RTM_GETROUTE, libc::NLM_F_DUMP as u16, "dump routes")?;
+        let mut routes = Vec::new();
+        for reply in replies {
+            routes.extend(default_routes_in(&reply, ipv6, |index| self.link_name(index))?);
+        }
+        routes.sort_by_key(|route| route.metric.unwrap_or(0));
+        Ok(routes)
+    }
+
+    fn sysctl(&mut self, key: &str) -> Result<String, NetError> {
+        Ok(std::fs::read_to_string(sysctl_path(key))?.trim().to_string())
+    }
+
+    fn set_sysctl(&mut self, key: &str, value: &str) -> Result<(), NetError> {
+        std::fs::write(sysctl_path(key), value)?;
+        Ok(())
+    }
+
+    fn run(&mut self, program: &str, args: &[String]) -> Result<(), NetError> {
+        let output = Command::new(program).args(args).output()?;
+        check_output(program, args, output)
+    }
+
+    fn run_with_input(&mut self, program: &str, args: &[String], input: &str) -> Result<(), NetError> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // dropping stdin closes it, which ends the input
+        child.stdin.take().expect("stdin is piped").write_all(input.as_bytes())?;
+        check_output(program, args, child.wait_with_output()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut request = Request::new(&[]);
+        request.attr(kind, data);
+        request.buf[HEADER_LEN..].to_vec()
+    }
+
+    fn next_hop(index: u32, gateway: Ipv4Addr) -> Vec<u8> {
+        let gateway = attr(libc::RTA_GATEWAY, &gateway.octets());
+        let mut hop = ((NEXT_HOP_LEN + gateway.len()) as u16).to_ne_bytes().to_vec();
+        hop.extend_from_slice(&[0, 0]);
+        hop.extend_from_slice(&index.to_ne_bytes());
+        hop.extend_from_slice(&gateway);
+        hop
+    }
+
+    fn route_message(dst_len: u8, table: u8, attrs: &[Vec<u8>]) -> Vec<u8> {
+        let mut reply = vec![libc::AF_INET as u8, dst_len, 0, 0, table, 0, 0, libc::RTN_UNICAST, 0, 0, 0, 0];
+        for attr in attrs {
+            reply.extend_from_slice(attr);
+        }
+        reply
+    }
+
+    fn link_name(index: u32) -> Option<String> {
+        Some(format!("eth{}", index))
+    }
+
+    #[test]
+    fn requests_are_aligned_and_carry_their_header() {
+        let mut request = Request::new(&[1, 2, 3]);
+        assert_eq!(request.buf.len(), HEADER_LEN + 4);
+        request.attr(7, &[9; 5]);
+        let buf = request.finish(libc::RTM_NEWROUTE, 0x0305, 42);
+        assert_eq!(buf.len(), HEADER_LEN + 4 + 12);
+        assert_eq!(u32::from_ne_bytes(buf[0..4].try_into().unwrap()), buf.len() as u32);
+        assert_eq!(u16::from_ne_bytes([buf[4], buf[5]]), libc::RTM_NEWROUTE);
+        assert_eq!(u16::from_ne_bytes([buf[6], buf[7]]), 0x0305);
+        assert_eq!(u32::from_ne_bytes(buf[8..12].try_into().unwrap()), 42);
+        assert_eq!(&buf[HEADER_LEN..HEADER_LEN + 3], &[1, 2, 3]);
+        assert_eq!(parse_attrs(&buf[HEADER_LEN + 4..]), vec![(7, &[9u8; 5][..])]);
+    }
+
+    #[test]
+    fn attribute_parsing_stops_at_bad_lengths() {
+        let mut data = attr(1, &[1, 2]);
+        data.extend(attr(2, &[3, 4, 5, 6]));
+        assert_eq!(parse_attrs(&data), vec![(1, &[1u8, 2][..]), (2, &[3u8, 4, 5, 6][..])]);
+
+        // the last attribute claims more than is left
+        let truncated = &data[..data.len() - 2];
+        assert_eq!(parse_attrs(truncated), vec![(1, &[1u8, 2][..])]);
+
+        let mut too_short = attr(1, &[]);
+        too_short[0] = 2;
+        assert!(parse_attrs(&too_short).is_empty());
+        assert!(parse_attrs(&[4, 0]).is_empty());
+    }
+
+    #[test]
+    fn address_requests_name_the_interface_and_prefix() {
+        let request = address_request(3, "10.8.0.1/24".parse().unwrap());
+        let body = &request.buf[HEADER_LEN..HEADER_LEN + 8];
+        assert_eq!(&body[..4], &[libc::AF_INET as u8, 24, 0, libc::RT_SCOPE_UNIVERSE]);
+        assert_eq!(u32::from_ne_bytes(body[4..8].try_into().unwrap()), 3);
+        let attrs = parse_attrs(&request.buf[HEADER_LEN + 8..]);
+        assert_eq!(attrs, vec![(libc::IFA_LOCAL, &[10u8, 8, 0, 1][..]), (libc::IFA_ADDRESS, &[10u8, 8, 0, 1][..])]);
+
+        let request = address_request(3, "fd00::1/64".parse().unwrap());
+        assert_eq!(request.buf[HEADER_LEN], libc::AF_INET6 as u8);
+        assert_eq!(request.buf[HEADER_LEN + 1], 64);
+        let attrs = parse_attrs(&request.buf[HEADER_LEN + 8..]);
+        assert_eq!(attrs[0].1, &"fd00::1".parse::<Ipv6Addr>().unwrap().octets()[..]);
+    }
+
+    #[test]
+    fn route_requests_carry_only_what_the_route_sets() {
+        let route = Route {
+            destination: "0.0.0.0/0".parse().unwrap(),
+            gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+            interface: Some(String::from("eth0")),
+            metric: Some(100),
+        };
+        let request = route_request(&route, Some(2));
+        let body = &request.buf[HEADER_LEN..HEADER_LEN + 12];
+        assert_eq!(body[1], 0);
+        assert_eq!(body[4], libc::RT_TABLE_MAIN);
+        assert_eq!(body[6], libc::RT_SCOPE_UNIVERSE);
+        let attrs = parse_attrs(&request.buf[HEADER_LEN + 12..]);
+        assert_eq!(
+            attrs,
+            vec![
+                (libc::RTA_GATEWAY, &[192u8, 168, 1, 1][..]),
+                (libc::RTA_OIF, &2u32.to_ne_bytes()[..]),
+                (libc::RTA_PRIORITY, &100u32.to_ne_bytes()[..]),
+            ]
+        );
+
+        let route = Route {
+            destination: "10.8.0.0/24".parse().unwrap(),
+            gateway: None,
+            interface: Some(String::from("tun0")),
+            metric: None,
+        };
+        let request = route_request(&route, Some(5));
+        assert_eq!(request.buf[HEADER_LEN + 1], 24);
+        assert_eq!(request.buf[HEADER_LEN + 6], libc::RT_SCOPE_LINK);
+        let attrs = parse_attrs(&request.buf[HEADER_LEN + 12..]);
+        assert_eq!(attrs, vec![(libc::RTA_DST, &[10u8, 8, 0, 0][..]), (libc::RTA_OIF, &5u32.to_ne_bytes()[..])]);
+    }
+
+    #[test]
+    fn reads_plain_default_routes_of_the_main_table() {
+        let reply = route_message(
+            0,
+            libc::RT_TABLE_MAIN,
+            &[
+                attr(libc::RTA_GATEWAY, &[192, 168, 1, 1]),
+                attr(libc::RTA_OIF, &2u32.to_ne_bytes()),
+                attr(libc::RTA_PRIORITY, &600u32.to_ne_bytes()),
+            ],
+        );
+        let routes = default_routes_in(&reply, false, link_name).unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].gateway, Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))));
+        assert_eq!(routes[0].interface.as_deref(), Some("eth2"));
+        assert_eq!(routes[0].metric, Some(600));
+
+        let other_table = route_message(0, libc::RT_TABLE_LOCAL, &[attr(libc::RTA_OIF, &2u32.to_ne_bytes())]);
+        assert!(default_routes_in(&other_table, false, link_name).unwrap().is_empty());
+        let not_default = route_message(24, libc::RT_TABLE_MAIN, &[attr(libc::RTA_OIF, &2u32.to_ne_bytes())]);
+        assert!(default_routes_in(&not_default, false, link_name).unwrap().is_empty());
+        assert!(default_routes_in(&[0; 4], false, link_name).is_err());
+    }
+
+    #[test]
+    fn multipath_default_routes_yield_one_route_per_next_hop() {
+        let mut hops = next_hop(2, Ipv4Addr::new(192, 168, 1, 1));
+        hops.extend(next_hop(3, Ipv4Addr::new(10, 0, 0, 1)));
+        let reply = route_message(
+            0,
+            libc::RT_TABLE_MAIN,
+            &[attr(libc::RTA_PRIORITY, &100u32.to_ne_bytes()), attr(libc::RTA_MULTIPATH, &hops)],
+        );
+        let routes = default_routes_in(&reply, false, link_name).unwrap();
+        let hops: Vec<_> = routes.iter().map(|route| (route.gateway, route.interface.as_deref(), route.metric)).collect();
+        assert_eq!(
+            hops,
+            vec![
+                (Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))), Some("eth2"), Some(100)),
+                (Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))), Some("eth3"), Some(100)),
+            ]
+        );
+
+        let mut bad = next_hop(2, Ipv4Addr::new(192, 168, 1, 1));
+        bad[0] = 200;
+        let reply = route_message(0, libc::RT_TABLE_MAIN, &[attr(libc::RTA_MULTIPATH, &bad)]);
+        assert!(default_routes_in(&reply, false, link_name).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use ipnet::{Ipv4Net, Ipv6Net};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, PartialEq)]
+pub enum PoolError {
+    Exhausted { capacity: u32 },
+    NotInPool(Ipv4Addr),
+    InUse(Ipv4Addr),
+    Ipv6NotInPool(Ipv6Addr),
+    /// A custom IPv6 address that is the one `owner` gets by default.
+    Ipv6Derived { addr: Ipv6Addr, owner: Ipv4Addr },
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Exhausted { capacity } => {
+                write!(f, "address pool exhausted, all {} client addresses are in use", capacity)
+            }
+            PoolError::NotInPool(addr) => write!(f, "{} is not a client address of the pool", addr),
+            PoolError::InUse(addr) => write!(f, "{} is already in use", addr),
+            PoolError::Ipv6NotInPool(addr) => write!(f, "{} is not a client address of the IPv6 prefix", addr),
+            PoolError::Ipv6Derived { addr, owner } => write!(f, "{} is the IPv6 address of {}", addr, owner),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// Hands out client addresses from the server's IPv4 subnet, skipping the network,
+/// broadcast and server addresses. Every client gets the IPv6 address carrying its
+/// IPv4 address in the low 32 bits of the /64, so either one identifies the client.
+pub struct AddressPool {
+    ipv4: Ipv4Net,
+    ipv6: Ipv6Net,
+    /// Lowest host offset never handed out so far.
+    next: u32,
+    /// Offsets given back, reused lowest first so assignment does not depend on timing.
+    released: BTreeSet<u32>,
+    in_use: HashSet<u32>,
+    /// Reserved or leased to someone, only handed out through [`AddressPool::take`].
+    held: HashSet<u32>,
+}
+
+impl AddressPool {
+    /// `ipv4` is the server address with the client subnet, `ipv6` the server's address in the /64.
+    pub fn new(ipv4: Ipv4Net, ipv6: Ipv6Net) -> AddressPool {
+        Self {
+            ipv4,
+            ipv6,
+            next: 1,
+            released: BTreeSet::new(),
+            in_use: HashSet::new(),
+            held: HashSet::new(),
+        }
+    }
+
+    pub fn server_ipv4(&self) -> Ipv4Addr {
+        self.ipv4.addr()
+    }
+
+    pub fn server_ipv6(&self) -> Ipv6Addr {
+        self.ipv6.addr()
+    }
+
+    /// Number of addresses clients can get.
+    pub fn capacity(&self) -> u32 {
+        self.last_offset() - 1
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+
+    /// Addresses currently assigned, lowest first.
+    pub fn allocated(&self) -> Vec<Ipv4Addr> {
+        let mut offsets: Vec<u32> = self.in_use.iter().copied().collect();
+        offsets.sort_unstable();
+        offsets
+            .into_iter()
+            .map(|offset| Ipv4Addr::from(u32::from(self.ipv4.network()) + offset))
+            .collect()
+    }
+
+    /// Number of addresses reserved or leased to someone.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn ipv4_cidr(&self) -> Ipv4Net {
+        self.ipv4
+    }
+
+    pub fn ipv6_prefix(&self) -> Ipv6Net {
+        self.ipv6
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        self.offset_of(addr).is_some()
+    }
+
+    /// Takes `preferred` when it is a free, unheld client address of this pool, otherwise the lowest such one.
+    pub fn allocate(&mut self, preferred: Option<Ipv4Addr>) -> Result<(Ipv4Addr, Ipv6Addr), PoolError> {
+        if let Some(offset) = preferred.and_then(|addr| self.offset_of(addr)) {
+            if !self.in_use.contains(&offset) && !self.held.contains(&offset) {
+                self.released.remove(&offset);
+                return Ok(self.claim(offset));
+            }
+        }
+        if let Some(offset) = self.released.iter().copied().find(|offset| !self.held.contains(offset)) {
+            self.released.remove(&offset);
+            return Ok(self.claim(offset));
+        }
+        while self.next <= self.last_offset() {
+            let offset = self.next;
+            self.next += 1;
+            if offset != self.server_offset() && !self.in_use.contains(&offset) {
+                if self.held.contains(&offset) {
+                    // still free, picked up from there once it is no longer held
+                    self.released.insert(offset);
+                    continue;
+                }
+                return Ok(self.claim(offset));
+            }
+        }
+        Err(PoolError::Exhausted { capacity: self.capacity() })
+    }
+
+    /// Claims exactly `addr`, held or not, for the user it is reserved or leased to.
+    pub fn take(&mut self, addr: Ipv4Addr) -> Result<(Ipv4Addr, Ipv6Addr), PoolError> {
+        let offset = self.offset_of(addr).ok_or(PoolError::NotInPool(addr))?;
+        if self.in_use.contains(&offset) {
+            return Err(PoolError::InUse(addr));
+        }
+        self.released.remove(&offset);
+        Ok(self.claim(offset))
+    }
+
+    /// Replaces the set of addresses [`AddressPool::allocate`] must not hand out.
+    pub fn set_held(&mut self, addrs: impl IntoIterator<Item = Ipv4Addr>) {
+        self.held = addrs
+            .into_iter()
+            .filter_map(|addr| self.offset_of(addr))
+            .collect();
+    }
+
+    pub fn contains_ipv6(&self, addr: Ipv6Addr) -> bool {
+        self.ipv6.contains(&addr) && addr != self.ipv6.addr()
+    }
+
+    /// The client address whose IPv6 address `addr` is by default, if any.
+    pub fn derived_ipv4(&self, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+        if !self.ipv6.contains(&addr) {
+            return None;
+        }
+        let host = addr.to_bits() - self.ipv6.network().to_bits();
+        u32::try_from(host)
+            .ok()
+            .map(Ipv4Addr::from)
+            .filter(|ipv4| self.contains(*ipv4))
+    }
+
+    /// Checks a static reservation: `ipv4` has to be a client address of the pool and a custom
+    /// `ipv6` one of the prefix that no other client address derives.
+    pub fn check_reservation(&self, ipv4: Ipv4Addr, ipv6: Option<Ipv6Addr>) -> Result<(), PoolError> {
+        if !self.contains(ipv4) {
+            return Err(PoolError::NotInPool(ipv4));
+        }
+        if let Some(ipv6) = ipv6 {
+            if !self.contains_ipv6(ipv6) {
+                return Err(PoolError::Ipv6NotInPool(ipv6));
+            }
+            if let Some(owner) = self.derived_ipv4(ipv6).filter(|owner| *owner != ipv4) {
+                return Err(PoolError::Ipv6Derived { addr: ipv6, owner });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn release(&mut self, addr: Ipv4Addr) {
+        if let Some(offset) = self.offset_of(addr) {
+            if self.in_use.remove(&offset) {
+                self.released.insert(offset);
+            }
+        }
+    }
+
+    pub fn ipv6_for(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        Ipv6Addr::from(self.ipv6.network().to_bits() | u32::from(addr) as u128)
+    }
+
+    fn claim(&mut self, offset: u32) -> (Ipv4Addr, Ipv6Addr) {
+        self.in_use.insert(offset);
+        let addr = Ipv4Addr::from(u32::from(self.ipv4.network()) + offset);
+        (addr, self.ipv6_for(addr))
+    }
+
+    /// Highest usable host offset, the one right below the broadcast address.
+    fn last_offset(&self) -> u32 {
+        u32::from(self.ipv4.broadcast()) - u32::from(self.ipv4.network()) - 1
+    }
+
+    fn server_offset(&self) -> u32 {
+        u32::from(self.ipv4.addr()) - u32::from(self.ipv4.network())
+    }
+
+    fn offset_of(&self, addr: Ipv4Addr) -> Option<u32> {
+        if !self.ipv4.contains(&addr) {
+            return None;
+        }
+        let offset = u32::from(addr) - u32::from(self.ipv4.network());
+        if offset == 0 || offset > self.last_offset() || offset == self.server_offset() {
+            return None;
+        }
+        Some(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> AddressPool {
+        AddressPool::new("10.0.8.1/24".parse().unwrap(), "fd00:8::1/64".parse().unwrap())
+    }
+
+    /// Five client addresses, 10.0.8.2 to 10.0.8.6, next to the server's 10.0.8.1.
+    fn small_pool() -> AddressPool {
+        AddressPool::new("10.0.8.1/29".parse().unwrap(), "fd00:8::1/64".parse().unwrap())
+    }
+
+    fn host(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 8, last)
+    }
+
+    #[test]
+    fn allocates_lowest_first_with_derived_ipv6() {
+        let mut pool = small_pool();
+        assert_eq!(pool.capacity(), 5);
+        let (ipv4, ipv6) = pool.allocate(None).unwrap();
+        assert_eq!(ipv4, host(2));
+        assert_eq!(ipv6, "fd00:8::a00:802".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(pool.allocate(None).unwrap().0, host(3));
+        assert_eq!(pool.allocated(), [host(2), host(3)]);
+        assert_eq!(pool.in_use(), 2);
+    }
+
+    #[test]
+    fn reuses_released_addresses_lowest_first() {
+        let mut pool = small_pool();
+        for _ in 0..4 {
+            pool.allocate(None).unwrap();
+        }
+        pool.release(host(4));
+        pool.release(host(2));
+        // releasing twice or outside the pool changes nothing
+        pool.release(host(2));
+        pool.release(Ipv4Addr::new(192, 168, 0, 2));
+        assert_eq!(pool.allocate(None).unwrap().0, host(2));
+        assert_eq!(pool.allocate(None).unwrap().0, host(4));
+        assert_eq!(pool.allocate(None).unwrap().0, host(6));
+    }
+
+    #[test]
+    fn reports_exhaustion() {
+        let mut pool = small_pool();
+        for _ in 0..5 {
+            pool.allocate(None).unwrap();
+        }
+        assert_eq!(pool.allocate(None), Err(PoolError::Exhausted { capacity: 5 }));
+        pool.release(host(5));
+        assert_eq!(pool.allocate(None).unwrap().0, host(5));
+        assert_eq!(pool.allocate(Some(host(6))), Err(PoolError::Exhausted { capacity: 5 }));
+    }
+
+    #[test]
+    fn honours_preferred_addresses_inside_the_pool_only() {
+        let mut pool = small_pool();
+        assert_eq!(pool.allocate(Some(host(5))).unwrap().0, host(5));
+        // taken, the server's own, the broadcast address or outside the subnet
+        for preferred in [host(5), host(1), host(7), Ipv4Addr::new(10, 0, 9, 5)] {
+            let (ipv4, _) = pool.allocate(Some(preferred)).unwrap();
+            assert_ne!(ipv4, preferred);
+            assert!(pool.contains(ipv4));
+        }
+        assert_eq!(pool.allocated(), [host(2), host(3), host(4), host(5), host(6)]);
+    }
+
+    #[test]
+    fn held_addresses_are_only_taken_explicitly() {
+        let mut pool = small_pool();
+        pool.set_held([host(2), Ipv4Addr::new(10, 0, 9, 2)]);
+        assert_eq!(pool.held(), 1);
+        assert_eq!(pool.allocate(Some(host(2))).unwrap().0, host(3));
+        assert_eq!(pool.take(host(2)).unwrap().0, host(2));
+        assert_eq!(pool.take(host(2)), Err(PoolError::InUse(host(2))));
+        assert_eq!(pool.take(host(7)), Err(PoolError::NotInPool(host(7))));
+    }
+
+    #[test]
+    fn reservations_stay_inside_the_pool() {
+        let pool = pool();
+        let ipv4 = Ipv4Addr::new(10, 0, 8, 5);
+        assert_eq!(pool.check_reservation(ipv4, None), Ok(()));
+        assert_eq!(pool.check_reservation(ipv4, Some(pool.ipv6_for(ipv4))), Ok(()));
+        assert_eq!(pool.check_reservation(ipv4, Some("fd00:8::beef:0:5".parse().unwrap())), Ok(()));
+        for outside in [Ipv4Addr::new(10, 0, 9, 5), Ipv4Addr::new(10, 0, 8, 1), Ipv4Addr::new(10, 0, 8, 255)] {
+            assert_eq!(pool.check_reservation(outside, None), Err(PoolError::NotInPool(outside)));
+        }
+        let foreign: Ipv6Addr = "fd00:9::5".parse().unwrap();
+        assert_eq!(pool.check_reservation(ipv4, Some(foreign)), Err(PoolError::Ipv6NotInPool(foreign)));
+        assert_eq!(pool.check_reservation(ipv4, Some(pool.server_ipv6())), Err(PoolError::Ipv6NotInPool(pool.server_ipv6())));
+    }
+
+    #[test]
+    fn custom_ipv6_cannot_take_another_clients_address() {
+        let pool = pool();
+        let other = Ipv4Addr::new(10, 0, 8, 6);
+        let taken = pool.ipv6_for(other);
+        assert_eq!(pool.derived_ipv4(taken), Some(other));
+        assert_eq!(
+            pool.check_reservation(Ipv4Addr::new(10, 0, 8, 5), Some(taken)),
+            Err(PoolError::Ipv6Derived { addr: taken, owner: other })
+        );
+        assert_eq!(pool.derived_ipv4("fd00:8::1:0:6".parse().unwrap()), None);
+    }
+}
@@ -6,8 +6,12 @@ use serde::de::{SeqAccess, Visitor};
 use serde::ser::SerializeTuple;
 use tfserver::bincode;
 
+use std::sync::Arc;
+
+use crate::error::ActorError;
+use crate::metrics::Metrics;
 use crate::util::rand_utils::generate_random_u8_vec;
-use crate::operational::tun_interface::IpPacket;
+use crate::operational::tun_interface::{IpPacket, MAX_PACKET_LEN};
 use crate::vpn_config::VpnConfig;
 
 use tfserver::structures::s_type;
@@ -17,6 +21,23 @@ pub const DATA_PACKET: u8 = 0;
 pub const GARBAGE_PACKET: u8 = 1;
 pub const SYSTEM_PACKET: u8 = 2;
 
+const LENGTH_PREFIX: usize = 4;
+/// Upper bound of what a packed packet takes on top of its payload: the length prefix plus
+/// the encoded type and payload length.
+pub const PACKET_OVERHEAD: usize = 16;
+/// Kept free in every frame for system packets, which are only a few hundred bytes.
+const SYSTEM_RESERVE: usize = 4096;
+
+/// Smallest `max_frame_size` that fits the garbage packets, the system packets and two of
+/// the largest IP packets.
+pub fn min_frame_size(config: &VpnConfig) -> usize {
+    garbage_budget(config) + SYSTEM_RESERVE + 2 * (MAX_PACKET_LEN + PACKET_OVERHEAD)
+}
+
+fn garbage_budget(config: &VpnConfig) -> usize {
+    config.max_garbage_packets_amount as usize * (config.garbage_packet_max_size as usize + PACKET_OVERHEAD)
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize,)]
 pub struct BytesBuff{
     pub data: Vec<u8>,
@@ -37,15 +58,33 @@ pub struct DataPacket {
 
 pub struct DataPack {
     vpn_config: VpnConfig,
+    metrics: Arc<Metrics>,
+    /// Largest frame the peer said it accepts, our own limit until it did.
+    peer_max_frame_size: usize,
 }
 
 impl DataPack {
-    pub fn new(config: VpnConfig) -> DataPack {
-        Self{vpn_config: config}
+    pub fn new(config: VpnConfig, metrics: Arc<Metrics>) -> DataPack {
+        let peer_max_frame_size = config.max_frame_size;
+        Self{vpn_config: config, metrics, peer_max_frame_size}
+    }
+
+    /// Packs outgoing frames to the smaller of our limit and `max`, so a peer configured with a
+    /// smaller `max_frame_size` never gets a frame it has to reject.
+    pub fn set_peer_max_frame_size(&mut self, max: usize) {
+        self.peer_max_frame_size = max;
     }
 
-    pub fn post_process_data(&self, mut packets: Vec<IpPacket>) -> Vec<u8> {
+    /// Packs `system_packets` (control messages such as rekeying) ahead of the IP packets.
+    pub fn post_process_data(&self, mut packets: Vec<IpPacket>, system_packets: Vec<Vec<u8>>) -> Result<Vec<u8>, ActorError> {
         let mut data = Vec::new();
+        for payload in system_packets {
+            let packet = DataPacket {
+                packet_type: SYSTEM_PACKET,
+                data: BytesBuff::new(payload),
+            };
+            Self::append_packet(&mut data, &packet)?;
+        }
         let mut garbage_packet_counter = 0;
         let garbage_amount = random_range(
             self.vpn_config.min_garbage_packets_amount..self.vpn_config.max_garbage_packets_amount,
@@ -56,12 +95,7 @@ impl DataPack {
                 let packet = Self::generate_garbage_packet(random_range(
                     self.vpn_config.garbage_packet_min_size..self.vpn_config.garbage_packet_max_size,
                 ));
-
-                let mut temp_data = bincode::serde::encode_to_vec(&packet, BINCODE_CFG.clone()).expect("Failed to serialize packet data");
-                let length_bytes: u32 = temp_data.len() as u32;
-                let length_bytes: [u8; 4] = length_bytes.to_be_bytes();
-                length_bytes.iter().for_each(|&x| data.push(x));
-                data.append(&mut temp_data);
+                Self::append_packet(&mut data, &packet)?;
                 garbage_packet_counter += 1;
             } else {
                 let packet: IpPacket = packets.pop().unwrap();
@@ -69,47 +103,74 @@ impl DataPack {
                     packet_type: DATA_PACKET,
                     data: BytesBuff::new(packet.data),
                 };
-                let mut temp_data = bincode::serde::encode_to_vec(&packed_data, BINCODE_CFG.clone()).expect("Failed to serialize packet data");
-                let length_bytes: u32 = temp_data.len() as u32;
-                let length_bytes: [u8; 4] = length_bytes.to_be_bytes();
-                length_bytes.iter().for_each(|&x| data.push(x));
-                data.append(&mut temp_data);
+                Self::append_packet(&mut data, &packed_data)?;
             }
         }
         while garbage_packet_counter < garbage_amount {
             let packet = Self::generate_garbage_packet(random_range(
                 self.vpn_config.garbage_packet_min_size..self.vpn_config.garbage_packet_max_size,
             ));
-            let mut temp_data = bincode::serde::encode_to_vec(&packet, BINCODE_CFG.clone()).expect("Failed to serialize packet data");
-            let length_bytes: u32 = temp_data.len() as u32;
-            let length_bytes: [u8; 4] = length_bytes.to_be_bytes();
-            length_bytes.iter().for_each(|&x| data.push(x));
-            data.append(&mut temp_data);
+            Self::append_packet(&mut data, &packet)?;
             garbage_packet_counter += 1;
         }
-        data
+        self.metrics.garbage_packets.add(garbage_packet_counter as u64);
+        Ok(data)
     }
 
-    pub fn pre_process_data(&self, data: &[u8]) -> Vec<DataPacket> {
-        let mut res_packets: Vec<DataPacket> = Vec::new();
-        let mut i: u64 = 0;
-        while i < data.len() as u64 {
-            let length_bytes: [u8;4] = data[i as usize..i as usize+4].try_into().unwrap();
-            let length = u32::from_be_bytes(length_bytes);
-            if length > data.len() as u32 {
-                return res_packets;
-            }
+    /// Unpacks one whole decrypted frame, dropping the garbage packets. Use [`DataPack::decoder`]
+    /// for data that may arrive in pieces.
+    pub fn pre_process_data(&self, data: &[u8]) -> Result<Vec<DataPacket>, ActorError> {
+        let mut decoder = self.decoder();
+        let packets = decoder.push(data)?;
+        if decoder.pending() > 0 {
+            return Err(ActorError::MalformedPack(format!(
+                "frame ends inside a packet, {} bytes left over",
+                decoder.pending()
+            )));
+        }
+        Ok(packets)
+    }
 
-            let start = i as usize+4;
-            let end = start + length as usize;
+    pub fn decoder(&self) -> PackDecoder {
+        PackDecoder::new(self.vpn_config.max_frame_size)
+    }
 
-            let data_packet: DataPacket = bincode::serde::decode_from_slice(&data[start..end], BINCODE_CFG.clone()).unwrap().0;
-            if data_packet.packet_type != GARBAGE_PACKET{
-                res_packets.push(data_packet);
-            }
-            i = i+4+(length as u64);
-        }
-        res_packets
+    pub fn max_frame_size(&self) -> usize {
+        self.vpn_config.max_frame_size
+    }
+
+    /// Takes as many packets from the front of `pending` as fit one frame next to the
+    /// garbage and system packets, at least one so an oversized packet cannot stall the queue.
+    pub fn take_frame_packets(&self, pending: &mut Vec<IpPacket>) -> Vec<IpPacket> {
+        let budget = self.payload_budget();
+        let mut used = 0;
+        let fitting = pending
+            .iter()
+            .take_while(|packet| {
+                used += packet.data.len() + PACKET_OVERHEAD;
+                used <= budget
+            })
+            .count();
+        pending.drain(..fitting.max(1).min(pending.len())).collect()
+    }
+
+    /// Bytes of IP packets one frame can carry without exceeding the `max_frame_size` of
+    /// either side.
+    pub fn payload_budget(&self) -> usize {
+        self.vpn_config
+            .max_frame_size
+            .min(self.peer_max_frame_size)
+            .saturating_sub(garbage_budget(&self.vpn_config) + SYSTEM_RESERVE)
+    }
+
+    fn append_packet(data: &mut Vec<u8>, packet: &DataPacket) -> Result<(), ActorError> {
+        let mut temp_data = bincode::serde::encode_to_vec(packet, BINCODE_CFG.clone())
+            .map_err(|err| ActorError::Encode(err.to_string()))?;
+        let length_bytes: u32 = temp_data.len() as u32;
+        let length_bytes: [u8; 4] = length_bytes.to_be_bytes();
+        length_bytes.iter().for_each(|&x| data.push(x));
+        data.append(&mut temp_data);
+        Ok(())
     }
 
     fn generate_garbage_packet(packet_size: u64) -> DataPacket {
@@ -120,3 +181,187 @@ impl DataPack {
         }
     }
 }
+
+/// Splits packed data into packets as it arrives. A packet cut off at the end of one chunk
+/// is kept until the next chunk completes it, every length prefix is checked against
+/// `max_frame_size` before anything is buffered for it.
+pub struct PackDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl PackDecoder {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Appends `data` and returns the packets it completed, garbage dropped. On malformed
+    /// input everything buffered is discarded along with the packets decoded so far, as
+    /// nothing after the first bad length can be trusted.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<DataPacket>, ActorError> {
+        self.buffer.extend_from_slice(data);
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        let result = loop {
+            let remaining = &self.buffer[offset..];
+            let length = match remaining.get(..LENGTH_PREFIX) {
+                Some(prefix) => u32::from_be_bytes(prefix.try_into().unwrap()) as usize,
+                None => break Ok(()),
+            };
+            if length > self.max_frame_size {
+                break Err(ActorError::MalformedPack(format!(
+                    "packet of {} bytes exceeds the maximum frame size of {}",
+                    length, self.max_frame_size
+                )));
+            }
+            let body = match remaining.get(LENGTH_PREFIX..LENGTH_PREFIX + length) {
+                Some(body) => body,
+                None => break Ok(()),
+            };
+            match Self::decode(body) {
+                Ok(packet) if packet.packet_type == GARBAGE_PACKET => {}
+                Ok(packet) => packets.push(packet),
+                Err(err) => break Err(err),
+            }
+            offset += LENGTH_PREFIX + length;
+        };
+        match result {
+            Ok(()) => {
+                self.buffer.drain(..offset);
+                Ok(packets)
+            }
+            Err(err) => {
+                self.buffer.clear();
+                Err(err)
+            }
+        }
+    }
+
+    /// Bytes of an incomplete packet waiting for the rest.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn decode(body: &[u8]) -> Result<DataPacket, ActorError> {
+        let (packet, read): (DataPacket, usize) = bincode::serde::decode_from_slice(body, BINCODE_CFG.clone())
+            .map_err(|err| ActorError::MalformedPack(err.to_string()))?;
+        if read != body.len() {
+            return Err(ActorError::MalformedPack(format!(
+                "{} bytes after the packet inside its length",
+                body.len() - read
+            )));
+        }
+        if !matches!(packet.packet_type, DATA_PACKET | GARBAGE_PACKET | SYSTEM_PACKET) {
+            return Err(ActorError::MalformedPack(format!("unknown packet type {}", packet.packet_type)));
+        }
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::example_config;
+
+    fn data_pack() -> DataPack {
+        DataPack::new(example_config(), Arc::new(Metrics::new(false)))
+    }
+
+    fn packed(packets: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (packet_type, payload) in packets {
+            let packet = DataPacket {
+                packet_type: *packet_type,
+                data: BytesBuff::new(payload.to_vec()),
+            };
+            DataPack::append_packet(&mut data, &packet).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn resumes_packets_split_across_chunks() {
+        let data = packed(&[(DATA_PACKET, b"first"), (GARBAGE_PACKET, b"xx"), (SYSTEM_PACKET, b"second")]);
+        let mut decoder = PackDecoder::new(1024);
+        let mut packets = Vec::new();
+        for chunk in data.chunks(3) {
+            packets.extend(decoder.push(chunk).unwrap());
+        }
+        assert_eq!(decoder.pending(), 0);
+        let payloads: Vec<&[u8]> = packets.iter().map(|packet| packet.data.data.as_slice()).collect();
+        assert_eq!(payloads, [b"first".as_slice(), b"second".as_slice()]);
+    }
+
+    #[test]
+    fn rejects_lengths_over_the_maximum() {
+        let mut decoder = PackDecoder::new(64);
+        let err = decoder.push(&1000u32.to_be_bytes()).unwrap_err();
+        assert!(matches!(err, ActorError::MalformedPack(_)));
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn rejects_garbage_inside_a_valid_length() {
+        let mut data = packed(&[(DATA_PACKET, b"ok")]);
+        // one stray byte inside the length of a second packet
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.push(0xff);
+        assert!(PackDecoder::new(1024).push(&data).is_err());
+
+        let mut trailing = packed(&[(DATA_PACKET, b"ok")]);
+        let length = u32::from_be_bytes(trailing[..4].try_into().unwrap()) + 1;
+        trailing[..4].copy_from_slice(&length.to_be_bytes());
+        trailing.push(0);
+        assert!(PackDecoder::new(1024).push(&trailing).is_err());
+
+        assert!(PackDecoder::new(1024).push(&packed(&[(7, b"??")])).is_err());
+    }
+
+    #[test]
+    fn keeps_an_incomplete_tail_pending() {
+        let data = packed(&[(DATA_PACKET, b"payload")]);
+        let mut decoder = PackDecoder::new(1024);
+        assert!(decoder.push(&data[..data.len() - 1]).unwrap().is_empty());
+        assert_eq!(decoder.pending(), data.len() - 1);
+        assert_eq!(decoder.push(&data[data.len() - 1..]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_frames_ending_inside_a_packet() {
+        let data_pack = data_pack();
+        let data = packed(&[(DATA_PACKET, b"first"), (DATA_PACKET, b"second")]);
+        assert_eq!(data_pack.pre_process_data(&data).unwrap().len(), 2);
+
+        let first = packed(&[(DATA_PACKET, b"first")]).len();
+        // cut inside the second length prefix, right after it and inside the body
+        for end in [first + 1, first + LENGTH_PREFIX - 1, first + LENGTH_PREFIX, data.len() - 1] {
+            let err = data_pack.pre_process_data(&data[..end]).unwrap_err();
+            assert!(matches!(err, ActorError::MalformedPack(_)), "cut at {}", end);
+        }
+        assert!(data_pack.pre_process_data(&data[..first]).is_ok());
+        assert!(data_pack.pre_process_data(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn packs_to_the_smaller_frame_limit() {
+        let mut data_pack = data_pack();
+        let own = data_pack.payload_budget();
+        data_pack.set_peer_max_frame_size(data_pack.max_frame_size() * 2);
+        assert_eq!(data_pack.payload_budget(), own);
+
+        let peer_max = min_frame_size(&data_pack.vpn_config);
+        data_pack.set_peer_max_frame_size(peer_max);
+        assert!(data_pack.payload_budget() < own);
+        let mut pending: Vec<IpPacket> = (0..64)
+            .map(|_| IpPacket { meta: None, data: vec![0x45; 1400] })
+            .collect();
+        let packets = data_pack.take_frame_packets(&mut pending);
+        let frame = data_pack.post_process_data(packets, Vec::new()).unwrap();
+        assert!(frame.len() <= peer_max);
+        // incoming frames are still checked against our own limit
+        assert_eq!(data_pack.decoder().max_frame_size, data_pack.max_frame_size());
+    }
+}
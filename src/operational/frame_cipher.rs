@@ -0,0 +1,654 @@
+use log::error;
+use crate::util::handshake::{derive_rekey, hkdf_sha256, DirectionKeys, EphemeralKey, SessionKeys};
+use crate::vpn_config::RekeyConfig;
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tfserver::bincode;
+use tfserver::openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use tfserver::structures::s_type::BINCODE_CFG;
+
+pub const EPOCH_LEN: usize = 4;
+pub const COUNTER_LEN: usize = 8;
+pub const HEADER_LEN: usize = EPOCH_LEN + COUNTER_LEN;
+pub const TAG_LEN: usize = 16;
+/// What sealing adds to the plaintext.
+pub const FRAME_OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+const NONCE_LEN: usize = 12;
+const KEY_LABEL: &[u8] = b"actor frame key v1";
+const MESSAGE_KEY_LABEL: &[u8] = b"actor message key v1";
+/// Frames older than this many counters behind the newest accepted one are dropped.
+pub const REPLAY_WINDOW_SIZE: u64 = 1024;
+/// A rekey request left unanswered this long is abandoned and may be sent again.
+const REKEY_RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    Truncated,
+    Tampered,
+    Replayed,
+    UnknownEpoch,
+    CounterExhausted,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "frame shorter than its header"),
+            FrameError::Tampered => write!(f, "frame failed authentication"),
+            FrameError::Replayed => write!(f, "frame counter already seen or too old"),
+            FrameError::UnknownEpoch => write!(f, "frame sealed with retired or unknown keys"),
+            FrameError::CounterExhausted => write!(f, "frame counter exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Debug, Default, Clone)]
+pub struct FrameStats {
+    pub frames_sealed: u64,
+    pub frames_opened: u64,
+    pub tampered_dropped: u64,
+    pub replayed_dropped: u64,
+    pub stale_dropped: u64,
+    pub rekeys: u64,
+}
+
+/// Which end of the tunnel a cipher belongs to, deciding the key directions and
+/// who initiates rekeying (always the client).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameRole {
+    Client,
+    Server,
+}
+
+/// Control messages exchanged in `SYSTEM_PACKET` frames to rotate the traffic keys.
+#[derive(Serialize, Deserialize)]
+pub enum RekeyMessage {
+    Request { epoch: u32, ephemeral: String },
+    Response { epoch: u32, ephemeral: String },
+}
+
+/// Sliding window over received counters, a bit per counter below the highest one seen.
+struct ReplayWindow {
+    highest: Option<u64>,
+    bitmap: [u64; (REPLAY_WINDOW_SIZE / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            highest: None,
+            bitmap: [0; (REPLAY_WINDOW_SIZE / 64) as usize],
+        }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let slot = counter % REPLAY_WINDOW_SIZE;
+        ((slot / 64) as usize, 1u64 << (slot % 64))
+    }
+
+    fn is_fresh(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) if highest - counter >= REPLAY_WINDOW_SIZE => false,
+            Some(_) => {
+                let (word, mask) = Self::bit(counter);
+                self.bitmap[word] & mask == 0
+            }
+        }
+    }
+
+    /// Only called for frames that passed authentication, so forged counters cannot move the window.
+    fn accept(&mut self, counter: u64) {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(counter);
+                let (word, mask) = Self::bit(counter);
+                self.bitmap[word] |= mask;
+                return;
+            }
+        };
+        if counter > highest {
+            if counter - highest >= REPLAY_WINDOW_SIZE {
+                self.bitmap = [0; (REPLAY_WINDOW_SIZE / 64) as usize];
+            } else {
+                for skipped in highest + 1..counter {
+                    let (word, mask) = Self::bit(skipped);
+                    self.bitmap[word] &= !mask;
+                }
+            }
+            self.highest = Some(counter);
+        }
+        let (word, mask) = Self::bit(counter);
+        self.bitmap[word] |= mask;
+    }
+}
+
+struct DirectionState {
+    key: Vec<u8>,
+    iv: [u8; NONCE_LEN],
+}
+
+impl DirectionState {
+    /// Keys for one use of `keys`, frames or messages, set apart by `label`.
+    fn new(keys: &DirectionKeys, label: &[u8]) -> Result<DirectionState, Box<dyn std::error::Error>> {
+        let key = general_purpose::STANDARD.decode(&keys.key)?;
+        let iv_material = general_purpose::STANDARD.decode(&keys.iv)?;
+        let okm = hkdf_sha256(&iv_material, &key, label, 32 + NONCE_LEN)?;
+        let mut iv = [0u8; NONCE_LEN];
+        iv.copy_from_slice(&okm[32..]);
+        Ok(Self {
+            key: okm[..32].to_vec(),
+            iv,
+        })
+    }
+
+    /// Per-frame nonce, the static IV xored with the counter as in TLS 1.3.
+    fn nonce(&self, counter: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = self.iv;
+        for (i, byte) in counter.to_be_bytes().iter().enumerate() {
+            nonce[NONCE_LEN - COUNTER_LEN + i] ^= byte;
+        }
+        nonce
+    }
+}
+
+/// One generation of traffic keys with its own counters.
+struct KeyEpoch {
+    id: u32,
+    keys: SessionKeys,
+    tx: DirectionState,
+    rx: DirectionState,
+    tx_counter: u64,
+    replay_window: ReplayWindow,
+    started: Instant,
+    bytes: u64,
+}
+
+impl KeyEpoch {
+    fn new(id: u32, keys: SessionKeys, role: FrameRole) -> Result<KeyEpoch, Box<dyn std::error::Error>> {
+        let (tx, rx) = match role {
+            FrameRole::Client => (&keys.client_to_server, &keys.server_to_client),
+            FrameRole::Server => (&keys.server_to_client, &keys.client_to_server),
+        };
+        Ok(Self {
+            id,
+            tx: DirectionState::new(tx, KEY_LABEL)?,
+            rx: DirectionState::new(rx, KEY_LABEL)?,
+            keys,
+            tx_counter: 0,
+            replay_window: ReplayWindow::new(),
+            started: Instant::now(),
+            bytes: 0,
+        })
+    }
+}
+
+struct PendingRekey {
+    epoch: u32,
+    ephemeral: EphemeralKey,
+    sent: Instant,
+}
+
+/// The server's answer to the last rekey request, kept until the client is seen using the
+/// new keys so a retried request gets the same answer again.
+struct AnsweredRekey {
+    request_ephemeral: String,
+    response: Vec<u8>,
+}
+
+/// ChaCha20-Poly1305 protection of tunnel frames. Each frame is
+/// `epoch (4 bytes) | counter (8 bytes) | ciphertext | tag (16 bytes)`, big endian, with the
+/// header as additional data, so any modification is rejected and nonces never repeat.
+///
+/// Keys are rotated in place: the client sends a [`RekeyMessage::Request`] once the
+/// configured limits are hit, the server answers under the old keys and switches, and both
+/// keep accepting frames from the previous epoch for `overlap_ms` so nothing in flight is lost.
+/// A client that hears nothing back sends the same request again after [`REKEY_RETRY`], so the
+/// server holds on to the previous epoch until the client has switched.
+pub struct FrameCipher {
+    role: FrameRole,
+    rekey_config: RekeyConfig,
+    current: KeyEpoch,
+    /// Retired epoch, still accepted for incoming frames until the deadline.
+    previous: Option<(KeyEpoch, Instant)>,
+    /// Set on the server between accepting a rekey and sealing the answer under the old keys.
+    seal_with_previous: bool,
+    pending_rekey: Option<PendingRekey>,
+    answered_rekey: Option<AnsweredRekey>,
+    outgoing_system: Vec<Vec<u8>>,
+    stats: FrameStats,
+}
+
+impl FrameCipher {
+    pub fn new(keys: &SessionKeys, role: FrameRole, rekey_config: RekeyConfig) -> Result<FrameCipher, Box<dyn std::error::Error>> {
+        Ok(Self {
+            role,
+            rekey_config,
+            current: KeyEpoch::new(0, keys.clone(), role)?,
+            previous: None,
+            seal_with_previous: false,
+            pending_rekey: None,
+            answered_rekey: None,
+            outgoing_system: Vec::new(),
+            stats: FrameStats::default(),
+        })
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.expire_previous();
+        let use_previous = self.seal_with_previous && self.previous.is_some();
+        self.seal_with_previous = false;
+        let epoch = if use_previous {
+            &mut self.previous.as_mut().unwrap().0
+        } else {
+            &mut self.current
+        };
+        if epoch.tx_counter == u64::MAX {
+            return Err(Box::new(FrameError::CounterExhausted));
+        }
+        let counter = epoch.tx_counter;
+        epoch.tx_counter += 1;
+        epoch.bytes += plaintext.len() as u64;
+        let mut header = [0u8; HEADER_LEN];
+        header[..EPOCH_LEN].copy_from_slice(&epoch.id.to_be_bytes());
+        header[EPOCH_LEN..].copy_from_slice(&counter.to_be_bytes());
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &epoch.tx.key,
+            Some(&epoch.tx.nonce(counter)),
+            &header,
+            plaintext,
+            &mut tag,
+        )?;
+        let mut frame = Vec::with_capacity(HEADER_LEN + ciphertext.len() + TAG_LEN);
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&ciphertext);
+        frame.extend_from_slice(&tag);
+        self.stats.frames_sealed += 1;
+        Ok(frame)
+    }
+
+    /// Authenticates and decrypts one frame, dropping (and counting) forged, replayed or stale ones.
+    pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        self.expire_previous();
+        if frame.len() < HEADER_LEN + TAG_LEN {
+            self.stats.tampered_dropped += 1;
+            return Err(FrameError::Truncated);
+        }
+        let (header, rest) = frame.split_at(HEADER_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let epoch_id = u32::from_be_bytes(header[..EPOCH_LEN].try_into().unwrap());
+        let counter = u64::from_be_bytes(header[EPOCH_LEN..].try_into().unwrap());
+        let epoch = if epoch_id == self.current.id {
+            &mut self.current
+        } else {
+            match self.previous.as_mut() {
+                Some((previous, _)) if previous.id == epoch_id => previous,
+                _ => {
+                    self.stats.stale_dropped += 1;
+                    return Err(FrameError::UnknownEpoch);
+                }
+            }
+        };
+        if !epoch.replay_window.is_fresh(counter) {
+            self.stats.replayed_dropped += 1;
+            return Err(FrameError::Replayed);
+        }
+        let plaintext = decrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &epoch.rx.key,
+            Some(&epoch.rx.nonce(counter)),
+            header,
+            ciphertext,
+            tag,
+        );
+        match plaintext {
+            Ok(plaintext) => {
+                epoch.replay_window.accept(counter);
+                epoch.bytes += plaintext.len() as u64;
+                self.stats.frames_opened += 1;
+                if epoch_id == self.current.id {
+                    self.peer_switched();
+                }
+                Ok(plaintext)
+            }
+            Err(_) => {
+                self.stats.tampered_dropped += 1;
+                Err(FrameError::Tampered)
+            }
+        }
+    }
+
+    /// Starts a rotation when this side is the client and the byte or time limit is reached.
+    pub fn poll_rekey(&mut self) {
+        if self.role != FrameRole::Client {
+            return;
+        }
+        if let Some(pending) = self.pending_rekey.as_mut() {
+            if pending.sent.elapsed() < REKEY_RETRY {
+                return;
+            }
+            // the request or its answer was lost, the server answers the same request again
+            let request = RekeyMessage::Request {
+                epoch: pending.epoch,
+                ephemeral: pending.ephemeral.public_b64.clone(),
+            };
+            pending.sent = Instant::now();
+            self.queue_system(&request);
+            return;
+        }
+        let due = self.current.bytes >= self.rekey_config.after_bytes
+            || self.current.started.elapsed() >= Duration::from_secs(self.rekey_config.after_secs);
+        if !due {
+            return;
+        }
+        let ephemeral = match EphemeralKey::generate() {
+            Ok(ephemeral) => ephemeral,
+            Err(err) => {
+                error!("Failed to start rekey: {}", err);
+                return;
+            }
+        };
+        let epoch = self.current.id.wrapping_add(1);
+        self.queue_system(&RekeyMessage::Request {
+            epoch,
+            ephemeral: ephemeral.public_b64.clone(),
+        });
+        self.pending_rekey = Some(PendingRekey {
+            epoch,
+            ephemeral,
+            sent: Instant::now(),
+        });
+    }
+
+    /// Handles a `SYSTEM_PACKET` payload received inside an authenticated frame.
+    pub fn handle_system(&mut self, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let message: RekeyMessage = bincode::serde::decode_from_slice(payload, BINCODE_CFG.clone())?.0;
+        match (message, self.role) {
+            (RekeyMessage::Request { epoch, ephemeral }, FrameRole::Server) => {
+                match self.answered_rekey.as_ref() {
+                    Some(answered) if epoch == self.current.id && answered.request_ephemeral == ephemeral => {
+                        self.outgoing_system.push(answered.response.clone());
+                        self.seal_with_previous = true;
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+                if epoch != self.current.id.wrapping_add(1) {
+                    return Err(format!("unexpected rekey epoch {}", epoch).into());
+                }
+                let own = EphemeralKey::generate()?;
+                let shared = own.diffie_hellman(&ephemeral)?;
+                let keys = derive_rekey(&self.current.keys, &shared, epoch)?;
+                let response = encode_system(&RekeyMessage::Response {
+                    epoch,
+                    ephemeral: own.public_b64.clone(),
+                });
+                self.install(KeyEpoch::new(epoch, keys, self.role)?);
+                self.outgoing_system.push(response.clone());
+                self.answered_rekey = Some(AnsweredRekey { request_ephemeral: ephemeral, response });
+                // the client needs the answer before it can read anything under the new keys
+                self.seal_with_previous = true;
+                Ok(())
+            }
+            (RekeyMessage::Response { epoch, ephemeral }, FrameRole::Client) => {
+                let pending = match self.pending_rekey.take() {
+                    Some(pending) if pending.epoch == epoch => pending,
+                    _ => return Err(format!("unsolicited rekey response for epoch {}", epoch).into()),
+                };
+                let shared = pending.ephemeral.diffie_hellman(&ephemeral)?;
+                let keys = derive_rekey(&self.current.keys, &shared, epoch)?;
+                self.install(KeyEpoch::new(epoch, keys, self.role)?);
+                Ok(())
+            }
+            _ => Err("rekey message sent by the wrong side".into()),
+        }
+    }
+
+    /// System payloads waiting to be packed into the next outgoing frame.
+    pub fn take_system_packets(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.outgoing_system)
+    }
+
+    pub fn has_system_packets(&self) -> bool {
+        !self.outgoing_system.is_empty()
+    }
+
+    pub fn stats(&self) -> &FrameStats {
+        &self.stats
+    }
+
+    fn install(&mut self, epoch: KeyEpoch) {
+        let retired = std::mem::replace(&mut self.current, epoch);
+        let deadline = Instant::now() + Duration::from_millis(self.rekey_config.overlap_ms);
+        self.previous = Some((retired, deadline));
+        self.stats.rekeys += 1;
+    }
+
+    fn queue_system(&mut self, message: &RekeyMessage) {
+        self.outgoing_system.push(encode_system(message));
+    }
+
+    /// Called for every frame opened under the current keys. On a server waiting for the
+    /// client to take up its answer, that is the sign it did, and the previous epoch is
+    /// only kept for the overlap from here on.
+    fn peer_switched(&mut self) {
+        if let (Some(_), Some((_, deadline))) = (self.answered_rekey.take(), self.previous.as_mut()) {
+            *deadline = Instant::now() + Duration::from_millis(self.rekey_config.overlap_ms);
+        }
+    }
+
+    fn expire_previous(&mut self) {
+        if let Some((_, deadline)) = self.previous.as_ref() {
+            if Instant::now() >= *deadline && !self.seal_with_previous && self.answered_rekey.is_none() {
+                self.previous = None;
+            }
+        }
+    }
+}
+
+/// Seals a message exchanged outside the tunnel frames, like the registration, under the key
+/// `keys` hold for messages: `nonce (12 bytes) | ciphertext | tag (16 bytes)`. Without a
+/// counter to build it from the nonce is random.
+pub fn seal_message(keys: &DirectionKeys, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let state = DirectionState::new(keys, MESSAGE_KEY_LABEL)?;
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(Cipher::chacha20_poly1305(), &state.key, Some(&nonce), &[], plaintext, &mut tag)?;
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+/// Opens what [`seal_message`] sealed, failing with a [`FrameError`] on any modification.
+pub fn open_message(keys: &DirectionKeys, sealed: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(Box::new(FrameError::Truncated));
+    }
+    let state = DirectionState::new(keys, MESSAGE_KEY_LABEL)?;
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt_aead(Cipher::chacha20_poly1305(), &state.key, Some(nonce), &[], ciphertext, tag)
+        .map_err(|_| Box::new(FrameError::Tampered) as Box<dyn std::error::Error>)
+}
+
+fn encode_system(message: &RekeyMessage) -> Vec<u8> {
+    bincode::serde::encode_to_vec(message, BINCODE_CFG.clone()).expect("Failed to serialize rekey message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::handshake::derive_secrets;
+
+    fn pair(rekey_config: RekeyConfig) -> (FrameCipher, FrameCipher) {
+        let user_key = general_purpose::STANDARD.encode([0x5a; 32]);
+        let keys = derive_secrets(&user_key, &[7; 32], b"test").unwrap().keys;
+        (
+            FrameCipher::new(&keys, FrameRole::Client, rekey_config.clone()).unwrap(),
+            FrameCipher::new(&keys, FrameRole::Server, rekey_config).unwrap(),
+        )
+    }
+
+    #[test]
+    fn round_trips_both_ways() {
+        let (mut client, mut server) = pair(RekeyConfig::default());
+        let frame = client.seal(b"to the server").unwrap();
+        assert_eq!(frame.len(), b"to the server".len() + FRAME_OVERHEAD);
+        assert_eq!(server.open(&frame).unwrap(), b"to the server");
+        assert_eq!(client.open(&server.seal(b"to the client").unwrap()).unwrap(), b"to the client");
+        // each direction has its own key
+        let echo = client.seal(b"echo").unwrap();
+        assert_eq!(client.open(&echo), Err(FrameError::Tampered));
+    }
+
+    #[test]
+    fn rejects_replays_but_not_reordering() {
+        let (mut client, mut server) = pair(RekeyConfig::default());
+        let frames: Vec<Vec<u8>> = (0..3).map(|n| client.seal(&[n]).unwrap()).collect();
+        assert_eq!(server.open(&frames[2]).unwrap(), [2]);
+        assert_eq!(server.open(&frames[0]).unwrap(), [0]);
+        assert_eq!(server.open(&frames[0]), Err(FrameError::Replayed));
+        assert_eq!(server.open(&frames[1]).unwrap(), [1]);
+        assert_eq!(server.open(&frames[2]), Err(FrameError::Replayed));
+        assert_eq!(server.stats().replayed_dropped, 2);
+    }
+
+    #[test]
+    fn drops_frames_behind_the_window() {
+        let (mut client, mut server) = pair(RekeyConfig::default());
+        let frames: Vec<Vec<u8>> = (0..REPLAY_WINDOW_SIZE + 1).map(|_| client.seal(b"x").unwrap()).collect();
+        server.open(frames.last().unwrap()).unwrap();
+        // the oldest frame is exactly one window behind, the next one still inside it
+        assert_eq!(server.open(&frames[0]), Err(FrameError::Replayed));
+        assert!(server.open(&frames[1]).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_frames() {
+        let (mut client, mut server) = pair(RekeyConfig::default());
+        let frame = client.seal(b"payload").unwrap();
+        let last = frame.len() - 1;
+        for position in [HEADER_LEN - 1, HEADER_LEN, last] {
+            let mut tampered = frame.clone();
+            tampered[position] ^= 1;
+            assert_eq!(server.open(&tampered), Err(FrameError::Tampered), "bit flipped at {}", position);
+        }
+        assert_eq!(server.open(&frame[..HEADER_LEN + TAG_LEN - 1]), Err(FrameError::Truncated));
+        assert_eq!(server.stats().tampered_dropped, 4);
+        // forgeries did not move the replay window
+        assert_eq!(server.open(&frame).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn messages_are_authenticated() {
+        let (client, _) = pair(RekeyConfig::default());
+        let keys = &client.current.keys;
+        let sealed = seal_message(&keys.server_to_client, b"registration").unwrap();
+        assert_eq!(open_message(&keys.server_to_client, &sealed).unwrap(), b"registration");
+        // a fresh nonce every time
+        assert_ne!(seal_message(&keys.server_to_client, b"registration").unwrap(), sealed);
+
+        let error = |result: Result<Vec<u8>, Box<dyn std::error::Error>>| result.unwrap_err().downcast::<FrameError>().map(|err| *err).ok();
+        for position in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[position] ^= 1;
+            assert_eq!(error(open_message(&keys.server_to_client, &tampered)), Some(FrameError::Tampered), "bit flipped at {}", position);
+        }
+        assert_eq!(error(open_message(&keys.client_to_server, &sealed)), Some(FrameError::Tampered));
+        assert_eq!(error(open_message(&keys.server_to_client, &sealed[..NONCE_LEN + TAG_LEN - 1])), Some(FrameError::Truncated));
+    }
+
+    #[test]
+    fn rejects_unknown_epochs() {
+        let (mut client, mut server) = pair(RekeyConfig::default());
+        let mut frame = client.seal(b"payload").unwrap();
+        frame[..EPOCH_LEN].copy_from_slice(&7u32.to_be_bytes());
+        assert_eq!(server.open(&frame), Err(FrameError::UnknownEpoch));
+        assert_eq!(server.stats().stale_dropped, 1);
+    }
+
+    /// Runs one rotation, returning a frame the client sealed under the old keys while the
+    /// request was in flight.
+    fn rekey(client: &mut FrameCipher, server: &mut FrameCipher) -> Vec<u8> {
+        client.poll_rekey();
+        let request = client.take_system_packets();
+        assert_eq!(request.len(), 1);
+        let in_flight = client.seal(b"sealed before the switch").unwrap();
+        server.handle_system(&request[0]).unwrap();
+        let response = server.take_system_packets();
+        // the answer goes out under the old keys, the client cannot read the new ones yet
+        let answer = server.seal(b"answer").unwrap();
+        assert_eq!(client.open(&answer).unwrap(), b"answer");
+        client.handle_system(&response[0]).unwrap();
+        in_flight
+    }
+
+    #[test]
+    fn rekey_keeps_the_old_epoch_during_the_overlap() {
+        let config = RekeyConfig { after_bytes: 0, overlap_ms: 60_000, ..Default::default() };
+        let (mut client, mut server) = pair(config);
+        let in_flight = rekey(&mut client, &mut server);
+
+        let frame = client.seal(b"new keys").unwrap();
+        assert_eq!(u32::from_be_bytes(frame[..EPOCH_LEN].try_into().unwrap()), 1);
+        assert_eq!(server.open(&frame).unwrap(), b"new keys");
+        assert_eq!(server.open(&in_flight).unwrap(), b"sealed before the switch");
+        assert_eq!(client.open(&server.seal(b"back").unwrap()).unwrap(), b"back");
+        assert_eq!((client.stats().rekeys, server.stats().rekeys), (1, 1));
+    }
+
+    #[test]
+    fn rekey_drops_the_old_epoch_after_the_overlap() {
+        let config = RekeyConfig { after_bytes: 0, overlap_ms: 0, ..Default::default() };
+        let (mut client, mut server) = pair(config);
+        let in_flight = rekey(&mut client, &mut server);
+
+        // the overlap starts once the server sees the client use the new keys
+        assert_eq!(server.open(&client.seal(b"new keys").unwrap()).unwrap(), b"new keys");
+        assert_eq!(server.open(&in_flight), Err(FrameError::UnknownEpoch));
+    }
+
+    #[test]
+    fn rekey_survives_a_lost_response() {
+        let config = RekeyConfig { after_bytes: 0, overlap_ms: 0, ..Default::default() };
+        let (mut client, mut server) = pair(config);
+        client.poll_rekey();
+        let request = client.take_system_packets();
+        server.handle_system(&request[0]).unwrap();
+        // the response never makes it to the client
+        server.take_system_packets();
+        server.seal(b"lost").unwrap();
+
+        client.poll_rekey();
+        assert!(!client.has_system_packets());
+        client.pending_rekey.as_mut().unwrap().sent = Instant::now().checked_sub(REKEY_RETRY).unwrap();
+        client.poll_rekey();
+        let retry = client.take_system_packets();
+        assert_eq!(retry, request);
+
+        // past the overlap, but the client has not switched yet, so its old keys still work
+        assert_eq!(server.open(&client.seal(b"retry").unwrap()).unwrap(), b"retry");
+        server.handle_system(&retry[0]).unwrap();
+        let response = server.take_system_packets();
+        assert_eq!(response.len(), 1);
+        assert_eq!(client.open(&server.seal(b"answer").unwrap()).unwrap(), b"answer");
+        let in_flight = client.seal(b"sealed before the switch").unwrap();
+        client.handle_system(&response[0]).unwrap();
+
+        assert_eq!(server.open(&client.seal(b"new keys").unwrap()).unwrap(), b"new keys");
+        assert_eq!(client.open(&server.seal(b"back").unwrap()).unwrap(), b"back");
+        assert_eq!((client.stats().rekeys, server.stats().rekeys), (1, 1));
+        // once the client switched the retired epoch goes as usual
+        assert_eq!(server.open(&in_flight), Err(FrameError::UnknownEpoch));
+    }
+}
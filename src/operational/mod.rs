@@ -1,3 +1,5 @@
+pub mod address_pool;
 pub mod data_pack;
+pub mod frame_cipher;
 pub mod packet_router;
 pub mod tun_interface;
\ No newline at end of file
@@ -1,7 +1,11 @@
 use std::any::Any;
+use crate::error::ActorError;
+use crate::metrics::Metrics;
+use crate::operational::address_pool::AddressPool;
 use crate::operational::tun_interface::{IpPacket, TunInterface};
 use crate::util::semaphore::Semaphore;
 use std::collections::HashMap;
+use ipnet::{Ipv4Net, Ipv6Net};
 use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ops::DerefMut;
@@ -19,11 +23,13 @@ pub trait PacketReceiver: Any + Send {
 }
 
 pub struct PacketRouterCreateInfo {
-    pub router_subnet: Ipv4Addr,
-    pub router_subnet_ipv6: Ipv6Addr,
+    pub ipv4_cidr: Ipv4Net,
+    pub ipv6_prefix: Ipv6Net,
     pub tun_interface: Arc<Mutex<TunInterface>>,
     pub resyncer_timeout: Duration,
     pub max_packets_attempts_amount: u32,
+    pub client_to_client: bool,
+    pub metrics: Arc<Metrics>,
 }
 #[derive(Clone)]
 pub struct AddressTuple {
@@ -37,7 +43,7 @@ impl AddressTuple {
         Self {
             ip,
             ip6: Ipv6Addr::from([0; 16]),
-            network_id: ip.octets()[3] as u32,
+            network_id: u32::from(ip),
         }
     }
     pub fn new6(ip: Ipv6Addr) -> Self {
@@ -58,11 +64,10 @@ impl AddressTuple {
     }
 
     pub fn new_full(ip: Ipv4Addr, ip6: Ipv6Addr) -> Self {
-        let network_id = ip.octets()[3];
         Self {
             ip,
             ip6,
-            network_id: network_id as u32,
+            network_id: u32::from(ip),
         }
     }
 }
@@ -87,93 +92,111 @@ impl Hash for AddressTuple {
 
 impl Eq for AddressTuple {}
 
+/// Which address a new receiver should get.
+pub enum AddressRequest {
+    Any,
+    /// Used when free, otherwise any address is fine.
+    Preferred(Ipv4Addr),
+    /// Exactly this address, reserved or leased to the user; the IPv6 one follows from it unless given.
+    Fixed(Ipv4Addr, Option<Ipv6Addr>),
+}
+
 pub struct PacketRouter {
     registered_addresses: HashMap<AddressTuple, Arc<Mutex<dyn PacketReceiver>>>,
-    router_subnet: Ipv4Addr,
-    router_subnet_ipv6: Ipv6Addr,
-    /** @TODO
-    In one time i will extend the clients pull amount, and enable packets exchange between clients
-    But not today...*/
-    subnet_counter: u32,
-    free_addresses: Vec<AddressTuple>,
+    /// Registered IPv6 addresses by IPv4 address, `registered_addresses` hashes on the latter.
+    ipv6_index: HashMap<Ipv6Addr, Ipv4Addr>,
+    pool: AddressPool,
     interface: Arc<Mutex<TunInterface>>,
     resyncer_timeout: Duration,
     max_packets_attempts_amount: u32,
     receiver_semaphore: Semaphore,
+    /// Deliver packets between clients directly instead of through the TUN device; when off they are dropped.
+    client_to_client: bool,
+    metrics: Arc<Metrics>,
 }
 
 impl PacketRouter {
     pub fn new(create_info: PacketRouterCreateInfo) -> PacketRouter {
         Self {
             registered_addresses: HashMap::new(),
-            router_subnet: create_info.router_subnet,
-            subnet_counter: 2,
+            ipv6_index: HashMap::new(),
+            pool: AddressPool::new(create_info.ipv4_cidr, create_info.ipv6_prefix),
             interface: create_info.tun_interface,
             resyncer_timeout: create_info.resyncer_timeout,
             max_packets_attempts_amount: create_info.max_packets_attempts_amount,
-            router_subnet_ipv6: create_info.router_subnet_ipv6,
             receiver_semaphore: Semaphore::new(1),
-            free_addresses: Vec::new(),
+            client_to_client: create_info.client_to_client,
+            metrics: create_info.metrics,
         }
     }
 
     pub fn register(
         &mut self,
         receiver: Arc<Mutex<dyn PacketReceiver>>,
-    ) -> (Ipv4Addr, Ipv6Addr, Arc<Mutex<dyn PacketReceiver>>) {
-        let tupple: AddressTuple = if self.free_addresses.is_empty() {
-            let addr = Ipv4Addr::new(
-                self.router_subnet.octets()[0],
-                self.router_subnet.octets()[1],
-                self.router_subnet.octets()[2],
-                self.subnet_counter as u8,
-            );
-            let base_segments = self.router_subnet_ipv6.segments();
-            let network_prefix = ((base_segments[0] as u128) << 112)
-                | ((base_segments[1] as u128) << 96)
-                | ((base_segments[2] as u128) << 80)
-                | ((base_segments[3] as u128) << 64);
-
-            let full_addr = network_prefix | (self.subnet_counter as u128);
-            let addr6 = Ipv6Addr::from(full_addr);
-            AddressTuple {
-                ip: addr,
-                ip6: addr6,
-                network_id: self.subnet_counter as u32,
+        request: AddressRequest,
+    ) -> Result<(Ipv4Addr, Ipv6Addr, Arc<Mutex<dyn PacketReceiver>>), ActorError> {
+        let (ip, ip6) = match request {
+            AddressRequest::Any => self.pool.allocate(None)?,
+            AddressRequest::Preferred(addr) => self.pool.allocate(Some(addr))?,
+            AddressRequest::Fixed(addr, fixed_ip6) => {
+                let (ip, ip6) = self.pool.take(addr)?;
+                match fixed_ip6 {
+                    Some(fixed_ip6)
+                        if self.pool.check_reservation(ip, Some(fixed_ip6)).is_ok()
+                            && !self.ipv6_index.contains_key(&fixed_ip6) =>
+                    {
+                        (ip, fixed_ip6)
+                    }
+                    _ => (ip, ip6),
+                }
             }
-        } else {
-            self.free_addresses.pop().unwrap()
         };
+        let tupple = AddressTuple::new_full(ip, ip6);
 
         self.receiver_semaphore.acquire();
         self.registered_addresses.insert(tupple.clone(), receiver.clone());
-        self.subnet_counter += 1;
+        self.ipv6_index.insert(ip6, ip);
         let res = (
             tupple.ip,
             tupple.ip6,
             receiver.clone(),
         );
         self.receiver_semaphore.release();
-        res
+        Ok(res)
     }
 
     pub fn deregister(&mut self, addr: Ipv4Addr) {
-        let mut tupple = AddressTuple::new(addr);
+        let tupple = AddressTuple::new(addr);
         self.receiver_semaphore.acquire();
-        let val = self.registered_addresses.remove(&tupple);
-        let base_segments = self.router_subnet_ipv6.segments();
-        let network_prefix = ((base_segments[0] as u128) << 112)
-            | ((base_segments[1] as u128) << 96)
-            | ((base_segments[2] as u128) << 80)
-            | ((base_segments[3] as u128) << 64);
-
-        let full_addr = network_prefix | (tupple.network_id as u128);
-        let addr6 = Ipv6Addr::from(full_addr);
-        tupple.ip6 = addr6;
-        self.free_addresses.push(tupple);
+        self.registered_addresses.remove(&tupple);
+        self.ipv6_index.retain(|_, ip| *ip != addr);
+        self.pool.release(addr);
         self.receiver_semaphore.release();
     }
 
+    pub fn set_client_to_client(&mut self, enabled: bool) {
+        self.client_to_client = enabled;
+    }
+
+    pub fn pool(&self) -> &AddressPool {
+        &self.pool
+    }
+
+    pub fn pool_mut(&mut self) -> &mut AddressPool {
+        &mut self.pool
+    }
+
+    /// Key of the receiver owning `addr`, either of its addresses works.
+    fn key_for(&self, addr: &IpAddr) -> AddressTuple {
+        match addr {
+            IpAddr::V6(v6) => match self.ipv6_index.get(v6) {
+                Some(v4) => AddressTuple::new(*v4),
+                None => AddressTuple::new6(*v6),
+            },
+            IpAddr::V4(_) => AddressTuple::new_addr(addr),
+        }
+    }
+
     pub fn receive_packets(&mut self) {
         let mut interface = self.interface.lock().expect("Lock failed");
         let mut packets: HashMap<IpAddr, Vec<IpPacket>> = HashMap::new();
@@ -183,14 +206,19 @@ impl PacketRouter {
             let packet = interface.read_packet_non_block();
             if packet.is_some() {
                 let packet = packet.unwrap();
+                self.metrics.packets_from_tun.inc();
                 if packet.meta.is_some() {
                     let key = packet.meta.as_ref().unwrap().destination.clone();
-                    let tupple = AddressTuple::new_addr(&key);
+                    let tupple = self.key_for(&key);
                     let rec = self.registered_addresses.get_mut(&tupple);
                     if rec.is_some(){
                         let mut rec = rec.unwrap().lock().unwrap();
                         rec.receive_packet(packet);
+                    } else {
+                        self.metrics.packets_dropped.inc();
                     }
+                } else {
+                    self.metrics.packets_dropped.inc();
                 }
             }
             if self.resyncer_timeout.as_nanos() > 0{
@@ -201,16 +229,182 @@ impl PacketRouter {
         self.receiver_semaphore.release();
 
     }
-    pub fn write_packets(&mut self) {
+    /// Delivers what the sessions sent, to other sessions or the TUN device. Packets from
+    /// another source than the session's own addresses are dropped, so no client can pose as
+    /// another. A packet the device refuses is dropped and counted without holding up the
+    /// rest, the last such error is returned.
+    pub fn write_packets(&mut self) -> Result<(), ActorError> {
         let mut interface = self.interface.lock().expect("Failed to lock interface");
         self.receiver_semaphore.acquire();
+        let mut outgoing: Vec<(AddressTuple, Vec<IpPacket>)> = Vec::new();
         self.registered_addresses
             .iter_mut()
             .for_each(|(key, receiver)| {
-                receiver.lock().unwrap().get_packets().iter().for_each(|packet| {
-                    interface.write(packet.data.as_slice());
-                })
+                outgoing.push((key.clone(), receiver.lock().unwrap().get_packets()));
             });
+        let mut failed = None;
+        let queued: usize = outgoing.iter().map(|(_, packets)| packets.len()).sum();
+        self.metrics.router_queue_depth.set(queued as i64);
+        for (source, packets) in outgoing {
+            for packet in packets {
+                let meta = match packet.meta.as_ref() {
+                    Some(meta) => meta,
+                    None => {
+                        self.metrics.packets_dropped.inc();
+                        continue;
+                    }
+                };
+                let from_owner = match meta.source {
+                    IpAddr::V4(v4) => v4 == source.ip,
+                    IpAddr::V6(v6) => v6 == source.ip6,
+                };
+                if !from_owner {
+                    self.metrics.packets_spoofed.inc();
+                    continue;
+                }
+                let destination = self.key_for(&meta.destination);
+                let peer = Some(destination)
+                    .filter(|destination| *destination != source)
+                    .and_then(|destination| self.registered_addresses.get(&destination));
+                match peer {
+                    Some(peer) if self.client_to_client => {
+                        peer.lock().unwrap().receive_packet(packet);
+                        self.metrics.packets_between_clients.inc();
+                    }
+                    // the kernel would route it straight back to the peer, so denying means dropping
+                    Some(_) => self.metrics.packets_dropped.inc(),
+                    None => match interface.write(packet.data.as_slice()) {
+                        Ok(()) => self.metrics.packets_to_tun.inc(),
+                        Err(err) => {
+                            self.metrics.packets_dropped.inc();
+                            failed = Some(err);
+                        }
+                    },
+                }
+            }
+        }
         self.receiver_semaphore.release();
+        failed.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operational::tun_interface::IpPacketMeta;
+
+    /// A session's queues, what it sent waiting in `outgoing`.
+    #[derive(Default)]
+    struct Queue {
+        outgoing: Vec<IpPacket>,
+        received: Vec<IpPacket>,
+    }
+
+    impl PacketReceiver for Queue {
+        fn receive_packets(&mut self, packets: Vec<IpPacket>) {
+            self.received.extend(packets);
+        }
+
+        fn receive_packet(&mut self, packet: IpPacket) {
+            self.received.push(packet);
+        }
+
+        fn get_packets(&mut self) -> Vec<IpPacket> {
+            std::mem::take(&mut self.outgoing)
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn packet(source: IpAddr, destination: IpAddr) -> IpPacket {
+        let meta = IpPacketMeta {
+            version: if source.is_ipv4() { 4 } else { 6 },
+            source,
+            destination,
+            ttl_or_hop_limit: 64,
+            identification: None,
+            checksum: None,
+        };
+        IpPacket { meta: Some(meta), data: Vec::new() }
+    }
+
+    struct Session {
+        queue: Arc<Mutex<Queue>>,
+        ipv4: IpAddr,
+        ipv6: IpAddr,
+    }
+
+    fn router(client_to_client: bool) -> (PacketRouter, Session, Session) {
+        let mut router = PacketRouter::new(PacketRouterCreateInfo {
+            ipv4_cidr: "10.0.8.1/24".parse().unwrap(),
+            ipv6_prefix: "fd00:8::1/64".parse().unwrap(),
+            tun_interface: Arc::new(Mutex::new(TunInterface::closed())),
+            resyncer_timeout: Duration::ZERO,
+            max_packets_attempts_amount: 1,
+            client_to_client,
+            metrics: Arc::new(Metrics::new(false)),
+        });
+        let mut session = || {
+            let queue = Arc::new(Mutex::new(Queue::default()));
+            let (ipv4, ipv6, _) = router.register(queue.clone(), AddressRequest::Any).unwrap();
+            Session { queue, ipv4: IpAddr::V4(ipv4), ipv6: IpAddr::V6(ipv6) }
+        };
+        let (a, b) = (session(), session());
+        (router, a, b)
+    }
+
+    fn send(session: &Session, packets: Vec<IpPacket>) {
+        session.queue.lock().unwrap().outgoing.extend(packets);
+    }
+
+    #[test]
+    fn delivers_between_clients() {
+        let (mut router, a, b) = router(true);
+        let outside: IpAddr = "192.0.2.1".parse().unwrap();
+        send(&a, vec![packet(a.ipv4, b.ipv4), packet(a.ipv6, b.ipv6), packet(a.ipv4, outside)]);
+        router.write_packets().unwrap();
+        assert_eq!(b.queue.lock().unwrap().received.len(), 2);
+        assert_eq!(router.metrics.packets_between_clients.get(), 2);
+        assert_eq!(router.metrics.packets_to_tun.get(), 1);
+    }
+
+    #[test]
+    fn drops_between_clients_when_denied() {
+        let (mut router, a, b) = router(false);
+        let outside: IpAddr = "192.0.2.1".parse().unwrap();
+        send(&a, vec![packet(a.ipv4, b.ipv4), packet(a.ipv6, b.ipv6), packet(a.ipv4, outside)]);
+        router.write_packets().unwrap();
+        assert!(b.queue.lock().unwrap().received.is_empty());
+        assert_eq!(router.metrics.packets_dropped.get(), 2);
+        assert_eq!(router.metrics.packets_to_tun.get(), 1);
+
+        router.set_client_to_client(true);
+        send(&a, vec![packet(a.ipv4, b.ipv4)]);
+        router.write_packets().unwrap();
+        assert_eq!(b.queue.lock().unwrap().received.len(), 1);
+    }
+
+    #[test]
+    fn drops_spoofed_sources() {
+        let (mut router, a, b) = router(true);
+        let outside: IpAddr = "192.0.2.1".parse().unwrap();
+        let unassigned: IpAddr = "10.0.8.200".parse().unwrap();
+        send(&a, vec![
+            packet(b.ipv4, outside),
+            packet(b.ipv6, b.ipv6),
+            packet(unassigned, b.ipv4),
+            packet(outside, a.ipv4),
+        ]);
+        router.write_packets().unwrap();
+        assert!(b.queue.lock().unwrap().received.is_empty());
+        assert!(a.queue.lock().unwrap().received.is_empty());
+        assert_eq!(router.metrics.packets_spoofed.get(), 4);
+        assert_eq!(router.metrics.packets_to_tun.get(), 0);
     }
 }
@@ -1,14 +1,19 @@
+use crate::error::ActorError;
 use cfg_if::cfg_if;
 use etherparse::{InternetSlice, SlicedPacket};
 use nix::fcntl::{FcntlArg, OFlag, fcntl};
 use std::io;
 use std::io::{Read, Write};
 use std::net::{IpAddr, Ipv4Addr};
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, RawFd};
 use tun::{AbstractDevice, Configuration, Device, ToAddress};
 
+/// Read buffer size, no packet from the device is larger.
+pub const MAX_PACKET_LEN: usize = 65537;
+
 pub struct TunInterface {
-    device: Device,
+    /// `None` once [`TunInterface::close`] removed the device.
+    device: Option<Device>,
     iff_name: String,
     iff_ip: IpAddr,
     iff_netmask: IpAddr,
@@ -33,6 +38,7 @@ pub struct TunInterfaceCreateInfo {
     iff_name: Option<String>,
     iff_ip: IpAddr,
     iff_netmask: IpAddr,
+    iff_mtu: Option<u16>,
 }
 
 impl TunInterfaceCreateInfo {
@@ -59,6 +65,14 @@ impl TunInterfaceCreateInfo {
     pub fn set_iff_netmask(&mut self, iff_netmask: &dyn ToAddress) {
         self.iff_netmask = iff_netmask.to_address().unwrap();
     }
+
+    pub fn iff_mtu(&self) -> &Option<u16> {
+        &self.iff_mtu
+    }
+
+    pub fn set_iff_mtu(&mut self, iff_mtu: u16) {
+        self.iff_mtu = Some(iff_mtu);
+    }
 }
 
 impl Default for TunInterfaceCreateInfo {
@@ -67,12 +81,13 @@ impl Default for TunInterfaceCreateInfo {
             iff_name: None,
             iff_ip: IpAddr::from([0; 4]),
             iff_netmask: IpAddr::from([0; 4]),
+            iff_mtu: None,
         }
     }
 }
 
 impl TunInterface {
-    pub fn new(create_info: &TunInterfaceCreateInfo) -> Self {
+    pub fn new(create_info: &TunInterfaceCreateInfo) -> Result<Self, ActorError> {
         let mut config = Configuration::default();
         config
             .address(create_info.iff_ip)
@@ -80,30 +95,33 @@ impl TunInterface {
         if create_info.iff_name.is_some() {
             config.tun_name(create_info.iff_name.as_ref().unwrap().clone());
         }
+        if let Some(mtu) = create_info.iff_mtu {
+            config.mtu(mtu);
+        }
         config.up();
 
         let mut res = Self {
-            device: tun::create(&config).expect("Failed to create device"),
+            device: Some(tun::create(&config).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?),
             iff_name: String::new(),
             iff_ip: create_info.iff_ip,
             iff_netmask: create_info.iff_netmask,
-            buffer: vec![0u8; 65537],
+            buffer: vec![0u8; MAX_PACKET_LEN],
         };
         cfg_if! {
         if #[cfg(unix)] {
                 unsafe{
-                    Self::set_non_blocking(&mut res.device).expect("Failed to set non-blocking");
+                    Self::set_non_blocking(res.device.as_ref().unwrap())?;
                 }
 
         }
             }
-        let name = res.device.tun_name();
+        let name = res.device.as_ref().unwrap().tun_name();
         if name.is_ok() {
             res.iff_name = name.unwrap();
         } else if create_info.iff_name.is_some() {
             res.iff_name = create_info.iff_name.as_ref().unwrap().clone();
         }
-        res
+        Ok(res)
     }
 
     cfg_if! {
@@ -121,8 +139,29 @@ impl TunInterface {
             }
         }
 
+    /// An interface whose device is already closed, for tests of code that only needs one to exist.
+    #[cfg(test)]
+    pub(crate) fn closed() -> Self {
+        Self {
+            device: None,
+            iff_name: String::new(),
+            iff_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            iff_netmask: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Closes the device, which removes it from the system as it is not persistent.
+    pub fn close(&mut self) {
+        self.device = None;
+    }
+
+    pub fn raw_fd(&self) -> RawFd {
+        self.device.as_ref().map_or(-1, |device| device.as_raw_fd())
+    }
+
     pub fn read_packet_non_block(&mut self) -> Option<IpPacket> {
-        let n_res = self.device.read(&mut self.buffer);
+        let n_res = self.device.as_mut()?.read(&mut self.buffer);
         if n_res.is_err() {
             return None;
         }
@@ -135,15 +174,22 @@ impl TunInterface {
         })
     }
 
-    pub fn read(&mut self, buffer: &mut [u8]) {
-        self.device.read(buffer).expect("Failed to read tun device");
+    /// Blocking read into `buffer`, 0 once the device is closed.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ActorError> {
+        match self.device.as_mut() {
+            Some(device) => Ok(device.read(buffer)?),
+            None => Ok(0),
+        }
     }
 
-    pub fn write(&mut self, buffer: &[u8]) {
-       
-        self.device
-            .write_all(buffer)
-            .expect("Failed to write tun device");
+    pub fn write(&mut self, buffer: &[u8]) -> Result<(), ActorError> {
+        let device = match self.device.as_mut() {
+            Some(device) => device,
+            // closed on shutdown, whatever is still in flight is dropped
+            None => return Ok(()),
+        };
+        device.write_all(buffer)?;
+        Ok(())
     }
 
     pub fn extract_general_ip_header(packet: &[u8]) -> Option<IpPacketMeta> {
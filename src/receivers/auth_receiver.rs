@@ -1,5 +1,5 @@
-use crate::handlers::actor_structure_type::{ActorStructureType, ChallengeAuthReq, ClientAnswerChallenge, ServerAuthoriChallenge};
-use crate::util::challenge_util::decrypt_aes_ecb_base64;
+use crate::handlers::actor_structure_type::{ActorStructureType, HandshakeComplete, HandshakeFinish, HandshakeInit, HandshakeResponse};
+use crate::util::handshake::{derive_secrets, derive_user_key, prove, transcript, verify_identity, verify_proof, EphemeralKey, SessionKeys};
 use crate::vpn_config::VpnConfig;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
@@ -9,11 +9,72 @@ use tfserver::structures::s_type::StructureType;
 use crate::receivers::register_receiver::RegisterReceiver;
 
 pub struct AuthReceiver {
-    pub(crate) auth_passed: AtomicBool,
-    pub(crate) challenge_answer: Option<ClientAnswerChallenge>,
-    pub(crate) config: Arc<VpnConfig>,
-    pub(crate) iv_result: Option<String>,
-    pub(crate) register_receiver: Arc<Mutex<RegisterReceiver>>,
+    pub auth_passed: AtomicBool,
+    /// Our half of the exchange, generated when the handshake starts.
+    pub ephemeral: Option<EphemeralKey>,
+    /// Proof waiting to be sent once the server's response has been processed.
+    pub finish: Option<HandshakeFinish>,
+    pub session: Option<SessionKeys>,
+    /// Confirmation key and transcript the server's `HandshakeComplete` is checked against.
+    pub server_confirm: Option<(Vec<u8>, Vec<u8>)>,
+    pub config: Arc<VpnConfig>,
+    pub register_receiver: Arc<Mutex<RegisterReceiver>>,
+}
+
+impl AuthReceiver {
+    /// Checks the server holds the expected identity key if one is pinned, before anything
+    /// derived from the user key is sent.
+    fn verify_identity(&self, response: &HandshakeResponse, transcript: &[u8]) -> Result<(), String> {
+        if let Some(pinned) = self.config.client.server_public_key.as_ref() {
+            if response.signature.is_empty() {
+                return Err("server presented no identity signature but a server key is pinned".to_string());
+            }
+            if !verify_identity(pinned.as_str(), transcript, response.signature.as_str()) {
+                return Err("server identity does not match the pinned server key, possible man-in-the-middle".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Derives the session from the server's half of the exchange and queues our proof.
+    fn process_response(&mut self, response: &[u8]) -> Result<(), String> {
+        let response = s_type::from_slice::<HandshakeResponse>(response)
+            .map_err(|err| format!("malformed handshake response: {}", err))?;
+        let ephemeral = self.ephemeral.as_ref().ok_or("handshake response before our init")?;
+        let user_key = derive_user_key(self.config.key.as_str(), response.salt.as_str())
+            .map_err(|err| format!("bad salt from the server: {}", err))?;
+        let shared = ephemeral
+            .diffie_hellman(response.ephemeral.as_str())
+            .map_err(|err| format!("bad ephemeral key from the server: {}", err))?;
+        let transcript = transcript(
+            self.config.client.username.as_str(),
+            ephemeral.public_b64.as_str(),
+            response.ephemeral.as_str(),
+            response.salt.as_str(),
+        );
+        self.verify_identity(&response, &transcript)?;
+        let secrets = derive_secrets(user_key.as_str(), &shared, &transcript).map_err(|err| err.to_string())?;
+        self.finish = Some(HandshakeFinish {
+            s_type: ActorStructureType::ClientHandshakeFinish,
+            proof: prove(&secrets.client_confirm, &transcript).map_err(|err| err.to_string())?,
+        });
+        self.session = Some(secrets.keys);
+        self.server_confirm = Some((secrets.server_confirm, transcript));
+        Ok(())
+    }
+
+    /// Checks the server knows our user key before the session is used.
+    fn process_complete(&mut self, complete: &[u8]) -> Result<(), String> {
+        let complete = s_type::from_slice::<HandshakeComplete>(complete)
+            .map_err(|err| format!("malformed handshake completion: {}", err))?;
+        let (server_confirm, transcript) = self.server_confirm.take().ok_or("handshake completed before our proof")?;
+        if !verify_proof(&server_confirm, &transcript, complete.proof.as_str()) {
+            return Err("server could not prove knowledge of the user key, refusing to continue".to_string());
+        }
+        self.auth_passed.store(true, std::sync::atomic::Ordering::Relaxed);
+        self.register_receiver.lock().unwrap().session_current = self.session.clone();
+        Ok(())
+    }
 }
 
 impl Receiver for AuthReceiver {
@@ -24,51 +85,39 @@ impl Receiver for AuthReceiver {
 
 
     fn get_request(&mut self) -> Option<(Vec<u8>, Box<dyn StructureType>)> {
-        if !self.auth_passed.load(std::sync::atomic::Ordering::Relaxed)
-            && !self
-                .challenge_answer.is_some()
-        {
-            let challenge_req = ChallengeAuthReq {
-                s_type: ActorStructureType::ClientChallengeReq,
+        if self.auth_passed.load(std::sync::atomic::Ordering::Relaxed) {
+            return None;
+        }
+        if self.ephemeral.is_none() {
+            let ephemeral = EphemeralKey::generate().unwrap();
+            let init = HandshakeInit {
+                s_type: ActorStructureType::ClientHandshakeInit,
+                username: self.config.client.username.clone(),
+                ephemeral: ephemeral.public_b64.clone(),
             };
+            self.ephemeral = Some(ephemeral);
             Some((
-                s_type::to_vec(&challenge_req).unwrap(),
-                Box::from(ActorStructureType::ClientChallengeReq),
+                s_type::to_vec(&init).unwrap(),
+                Box::from(ActorStructureType::ClientHandshakeInit),
+            ))
+        } else if let Some(finish) = self.finish.take() {
+            Some((
+                s_type::to_vec(&finish).unwrap(),
+                Box::new(ActorStructureType::ClientHandshakeFinish),
             ))
-        } else if self
-            .challenge_answer.is_some()
-        {
-            let answer = s_type::to_vec(self.challenge_answer.as_ref().unwrap()).unwrap();
-            Some((answer, Box::new(ActorStructureType::ClientAuthAnswer)))
         } else {
-            return None;
+            None
         }
     }
 
     fn receive_response(&mut self, response: Vec<u8>) {
-        if !self
-            .challenge_answer.is_some()
-        {
-            let challenge =
-                s_type::from_slice::<ServerAuthoriChallenge>(response.as_slice()).unwrap();
-            let challenge_answer =
-                decrypt_aes_ecb_base64(self.config.key.as_str(), challenge.challenge.as_str())
-                    .unwrap();
-            println!("{:?}", challenge_answer);
-            self.challenge_answer = Some(ClientAnswerChallenge{s_type: ActorStructureType::ClientAuthAnswer, answer: challenge_answer});
+        let result = if self.session.is_none() {
+            self.process_response(&response)
         } else {
-            for x in response.iter() {
-                print!("{}", *x as char);
-            }
-            println!();
-            let challenge =
-                s_type::from_slice::<ServerAuthoriChallenge>(response.as_slice()).unwrap();
-            let challenge_answer =
-                decrypt_aes_ecb_base64(self.config.key.as_str(), challenge.challenge.as_str())
-                    .unwrap();
-            self.auth_passed.store(true, std::sync::atomic::Ordering::Relaxed);
-            self.register_receiver.lock().unwrap().iv_current = Some(challenge_answer.clone());
-            self.iv_result = Some(challenge_answer);
+            self.process_complete(&response)
+        };
+        if let Err(reason) = result {
+            self.register_receiver.lock().unwrap().on_register_info.lock().unwrap().handshake_failed(reason);
         }
     }
 }
@@ -1,6 +1,9 @@
+use log::{debug, error};
 use crate::handlers::actor_structure_type::{
     ActorStructureType, RegisterHandlerAnswer, RegisterHandlerRequest,
 };
+use crate::operational::frame_cipher::{open_message, seal_message};
+use crate::util::handshake::SessionKeys;
 use crate::vpn_config::VpnConfig;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
@@ -9,17 +12,31 @@ use tfserver::structures::s_type;
 use tfserver::structures::s_type::StructureType;
 
 pub trait OnRegisterInfoReceiver: Send + Sync {
-    fn info_received(&mut self, iv: String, reg_info: RegisterHandlerAnswer);
+    /// Brings the tunnel up with the assigned addresses, failing when the answer is unusable.
+    fn info_received(&mut self, session: SessionKeys, reg_info: RegisterHandlerAnswer) -> Result<(), String>;
+    /// Called when the server could not be verified; the connection must not be used.
+    fn handshake_failed(&mut self, reason: String);
+    /// Called when the server accepted us but could not assign addresses, e.g. its pool is exhausted.
+    fn register_failed(&mut self, reason: String);
 }
 
 pub struct RegisterReceiver {
-    pub iv_current: Option<String>,
-    pub(crate) reg_info: Option<RegisterHandlerAnswer>,
-    pub(crate) data_send: AtomicBool,
-    pub(crate) config: Arc<VpnConfig>,
+    pub session_current: Option<SessionKeys>,
+    pub reg_info: Option<RegisterHandlerAnswer>,
+    pub data_send: AtomicBool,
+    pub config: Arc<VpnConfig>,
     pub on_register_info: Arc<Mutex<dyn OnRegisterInfoReceiver>>,
 }
 
+impl RegisterReceiver {
+    /// Opens the server's answer, refusing it if it was modified on the way.
+    fn open_answer(session: &SessionKeys, response: &[u8]) -> Result<RegisterHandlerAnswer, String> {
+        let data = open_message(&session.server_to_client, response)
+            .map_err(|err| format!("registration answer rejected: {}", err))?;
+        s_type::from_slice::<RegisterHandlerAnswer>(&data).map_err(|err| format!("malformed registration answer: {}", err))
+    }
+}
+
 impl Receiver for RegisterReceiver {
     fn get_handler_name(&self) -> String {
         "REGISTER_HANDLER".to_string()
@@ -27,14 +44,24 @@ impl Receiver for RegisterReceiver {
 
 
     fn get_request(&mut self) -> Option<(Vec<u8>, Box<dyn StructureType>)> {
-        if self.iv_current.is_some() && !self.data_send.load(std::sync::atomic::Ordering::SeqCst) {
-            println!("Awaiting reg info");
+        let session = self.session_current.as_ref()?;
+        if !self.data_send.load(std::sync::atomic::Ordering::SeqCst) {
+            debug!("Awaiting registration info");
             self.data_send.store(true, std::sync::atomic::Ordering::SeqCst);
             let request = RegisterHandlerRequest {
                 s_type: ActorStructureType::RegisterHandlerRequest,
+                requested_ipv4: self.reg_info.as_ref().map(|info| info.ipv4.clone()),
+                requested_ipv6: self.reg_info.as_ref().map(|info| info.ipv6.clone()),
+                max_frame_size: self.config.max_frame_size as u64,
+            };
+            debug!("Sending registration request");
+            let register_req = match seal_message(&session.client_to_server, &s_type::to_vec(&request).unwrap()) {
+                Ok(register_req) => register_req,
+                Err(err) => {
+                    error!("Failed to seal the registration request: {}", err);
+                    return None;
+                }
             };
-            println!("Sending register info request");
-            let register_req = s_type::to_vec(&request).unwrap();
             return Some((
                 register_req,
                 Box::from(ActorStructureType::RegisterHandlerRequest),
@@ -45,14 +72,77 @@ impl Receiver for RegisterReceiver {
     }
 
     fn receive_response(&mut self, response: Vec<u8>) {
-        println!("Received reginfo");
-        let response = s_type::from_encrypted_slice::<RegisterHandlerAnswer>(
-            response.as_slice(),
-            self.config.encryption_type,
-            self.config.key.clone(),
-            self.iv_current.as_ref().unwrap().as_bytes(),
-        ).unwrap();
-        self.reg_info = Some(response.clone());
-        self.on_register_info.lock().unwrap().info_received(self.iv_current.as_ref().unwrap().clone(), response);
+        debug!("Received registration info");
+        let session = match self.session_current.clone() {
+            Some(session) => session,
+            None => return,
+        };
+        let response = Self::open_answer(&session, &response);
+        let mut listener = self.on_register_info.lock().unwrap();
+        let response = match response {
+            Ok(response) => response,
+            Err(reason) => return listener.register_failed(reason),
+        };
+        if let Some(reason) = response.error {
+            return listener.register_failed(reason);
+        }
+        match listener.info_received(session, response.clone()) {
+            Ok(()) => self.reg_info = Some(response),
+            Err(reason) => listener.register_failed(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::handshake::derive_secrets;
+    use crate::util::testing::example_config;
+    use base64::{engine::general_purpose, Engine as _};
+
+    #[derive(Default)]
+    struct Outcome {
+        failures: Vec<String>,
+        infos: usize,
+    }
+
+    impl OnRegisterInfoReceiver for Outcome {
+        fn info_received(&mut self, _session: SessionKeys, _reg_info: RegisterHandlerAnswer) -> Result<(), String> {
+            self.infos += 1;
+            Ok(())
+        }
+
+        fn handshake_failed(&mut self, reason: String) {
+            self.failures.push(reason);
+        }
+
+        fn register_failed(&mut self, reason: String) {
+            self.failures.push(reason);
+        }
+    }
+
+    #[test]
+    fn tampered_answers_are_rejected() {
+        let user_key = general_purpose::STANDARD.encode([0x5a; 32]);
+        let session = derive_secrets(&user_key, &[7; 32], b"test").unwrap().keys;
+        let outcome = Arc::new(Mutex::new(Outcome::default()));
+        let mut receiver = RegisterReceiver {
+            session_current: Some(session.clone()),
+            reg_info: None,
+            data_send: AtomicBool::new(false),
+            config: Arc::new(example_config()),
+            on_register_info: outcome.clone(),
+        };
+
+        let mut answer = seal_message(&session.server_to_client, b"dns = 192.0.2.53").unwrap();
+        let last = answer.len() - 1;
+        answer[last] ^= 1;
+        receiver.receive_response(answer);
+
+        let outcome = outcome.lock().unwrap();
+        assert_eq!(outcome.infos, 0);
+        assert_eq!(outcome.failures.len(), 1);
+        assert!(outcome.failures[0].starts_with("registration answer rejected"), "{}", outcome.failures[0]);
+        assert!(receiver.reg_info.is_none());
     }
 }
@@ -1,91 +1,363 @@
-use std::process::Command;
-use std::io;
+use log::warn;
+use std::net::{IpAddr, Ipv6Addr};
+use ipnet::{IpNet, Ipv6Net};
+use crate::handlers::actor_structure_type::NetworkOptions;
+use crate::netconfig::{ensure_rule, NetBackend, NetError, Route};
+use crate::vpn_config::{FirewallBackend, TunnelMode};
 
-fn run_command(cmd: &str) -> io::Result<()> {
-    let status = Command::new("sh")
-        .arg("-c")
-        .arg(cmd)
-        .status()?;
+fn args(items: &[&str]) -> Vec<String> {
+    items.iter().map(|item| item.to_string()).collect()
+}
+
+/// Table holding every nftables rule of the server.
+pub const NFT_TABLE: &str = "actor";
+
+/// Brings up the server side of the tunnel and forwards and masquerades its traffic out
+/// of `net_if` (e.g. "eth0"). Running it again changes nothing.
+pub fn setup_tun_interface(
+    net: &mut dyn NetBackend,
+    iface: &str,
+    server_ipv6: Ipv6Net,
+    net_if: &str,
+    firewall: FirewallBackend,
+) -> Result<(), NetError> {
+    net.add_address(iface, IpNet::V6(server_ipv6))?;
+    net.set_link(iface, true, None)?;
+
+    net.set_sysctl("net.ipv4.ip_forward", "1")?;
+    net.set_sysctl("net.ipv6.conf.all.forwarding", "1")?;
+
+    match firewall {
+        FirewallBackend::Iptables => setup_iptables(net, iface, net_if),
+        FirewallBackend::Nftables => {
+            let ruleset = nftables_ruleset(iface, net_if)?;
+            net.run_with_input("nft", &args(&["-f", "-"]), &ruleset)
+        }
+    }
+}
 
-    if !status.success() {
-        eprintln!("Command failed: {}", cmd);
+fn setup_iptables(net: &mut dyn NetBackend, iface: &str, net_if: &str) -> Result<(), NetError> {
+    for program in ["iptables", "ip6tables"] {
+        ensure_rule(net, program, &args(&["FORWARD", "-i", iface, "-o", net_if, "-j", "ACCEPT"]))?;
+        ensure_rule(
+            net,
+            program,
+            &args(&["FORWARD", "-i", net_if, "-o", iface, "-m", "state", "--state", "RELATED,ESTABLISHED", "-j", "ACCEPT"]),
+        )?;
     }
+    // IPv6 is forwarded without NAT
+    ensure_rule(net, "iptables", &args(&["POSTROUTING", "-t", "nat", "-o", net_if, "-j", "MASQUERADE"]))?;
 
     Ok(())
 }
 
-pub fn setup_tun_interface(
+/// Interface names end up in double quotes inside an nft script.
+fn nft_name(name: &str) -> Result<String, NetError> {
+    if name.is_empty() || name.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+        return Err(NetError::InvalidName(name.to_string()));
+    }
+    Ok(format!("\"{}\"", name))
+}
+
+/// The server's own table: forwarding between the tunnel and `net_if` for both families,
+/// with replies let back in, and IPv4 masqueraded on the way out. Declaring the table and
+/// deleting it first makes `nft -f` replace it atomically when it already exists.
+fn nftables_ruleset(iface: &str, net_if: &str) -> Result<String, NetError> {
+    let (tun, uplink) = (nft_name(iface)?, nft_name(net_if)?);
+    Ok(format!(
+        "table inet {table}
+delete table inet {table}
+table inet {table} {{
+    chain forward {{
+        type filter hook forward priority filter; policy accept;
+        iifname {tun} oifname {uplink} accept
+        iifname {uplink} oifname {tun} ct state related,established accept
+    }}
+    chain postrouting {{
+        type nat hook postrouting priority srcnat; policy accept;
+        meta nfproto ipv4 oifname {uplink} masquerade
+    }}
+}}
+",
+        table = NFT_TABLE,
+    ))
+}
+
+/// Routes added by [`route_traffic_through_tun`] and the default routes in place before,
+/// everything [`restore_routes`] needs to put the routing table back.
+#[derive(Debug, Default)]
+pub struct RouteState {
+    added: Vec<Route>,
+    original_defaults: Vec<Route>,
+}
+
+fn add_route(net: &mut dyn NetBackend, state: &mut RouteState, route: Route) -> Result<(), NetError> {
+    net.replace_route(&route)?;
+    state.added.push(route);
+    Ok(())
+}
+
+fn default_net(ipv6: bool) -> IpNet {
+    if ipv6 { "::/0".parse().unwrap() } else { "0.0.0.0/0".parse().unwrap() }
+}
+
+/// Sends traffic into `iface`: everything in full mode, only `include` in split mode.
+/// `exclude` and the VPN server's own addresses keep using the original default route,
+/// which also keeps the tunnel connection itself out of the tunnel. On failure the routes
+/// changed so far are put back before the error is returned.
+pub fn route_traffic_through_tun(
+    net: &mut dyn NetBackend,
     iface: &str,
-    server_ipv4: &str,
-    server_ipv6: &str,
-    net_if: &str, // e.g., "eth0"
-) -> io::Result<()> {
-    let commands = vec![
-        // Create the TUN interface (optional, may require privileges)
-        // format!("ip tuntap add dev {} mode tun", iface),
-
-        // Assign IPv4 and IPv6 to TUN interface
-        // format!("ip addr add {} dev {}", server_ipv4, iface),
-        format!("ip addr add {} dev {}", server_ipv6, iface),
-
-        // Bring up the interface
-        format!("ip link set {} up", iface),
-
-        // Enable IP forwarding
-        "sysctl -w net.ipv4.ip_forward=1".to_string(),
-        "sysctl -w net.ipv6.conf.all.forwarding=1".to_string(),
-
-        // IPv4 NAT rules
-        format!("iptables -A FORWARD -i {} -o {} -j ACCEPT", iface, net_if),
-        format!(
-            "iptables -A FORWARD -i {} -o {} -m state --state RELATED,ESTABLISHED -j ACCEPT",
-            net_if, iface
-        ),
-        format!("iptables -t nat -A POSTROUTING -o {} -j MASQUERADE", net_if),
-
-        // IPv6 forwarding rules (no NAT typically)
-        format!("ip6tables -A FORWARD -i {} -o {} -j ACCEPT", iface, net_if),
-        format!(
-            "ip6tables -A FORWARD -i {} -o {} -m state --state RELATED,ESTABLISHED -j ACCEPT",
-            net_if, iface
-        ),
-    ];
-
-    for cmd in commands {
-        run_command(&cmd)?;
+    mode: TunnelMode,
+    include: &[IpNet],
+    exclude: &[IpNet],
+    server_addrs: &[IpAddr],
+) -> Result<RouteState, NetError> {
+    let mut state = RouteState::default();
+    match add_tunnel_routes(net, &mut state, iface, mode, include, exclude, server_addrs) {
+        Ok(()) => Ok(state),
+        Err(err) => {
+            if let Err(restore_err) = restore_routes(net, state) {
+                warn!("Failed to roll back the routes added before the error: {}", restore_err);
+            }
+            Err(err)
+        }
     }
+}
 
+fn add_tunnel_routes(
+    net: &mut dyn NetBackend,
+    state: &mut RouteState,
+    iface: &str,
+    mode: TunnelMode,
+    include: &[IpNet],
+    exclude: &[IpNet],
+    server_addrs: &[IpAddr],
+) -> Result<(), NetError> {
+    for ipv6 in [false, true] {
+        let originals = net.default_routes(ipv6)?;
+        match originals.first() {
+            Some(uplink) => {
+                let uplink = uplink.clone();
+                for addr in server_addrs.iter().filter(|addr| addr.is_ipv6() == ipv6) {
+                    add_route(net, state, Route::via_uplink(IpNet::from(*addr), &uplink))?;
+                }
+                for network in exclude.iter().filter(|network| matches!(network, IpNet::V6(_)) == ipv6) {
+                    add_route(net, state, Route::via_uplink(*network, &uplink))?;
+                }
+            }
+            None => {
+                if exclude.iter().any(|network| matches!(network, IpNet::V6(_)) == ipv6) {
+                    warn!("No default {} route, excluded networks will not be reachable", if ipv6 { "IPv6" } else { "IPv4" });
+                }
+            }
+        }
+        match mode {
+            TunnelMode::Full => {
+                // the originals go first so the tunnel's default is the only one left
+                for original in originals {
+                    net.delete_route(&original)?;
+                    state.original_defaults.push(original);
+                }
+                add_route(net, state, Route::via_interface(default_net(ipv6), iface))?;
+            }
+            TunnelMode::Split => {
+                for network in include.iter().filter(|network| matches!(network, IpNet::V6(_)) == ipv6) {
+                    add_route(net, state, Route::via_interface(*network, iface))?;
+                }
+            }
+        }
+    }
     Ok(())
 }
 
-pub fn route_all_traffic_through_tun(
+/// Removes the routes added by [`route_traffic_through_tun`] and brings back the default routes it displaced.
+pub fn restore_routes(net: &mut dyn NetBackend, state: RouteState) -> Result<(), NetError> {
+    for route in state.added.iter().rev() {
+        net.delete_route(route)?;
+    }
+    for original in &state.original_defaults {
+        net.replace_route(original)?;
+    }
+    Ok(())
+}
+
+/// Applies what the server pushed and the TUN device cannot carry itself: the IPv6
+/// address and the DNS settings. Pushed routes go through [`route_traffic_through_tun`].
+pub fn apply_network_options(
+    net: &mut dyn NetBackend,
     iface: &str,
-    client_ipv4: &str,
-    client_ipv6: &str,
-) -> io::Result<()> {
-    let commands = vec![
-        // Delete existing default routes (ignore error with `|| true`)
-        "ip route del default || true".to_string(),
-        "ip -6 route del default || true".to_string(),
+    client_ipv6: Ipv6Addr,
+    options: &NetworkOptions,
+) -> Result<(), NetError> {
+    let address = Ipv6Net::new(client_ipv6, options.ipv6_prefix_len).unwrap_or_else(|_| Ipv6Net::from(client_ipv6));
+    net.add_address(iface, IpNet::V6(address))?;
+    net.set_link(iface, true, None)?;
+
+    // per-link DNS through systemd-resolved, other resolvers are left alone
+    if !options.dns_servers.is_empty() {
+        let mut dns = args(&["dns", iface]);
+        dns.extend(options.dns_servers.iter().map(|server| server.to_string()));
+        net.run("resolvectl", &dns)?;
+    }
+    if !options.search_domains.is_empty() {
+        let mut domain = args(&["domain", iface]);
+        domain.extend(options.search_domains.iter().cloned());
+        net.run("resolvectl", &domain)?;
+    }
+
+    Ok(())
+}
 
-        // Create TUN interface (optional)
-        // format!("ip tuntap add dev {} mode tun", iface),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netconfig::mock::MockBackend;
+    use crate::netconfig::NetOp;
 
-        // Assign IP addresses to TUN interface
-        // format!("ip addr add {} dev {}", client_ipv4, iface),
-        format!("ip addr add {} dev {}", client_ipv6, iface),
+    fn uplink() -> Route {
+        Route {
+            destination: "0.0.0.0/0".parse().unwrap(),
+            gateway: Some("192.168.1.1".parse().unwrap()),
+            interface: Some("eth0".to_string()),
+            metric: Some(100),
+        }
+    }
 
-        // Bring up the interface
-        format!("ip link set {} up", iface),
+    fn backend() -> MockBackend {
+        let mut net = MockBackend::with_links(&["eth0", "actor-tun0"]);
+        net.routes.push(uplink());
+        net
+    }
 
-        // Route all traffic through TUN
-        format!("ip route add default dev {}", iface),
-        // format!("ip -6 route add default dev {}", iface),
-    ];
+    #[test]
+    fn server_setup_is_idempotent() {
+        let mut net = backend();
+        let address = "fd00::1/64".parse().unwrap();
+        setup_tun_interface(&mut net, "actor-tun0", address, "eth0", FirewallBackend::Iptables).unwrap();
+        let first = net.changes().len();
+        assert_eq!(net.rules.len(), 5);
+        assert_eq!(net.sysctls.get("net.ipv4.ip_forward").map(String::as_str), Some("1"));
 
-    for cmd in commands {
-        run_command(&cmd)?;
+        setup_tun_interface(&mut net, "actor-tun0", address, "eth0", FirewallBackend::Iptables).unwrap();
+        assert_eq!(net.rules.len(), 5);
+        assert_eq!(net.addresses.len(), 1);
+        // the second run only re-applies the link, address and sysctls, no rule is appended
+        let appended = net.changes()[first..]
+            .iter()
+            .filter(|op| matches!(op, NetOp::Command { .. }))
+            .count();
+        assert_eq!(appended, 0);
     }
 
-    Ok(())
+    #[test]
+    fn interface_names_are_passed_as_single_arguments() {
+        let mut net = MockBackend::with_links(&["tun; rm -rf /", "eth0"]);
+        setup_tun_interface(&mut net, "tun; rm -rf /", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Iptables).unwrap();
+        assert!(net.ops.iter().any(|op| matches!(
+            op,
+            NetOp::Command { args, .. } if args.iter().any(|arg| arg == "tun; rm -rf /")
+        )));
+    }
+
+    #[test]
+    fn missing_interface_is_an_error() {
+        let mut net = MockBackend::with_links(&["eth0"]);
+        let err = setup_tun_interface(&mut net, "actor-tun0", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Iptables).unwrap_err();
+        assert!(matches!(err, NetError::NoSuchInterface(name) if name == "actor-tun0"));
+    }
+
+    #[test]
+    fn nftables_ruleset_goes_into_its_own_table() {
+        let mut net = backend();
+        setup_tun_interface(&mut net, "actor-tun0", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Nftables).unwrap();
+        assert!(net.rules.is_empty());
+        assert_eq!(net.scripts.len(), 1);
+        let script = &net.scripts[0];
+        assert!(script.contains("delete table inet actor"));
+        assert!(script.contains("iifname \"actor-tun0\" oifname \"eth0\" accept"));
+        assert!(script.contains("meta nfproto ipv4 oifname \"eth0\" masquerade"));
+    }
+
+    #[test]
+    fn nftables_rejects_names_that_break_out_of_quotes() {
+        let mut net = MockBackend::with_links(&["tun\"0", "eth0"]);
+        let err = setup_tun_interface(&mut net, "tun\"0", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Nftables).unwrap_err();
+        assert!(matches!(err, NetError::InvalidName(_)));
+        assert!(net.scripts.is_empty());
+    }
+
+    #[test]
+    fn failing_firewall_tool_is_reported() {
+        let mut net = backend();
+        net.failing.insert("iptables".to_string());
+        let err = setup_tun_interface(&mut net, "actor-tun0", "fd00::1/64".parse().unwrap(), "eth0", FirewallBackend::Iptables).unwrap_err();
+        assert!(matches!(err, NetError::CommandFailed { .. }));
+    }
+
+    #[test]
+    fn full_tunnel_replaces_default_route_and_restores_it() {
+        let mut net = backend();
+        let server: IpAddr = "203.0.113.7".parse().unwrap();
+        let state = route_traffic_through_tun(&mut net, "actor-tun0", TunnelMode::Full, &[], &[], &[server]).unwrap();
+
+        let defaults = net.default_routes(false).unwrap();
+        assert_eq!(defaults, vec![Route::via_interface("0.0.0.0/0".parse().unwrap(), "actor-tun0")]);
+        let pinned = Route::via_uplink("203.0.113.7/32".parse().unwrap(), &uplink());
+        assert!(net.routes.contains(&pinned));
+
+        restore_routes(&mut net, state).unwrap();
+        assert_eq!(net.routes, vec![uplink()]);
+    }
+
+    #[test]
+    fn split_tunnel_only_routes_included_networks() {
+        let mut net = backend();
+        let include: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "fd10::/16".parse().unwrap()];
+        let exclude: Vec<IpNet> = vec!["10.1.0.0/16".parse().unwrap()];
+        let state = route_traffic_through_tun(&mut net, "actor-tun0", TunnelMode::Split, &include, &exclude, &[]).unwrap();
+
+        assert!(net.routes.contains(&uplink()));
+        assert!(net.routes.contains(&Route::via_interface(include[0], "actor-tun0")));
+        assert!(net.routes.contains(&Route::via_interface(include[1], "actor-tun0")));
+        assert!(net.routes.contains(&Route::via_uplink(exclude[0], &uplink())));
+
+        restore_routes(&mut net, state).unwrap();
+        assert_eq!(net.routes, vec![uplink()]);
+    }
+
+    #[test]
+    fn failed_routing_puts_back_what_it_changed() {
+        // without the TUN link every route through it fails, after the uplink routes went in
+        let mut net = MockBackend::with_links(&["eth0"]);
+        net.routes.push(uplink());
+        let server: IpAddr = "203.0.113.7".parse().unwrap();
+        let include: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let err = route_traffic_through_tun(&mut net, "actor-tun0", TunnelMode::Split, &include, &[], &[server]).unwrap_err();
+        assert!(matches!(err, NetError::NoSuchInterface(_)));
+        assert_eq!(net.routes, vec![uplink()]);
+
+        let err = route_traffic_through_tun(&mut net, "actor-tun0", TunnelMode::Full, &[], &[], &[server]).unwrap_err();
+        assert!(matches!(err, NetError::NoSuchInterface(_)));
+        assert_eq!(net.routes, vec![uplink()]);
+    }
+
+    #[test]
+    fn network_options_set_address_and_dns() {
+        let mut net = backend();
+        let options = NetworkOptions {
+            dns_servers: vec!["10.8.0.1".parse().unwrap()],
+            search_domains: vec!["corp.example".to_string()],
+            ..Default::default()
+        };
+        let address: Ipv6Addr = "fd00::5".parse().unwrap();
+        apply_network_options(&mut net, "actor-tun0", address, &options).unwrap();
+        apply_network_options(&mut net, "actor-tun0", address, &options).unwrap();
+
+        assert_eq!(net.addresses, vec![("actor-tun0".to_string(), "fd00::5/64".parse().unwrap())]);
+        assert!(net.ops.contains(&NetOp::Command {
+            program: "resolvectl".to_string(),
+            args: args(&["dns", "actor-tun0", "10.8.0.1"]),
+        }));
+    }
 }
@@ -0,0 +1,384 @@
+use log::{error, warn};
+use crate::operational::packet_router::PacketRouter;
+use crate::server::lease_store::{Lease, LeaseStore};
+use crate::server::proxy_internal_server::ProxyServerInternal;
+use crate::server::user_store::UserStore;
+use crate::util::deadline::DeadlineReader;
+use crate::util::poll::wait_readable;
+use crate::util::semaphore::Semaphore;
+use crate::util::shutdown;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::fs::DirBuilder;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long an admin connection may sit idle before it is dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a request line may take to arrive once it started.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections served at once, further ones are closed right away.
+const MAX_CONNECTIONS: usize = 8;
+
+/// One request per line, e.g. `{"command": "kick", "user": "alice"}`.
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum AdminRequest {
+    ListSessions,
+    /// Closes the sessions of `user`, the one holding `ipv4`, or both.
+    Kick { user: Option<String>, ipv4: Option<Ipv4Addr> },
+    /// Disables the user in the users file and closes their sessions.
+    BlockUser { user: String },
+    UnblockUser { user: String },
+    Pool,
+}
+
+#[derive(Serialize)]
+struct SessionSummary {
+    user: String,
+    peer: SocketAddr,
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    /// Unix seconds.
+    connected_at: u64,
+    connected_secs: u64,
+    idle_secs: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Serialize)]
+struct PoolAddress {
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    user: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PoolState {
+    ipv4_cidr: String,
+    ipv6_prefix: String,
+    capacity: u32,
+    in_use: usize,
+    held: usize,
+    allocated: Vec<PoolAddress>,
+    leases: Vec<Lease>,
+}
+
+/// Local control interface on a Unix socket speaking line-delimited JSON. Every reply is
+/// `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+pub struct AdminServer {
+    pub proxy_server: Arc<Mutex<ProxyServerInternal>>,
+    pub router: Arc<Mutex<PacketRouter>>,
+    pub users: Arc<Mutex<UserStore>>,
+    pub leases: Arc<Mutex<LeaseStore>>,
+}
+
+impl AdminServer {
+    /// Binds `path`, replacing a socket left behind by a crash, and serves it until shutdown.
+    /// Every connection gets its own thread, up to `MAX_CONNECTIONS` of them, so a client
+    /// sitting on its connection does not keep the others waiting.
+    pub fn start(self, path: &Path) -> io::Result<JoinHandle<()>> {
+        let listener = bind_private(path)?;
+        listener.set_nonblocking(true)?;
+        let path: PathBuf = path.to_path_buf();
+        let admin = Arc::new(self);
+        let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        Ok(spawn(move || {
+            while !shutdown::stop_requested() {
+                if !wait_readable(listener.as_raw_fd(), 200) {
+                    continue;
+                }
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if !connections.try_acquire() {
+                            warn!("Too many admin connections, turning one away");
+                            continue;
+                        }
+                        let (admin, connections) = (admin.clone(), connections.clone());
+                        spawn(move || {
+                            if let Err(err) = admin.serve(stream) {
+                                warn!("Admin connection failed: {}", err);
+                            }
+                            connections.release();
+                        });
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => error!("Failed to accept admin connection: {}", err),
+                }
+            }
+            let _ = fs::remove_file(&path);
+        }))
+    }
+
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        let mut reader = BufReader::new(DeadlineReader::new(stream.try_clone()?, Instant::now()));
+        let mut writer = stream;
+        let mut line = String::new();
+        let mut last_request = Instant::now();
+        while !shutdown::stop_requested() {
+            // wait for requests in short rounds to notice shutdown between them
+            if reader.buffer().is_empty() && !wait_readable(writer.as_raw_fd(), 200) {
+                if last_request.elapsed() >= CLIENT_TIMEOUT {
+                    break;
+                }
+                continue;
+            }
+            line.clear();
+            reader.get_mut().set_deadline(Instant::now() + REQUEST_TIMEOUT);
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            last_request = Instant::now();
+            if line.trim().is_empty() {
+                continue;
+            }
+            writeln!(writer, "{}", self.reply(&line))?;
+        }
+        Ok(())
+    }
+
+    fn reply(&self, line: &str) -> Value {
+        match serde_json::from_str::<AdminRequest>(line) {
+            Ok(request) => match self.handle(request) {
+                Ok(result) => json!({ "ok": true, "result": result }),
+                Err(err) => json!({ "ok": false, "error": err }),
+            },
+            Err(err) => json!({ "ok": false, "error": format!("bad request: {}", err) }),
+        }
+    }
+
+    fn handle(&self, request: AdminRequest) -> Result<Value, String> {
+        match request {
+            AdminRequest::ListSessions => to_value(self.sessions()),
+            AdminRequest::Kick { user, ipv4 } => {
+                if user.is_none() && ipv4.is_none() {
+                    return Err("kick needs a user or an ipv4 address".to_string());
+                }
+                let kicked = self.proxy_server.lock().unwrap().kick(
+                    |info| {
+                        user.as_ref().is_none_or(|user| info.user == *user)
+                            && ipv4.is_none_or(|ipv4| info.ipv4addr == ipv4)
+                    },
+                    "kicked by administrator",
+                );
+                Ok(json!({ "kicked": kicked }))
+            }
+            AdminRequest::BlockUser { user } => {
+                self.users
+                    .lock()
+                    .unwrap()
+                    .set_enabled(&user, false)
+                    .map_err(|err| err.to_string())?;
+                let kicked = self
+                    .proxy_server
+                    .lock()
+                    .unwrap()
+                    .kick(|info| info.user == user, "user blocked by administrator");
+                Ok(json!({ "kicked": kicked }))
+            }
+            AdminRequest::UnblockUser { user } => {
+                self.users
+                    .lock()
+                    .unwrap()
+                    .set_enabled(&user, true)
+                    .map_err(|err| err.to_string())?;
+                Ok(json!({}))
+            }
+            AdminRequest::Pool => to_value(self.pool_state()),
+        }
+    }
+
+    fn sessions(&self) -> Vec<SessionSummary> {
+        let now = SystemTime::now();
+        let proxy_server = self.proxy_server.lock().unwrap();
+        let streams = proxy_server.streams_in_handle.lock().unwrap();
+        let mut sessions: Vec<SessionSummary> = streams
+            .values()
+            .map(|entry| {
+                let info = entry.0.1.lock().unwrap();
+                let connected_at = now
+                    .checked_sub(info.connected_at.elapsed())
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |since| since.as_secs());
+                SessionSummary {
+                    user: info.user.clone(),
+                    peer: info.peer,
+                    ipv4: info.ipv4addr,
+                    ipv6: info.ipv6addr,
+                    connected_at,
+                    connected_secs: info.connected_at.elapsed().as_secs(),
+                    idle_secs: info.last_seen.elapsed().as_secs(),
+                    bytes_in: info.bytes_in,
+                    bytes_out: info.bytes_out,
+                }
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.ipv4);
+        sessions
+    }
+
+    fn pool_state(&self) -> PoolState {
+        let sessions = self.sessions();
+        let router = self.router.lock().unwrap();
+        let pool = router.pool();
+        let allocated = pool
+            .allocated()
+            .into_iter()
+            .map(|ipv4| PoolAddress {
+                ipv4,
+                ipv6: pool.ipv6_for(ipv4),
+                user: sessions.iter().find(|session| session.ipv4 == ipv4).map(|session| session.user.clone()),
+            })
+            .collect();
+        let mut leases: Vec<Lease> = self.leases.lock().unwrap().leases().cloned().collect();
+        leases.sort_by_key(|lease| lease.ipv4);
+        PoolState {
+            ipv4_cidr: pool.ipv4_cidr().to_string(),
+            ipv6_prefix: pool.ipv6_prefix().to_string(),
+            capacity: pool.capacity(),
+            in_use: pool.in_use(),
+            held: pool.held(),
+            allocated,
+            leases,
+        }
+    }
+}
+
+/// Binds the socket inside a directory only we can enter and moves it to `path` once it is
+/// 0600, as anyone who can connect can kick sessions and block users.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "admin socket path has no file name"))?;
+    let staging = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    listener
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, String> {
+    serde_json::to_value(value).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::operational::packet_router::PacketRouterCreateInfo;
+    use crate::operational::tun_interface::TunInterface;
+    use crate::util::testing::{example_config, TempPath};
+    use std::collections::HashMap;
+    use tfserver::util::thread_pool::ThreadPool;
+
+    /// The server along with the directory holding its users and leases files.
+    fn admin(name: &str) -> (AdminServer, TempPath) {
+        let dir = TempPath::dir(&format!("admin-{}", name));
+        let config = Arc::new(example_config());
+        let metrics = Arc::new(Metrics::new(false));
+        let router = Arc::new(Mutex::new(PacketRouter::new(PacketRouterCreateInfo {
+            ipv4_cidr: "10.0.8.0/24".parse().unwrap(),
+            ipv6_prefix: "fd00:8::/64".parse().unwrap(),
+            tun_interface: Arc::new(Mutex::new(TunInterface::closed())),
+            resyncer_timeout: Duration::from_millis(100),
+            max_packets_attempts_amount: 1,
+            client_to_client: false,
+            metrics: metrics.clone(),
+        })));
+        let leases = LeaseStore::open(&dir.join("leases.toml"), Duration::from_secs(3600)).unwrap();
+        let leases = Arc::new(Mutex::new(leases));
+        let proxy_server = ProxyServerInternal::new(
+            config,
+            router.clone(),
+            ThreadPool::new(1),
+            Arc::new(Mutex::new(HashMap::new())),
+            leases.clone(),
+            metrics,
+        );
+        let mut users = UserStore::open(&dir.join("users.toml")).unwrap();
+        users.add_user("alice", "secret").unwrap();
+        let admin = AdminServer {
+            proxy_server: Arc::new(Mutex::new(proxy_server)),
+            router,
+            users: Arc::new(Mutex::new(users)),
+            leases,
+        };
+        (admin, dir)
+    }
+
+    fn request(admin: &AdminServer, line: &str) -> Value {
+        let reply = admin.reply(line);
+        assert_eq!(reply["ok"], true, "{} failed: {}", line, reply);
+        reply["result"].clone()
+    }
+
+    fn error(admin: &AdminServer, line: &str) -> String {
+        let reply = admin.reply(line);
+        assert_eq!(reply["ok"], false, "{} should fail", line);
+        reply["error"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn kick_needs_a_target() {
+        let (admin, _dir) = admin("kick");
+        assert!(error(&admin, r#"{"command": "kick"}"#).contains("needs a user"));
+        assert_eq!(request(&admin, r#"{"command": "kick", "user": "alice"}"#), json!({ "kicked": 0 }));
+        assert_eq!(request(&admin, r#"{"command": "kick", "ipv4": "10.0.8.2"}"#), json!({ "kicked": 0 }));
+    }
+
+    #[test]
+    fn blocking_reaches_the_users_file() {
+        let (admin, _dir) = admin("block");
+        request(&admin, r#"{"command": "block_user", "user": "alice"}"#);
+        assert!(admin.users.lock().unwrap().find_active("alice").is_none());
+        request(&admin, r#"{"command": "unblock_user", "user": "alice"}"#);
+        assert!(admin.users.lock().unwrap().find_active("alice").is_some());
+        assert!(error(&admin, r#"{"command": "block_user", "user": "bob"}"#).contains("no such user"));
+    }
+
+    #[test]
+    fn reports_sessions_and_pool() {
+        let (admin, _dir) = admin("pool");
+        assert_eq!(request(&admin, r#"{"command": "list_sessions"}"#), json!([]));
+        let pool = request(&admin, r#"{"command": "pool"}"#);
+        assert_eq!(pool["ipv4_cidr"], "10.0.8.0/24");
+        assert_eq!(pool["in_use"], 0);
+        assert_eq!(pool["allocated"], json!([]));
+    }
+
+    #[test]
+    fn socket_is_bound_owner_only() {
+        let dir = TempPath::dir("admin-socket");
+        let path = dir.join("admin.sock");
+        fs::write(&path, "left behind by a crash").unwrap();
+        let _listener = bind_private(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        UnixStream::connect(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let (admin, _dir) = admin("malformed");
+        assert!(error(&admin, "not json").starts_with("bad request"));
+        assert!(error(&admin, r#"{"command": "reboot"}"#).starts_with("bad request"));
+        assert!(error(&admin, r#"{"command": "block_user"}"#).starts_with("bad request"));
+    }
+}
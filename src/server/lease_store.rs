@@ -0,0 +1,152 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Dynamic address last given to a user, kept for them until `expires` (unix seconds).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub user: String,
+    pub ipv4: Ipv4Addr,
+    pub ipv6: Ipv6Addr,
+    pub expires: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LeaseFile {
+    #[serde(default)]
+    leases: Vec<Lease>,
+}
+
+/// Leases persisted to a TOML file, so a user keeps their tunnel address across
+/// reconnects and server restarts as long as they come back before the lease expires.
+pub struct LeaseStore {
+    path: PathBuf,
+    expiry: Duration,
+    leases: HashMap<String, Lease>,
+}
+
+impl LeaseStore {
+    pub fn open(path: &Path, expiry: Duration) -> Result<LeaseStore, Box<dyn std::error::Error>> {
+        let mut store = Self {
+            path: path.to_path_buf(),
+            expiry,
+            leases: HashMap::new(),
+        };
+        if path.exists() {
+            let file: LeaseFile = toml::from_str(&fs::read_to_string(path)?)?;
+            store.leases = file
+                .leases
+                .into_iter()
+                .map(|lease| (lease.user.clone(), lease))
+                .collect();
+        }
+        store.expire();
+        Ok(store)
+    }
+
+    pub fn lease_for(&self, user: &str) -> Option<&Lease> {
+        self.leases.get(user)
+    }
+
+    pub fn leases(&self) -> impl Iterator<Item = &Lease> + '_ {
+        self.leases.values()
+    }
+
+    /// Addresses nobody but their lease holder may get.
+    pub fn leased_addresses(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.leases.values().map(|lease| lease.ipv4)
+    }
+
+    /// Records that `user` holds these addresses, starting their lease or extending it from now.
+    /// Called when a session starts and again when it ends.
+    pub fn renew(&mut self, user: &str, ipv4: Ipv4Addr, ipv6: Ipv6Addr) {
+        self.leases.insert(
+            user.to_string(),
+            Lease {
+                user: user.to_string(),
+                ipv4,
+                ipv6,
+                expires: now_secs() + self.expiry.as_secs(),
+            },
+        );
+        self.save_or_log();
+    }
+
+    /// Drops expired leases, returning whether any were removed.
+    pub fn expire(&mut self) -> bool {
+        let now = now_secs();
+        let before = self.leases.len();
+        self.leases.retain(|_, lease| lease.expires > now);
+        if self.leases.len() == before {
+            return false;
+        }
+        self.save_or_log();
+        true
+    }
+
+    fn save_or_log(&self) {
+        if let Err(err) = self.save() {
+            error!("Failed to save leases to {}: {}", self.path.display(), err);
+        }
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut leases: Vec<Lease> = self.leases.values().cloned().collect();
+        leases.sort_by(|a, b| a.user.cmp(&b.user));
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, toml::to_string(&LeaseFile { leases })?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::TempPath;
+
+    fn leases_path(name: &str) -> TempPath {
+        TempPath::file(&format!("leases-{}.toml", name))
+    }
+
+    #[test]
+    fn leases_survive_a_restart() {
+        let path = leases_path("restart");
+        let mut store = LeaseStore::open(&path, Duration::from_secs(3600)).unwrap();
+        store.renew("alice", Ipv4Addr::new(10, 0, 8, 2), "fd00:8::a00:802".parse().unwrap());
+        store.renew("bob", Ipv4Addr::new(10, 0, 8, 3), "fd00:8::a00:803".parse().unwrap());
+        store.renew("alice", Ipv4Addr::new(10, 0, 8, 4), "fd00:8::a00:804".parse().unwrap());
+
+        let reopened = LeaseStore::open(&path, Duration::from_secs(3600)).unwrap();
+        assert_eq!(reopened.lease_for("alice").map(|lease| lease.ipv4), Some(Ipv4Addr::new(10, 0, 8, 4)));
+        assert_eq!(reopened.lease_for("bob").map(|lease| lease.ipv4), Some(Ipv4Addr::new(10, 0, 8, 3)));
+        assert_eq!(reopened.leased_addresses().count(), 2);
+    }
+
+    #[test]
+    fn expired_leases_are_dropped_and_saved() {
+        let path = leases_path("expiry");
+        let mut store = LeaseStore::open(&path, Duration::ZERO).unwrap();
+        store.renew("alice", Ipv4Addr::new(10, 0, 8, 2), "fd00:8::a00:802".parse().unwrap());
+        assert!(store.expire());
+        assert!(store.lease_for("alice").is_none());
+        assert!(!store.expire());
+        assert_eq!(LeaseStore::open(&path, Duration::from_secs(3600)).unwrap().leases().count(), 0);
+
+        // a lease that ran out while the server was down is gone on start
+        let mut store = LeaseStore::open(&path, Duration::ZERO).unwrap();
+        store.renew("bob", Ipv4Addr::new(10, 0, 8, 3), "fd00:8::a00:803".parse().unwrap());
+        assert!(LeaseStore::open(&path, Duration::from_secs(3600)).unwrap().lease_for("bob").is_none());
+    }
+}
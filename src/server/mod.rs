@@ -1,2 +1,5 @@
 pub mod receiver_info;
 pub mod proxy_internal_server;
+pub mod lease_store;
+pub mod user_store;
+pub mod admin;
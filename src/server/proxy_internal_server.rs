@@ -1,38 +1,183 @@
+use log::{info, warn};
+use crate::error::ActorError;
+use crate::metrics::Metrics;
 use crate::operational::packet_router::{AddressTuple, PacketRouter};
 use crate::vpn_config::VpnConfig;
 use std::collections::HashMap;
-use std::net::TcpStream;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
 use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tfserver::tungstenite;
+use tfserver::tungstenite::protocol::frame::coding::CloseCode;
+use tfserver::tungstenite::protocol::CloseFrame;
 use tfserver::tungstenite::{Bytes, Message, WebSocket};
 use tfserver::util::thread_pool::ThreadPool;
 use crate::front_interface::jni_receiver::JniReceiver;
 use crate::server::receiver_info::ReceiverInfo;
+use crate::handlers::register_handler::AuthorizedClient;
+use crate::server::lease_store::LeaseStore;
+
+/// How long [`ProxyServerInternal::stop`] waits for the pump and its jobs before closing sessions anyway.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+type SessionStreams =
+    HashMap<AddressTuple, ((Arc<Mutex<WebSocket<TcpStream>>>, Arc<Mutex<ReceiverInfo>>), Arc<Mutex<AtomicBool>>)>;
 
 pub struct ProxyServerInternal {
     running: Arc<Mutex<AtomicBool>>,
-    workgroup: Arc<Mutex<ThreadPool>>,
+    /// Set by the pump once it saw `running` go false and returned.
+    stopped: Arc<Mutex<AtomicBool>>,
+    /// Taken and dropped by [`ProxyServerInternal::stop`] once its jobs returned.
+    workgroup: Arc<Mutex<Option<ThreadPool>>>,
     router: Arc<Mutex<PacketRouter>>,
-    pub(crate) streams_in_handle:
-        Arc<Mutex<HashMap<AddressTuple, ((Arc<Mutex<WebSocket<TcpStream>>>, Arc<Mutex<ReceiverInfo>>), Arc<Mutex<AtomicBool>>)>>>,
-    config: Arc<VpnConfig>,
+    pub(crate) streams_in_handle: Arc<Mutex<SessionStreams>>,
+    /// Shared with `RegisterHandler`, entries are dropped together with their session.
+    authorized_clients: Arc<Mutex<HashMap<SocketAddr, AuthorizedClient>>>,
+    leases: Arc<Mutex<LeaseStore>>,
+    /// Swapped by [`ProxyServerInternal::set_config`] on reload, the pump picks it up on its next round.
+    config: Arc<Mutex<Arc<VpnConfig>>>,
+    metrics: Arc<Metrics>,
 }
 
 impl ProxyServerInternal {
     pub fn new(
         config: Arc<VpnConfig>,
         packet_router: Arc<Mutex<PacketRouter>>,
-        thread_pool: Arc<Mutex<ThreadPool>>,
+        thread_pool: ThreadPool,
+        authorized_clients: Arc<Mutex<HashMap<SocketAddr, AuthorizedClient>>>,
+        leases: Arc<Mutex<LeaseStore>>,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             running: Arc::new(Mutex::new(AtomicBool::new(true))),
+            stopped: Arc::new(Mutex::new(AtomicBool::new(false))),
             streams_in_handle: Arc::new(Mutex::new(HashMap::new())),
             router: packet_router,
-            workgroup: thread_pool,
-            config,
+            workgroup: Arc::new(Mutex::new(Some(thread_pool))),
+            authorized_clients,
+            leases,
+            config: Arc::new(Mutex::new(config)),
+            metrics,
+        }
+    }
+
+    pub fn set_config(&self, config: Arc<VpnConfig>) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    /// Drops the session holding `addr` if it belongs to `user`, so a client reconnecting
+    /// before its old connection timed out can take its addresses back.
+    pub fn evict_stale_session(&self, user: &str, addr: Ipv4Addr) -> bool {
+        let key = AddressTuple::new(addr);
+        let entry = self.streams_in_handle.lock().unwrap().get(&key).map(|entry| entry.0.1.clone());
+        let owned = match entry {
+            Some(info) => info.lock().unwrap().user == user,
+            None => false,
+        };
+        if !owned {
+            return false;
+        }
+        Self::teardown(
+            &self.streams_in_handle,
+            &self.router,
+            &self.authorized_clients,
+            &self.leases,
+            &self.metrics,
+            &key,
+            CloseCode::Normal,
+            "replaced by a new connection of the same user",
+        );
+        true
+    }
+
+    /// Closes every session `select` picks, returning how many were closed.
+    pub fn kick(&self, select: impl Fn(&ReceiverInfo) -> bool, reason: &str) -> usize {
+        let keys: Vec<AddressTuple> = self
+            .streams_in_handle
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| select(&entry.0.1.lock().unwrap()))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            Self::teardown(
+                &self.streams_in_handle,
+                &self.router,
+                &self.authorized_clients,
+                &self.leases,
+                &self.metrics,
+                key,
+                CloseCode::Policy,
+                reason,
+            );
+        }
+        keys.len()
+    }
+
+    /// Removes a session everywhere it is referenced and logs what it did while it lasted.
+    fn teardown(
+        streams: &Arc<Mutex<SessionStreams>>,
+        router: &Arc<Mutex<PacketRouter>>,
+        authorized_clients: &Arc<Mutex<HashMap<SocketAddr, AuthorizedClient>>>,
+        leases: &Arc<Mutex<LeaseStore>>,
+        metrics: &Metrics,
+        key: &AddressTuple,
+        close_code: CloseCode,
+        reason: &str,
+    ) {
+        let entry = streams.lock().unwrap().remove(key);
+        let ((stream, info), _) = match entry {
+            Some(entry) => entry,
+            None => return,
+        };
+        let info = info.lock().unwrap();
+        router.lock().unwrap().deregister(info.ipv4addr);
+        authorized_clients.lock().unwrap().remove(&info.peer);
+        // the lease runs from the end of the session
+        leases.lock().unwrap().renew(&info.user, info.ipv4addr, info.ipv6addr);
+        metrics.session_closed(&info.user, info.ipv4addr);
+        let frame = CloseFrame {
+            code: close_code,
+            reason: reason.into(),
+        };
+        let _ = stream.lock().unwrap().close(Some(frame));
+        let _ = stream.lock().unwrap().flush();
+
+        let mut receiver = info.receiver_handle.lock().unwrap();
+        let stats = receiver
+            .as_any_mut()
+            .downcast_mut::<JniReceiver>()
+            .map(|receiver| receiver.frame_stats().clone())
+            .unwrap_or_default();
+        info!(
+            "Session closed: user {} from {} ({}, {}) after {}s, {} bytes in, {} bytes out, {} frames dropped as tampered, {} as replayed, {} rekeys: {}",
+            info.user,
+            info.peer,
+            info.ipv4addr,
+            info.ipv6addr,
+            info.connected_at.elapsed().as_secs(),
+            info.bytes_in,
+            info.bytes_out,
+            stats.tampered_dropped,
+            stats.replayed_dropped,
+            stats.rekeys,
+            reason,
+        );
+    }
+
+    /// Decides what an error of one session means for it: the reason to close it, or `None`
+    /// when only the frame at hand is lost.
+    fn session_error(metrics: &Metrics, user: &str, err: ActorError) -> Option<String> {
+        if err.closes_session() {
+            metrics.session_errors.inc();
+            Some(format!("session error: {}", err))
+        } else {
+            warn!("Dropped frame from {}: {}", user, err);
+            None
         }
     }
 
@@ -43,50 +188,188 @@ impl ProxyServerInternal {
         }
     }
 
+    fn is_would_block(err: &tungstenite::Error) -> bool {
+        match err {
+            tungstenite::Error::Io(err) => err.kind() == ErrorKind::WouldBlock,
+            _ => false,
+        }
+    }
+
     pub fn start(mut self_ref: Arc<Mutex<Self>>) {
         let mut running_ref = self_ref.lock().unwrap().running.clone();
-        let config_ref = self_ref.lock().unwrap().config.clone();
+        let stopped_ref = self_ref.lock().unwrap().stopped.clone();
+        let config_cell = self_ref.lock().unwrap().config.clone();
         let router_ref = self_ref.lock().unwrap().router.clone();
         let streams_ref = self_ref.lock().unwrap().streams_in_handle.clone();
+        let authorized_ref = self_ref.lock().unwrap().authorized_clients.clone();
+        let leases_ref = self_ref.lock().unwrap().leases.clone();
+        let metrics_ref = self_ref.lock().unwrap().metrics.clone();
         let workgroup_ref = self_ref.lock().unwrap().workgroup.clone();
         let workgroup2 = self_ref.lock().unwrap().workgroup.clone();
-        workgroup_ref.lock().unwrap().execute(move || loop {
+        let mut workgroup = workgroup_ref.lock().unwrap();
+        let workgroup = match workgroup.as_mut() {
+            Some(workgroup) => workgroup,
+            None => return,
+        };
+        workgroup.execute(move || loop {
             if !running_ref.lock().unwrap().load(Relaxed) {
+                stopped_ref.lock().unwrap().store(true, Relaxed);
                 break;
             }
+            let config_ref = config_cell.lock().unwrap().clone();
+            let idle_timeout = Duration::from_secs(config_ref.server.idle_timeout_secs);
+            let mut disconnected: Vec<(AddressTuple, String)> = Vec::new();
             streams_ref.lock().unwrap().iter().for_each(|element| {
                 if !element.1 .1.lock().unwrap().load(Relaxed) {
                     let in_handle_ref = element.1 .1.clone();
                     let stream_ref = element.1 .0.0.clone();
                     let info_ref = element.1 .0.1.clone();
+                    if let Some(reason) = info_ref.lock().unwrap().disconnect_reason.clone() {
+                        disconnected.push((element.0.clone(), reason));
+                        return;
+                    }
                     in_handle_ref.lock().unwrap().store(true, Relaxed);
                     let value = config_ref.clone();
-                    workgroup2.lock().unwrap().execute(move || {
+                    let metrics = metrics_ref.clone();
+                    let mut workgroup = workgroup2.lock().unwrap();
+                    let workgroup = match workgroup.as_mut() {
+                        Some(workgroup) => workgroup,
+                        None => return,
+                    };
+                    workgroup.execute(move || {
                         let mut receiver_info_lock = info_ref.lock().unwrap();
-                        let mut data = stream_ref.lock().unwrap().read();
-                        let mut receiver_ref =
-                            receiver_info_lock.receiver_handle.lock().unwrap();
-                        let receiver = receiver_ref
-                            .as_any_mut()
-                            .downcast_mut::<JniReceiver>()
-                            .unwrap();
-                        if data.is_ok() {
-                            let mut data = Self::bytes_into_vec(data.unwrap().into_data());
-                            if !data.is_empty(){
-                                receiver.write_data(data.as_mut_slice());
-                            }
+                        let data = stream_ref.lock().unwrap().read();
+                        let mut disconnect_reason = None;
+                        let mut received = Vec::new();
+                        match data {
+                            Ok(Message::Binary(data)) => received = Self::bytes_into_vec(data),
+                            Ok(Message::Close(_)) => disconnect_reason = Some("closed by client".to_string()),
+                            Ok(_) => {}
+                            Err(err) if Self::is_would_block(&err) => {}
+                            Err(err) => disconnect_reason = Some(format!("read failed: {}", err)),
                         }
-                        sleep(Duration::from_millis(value.resyncer_timeout_ms as u64));
-                        let packets = receiver.get_data();
-                        if !packets.is_empty(){
-                            stream_ref.lock().unwrap().send(Message::Binary(Bytes::from(packets))).unwrap();
+                        if !received.is_empty() {
+                            receiver_info_lock.last_seen = Instant::now();
+                            receiver_info_lock.bytes_in += received.len() as u64;
+                            metrics.bytes_received.add(received.len() as u64);
+                            metrics.frames_received.inc();
+                            metrics.session_traffic(&receiver_info_lock.user, receiver_info_lock.ipv4addr, received.len() as u64, 0);
+                        } else if disconnect_reason.is_none() && receiver_info_lock.last_seen.elapsed() >= idle_timeout {
+                            disconnect_reason = Some(format!("idle for {}s", idle_timeout.as_secs()));
                         }
+                        if disconnect_reason.is_none() {
+                            let handle = receiver_info_lock.receiver_handle.clone();
+                            let mut receiver_ref = handle.lock().unwrap();
+                            let receiver = receiver_ref
+                                .as_any_mut()
+                                .downcast_mut::<JniReceiver>()
+                                .unwrap();
+                            if !received.is_empty() {
+                                if let Err(err) = receiver.write_data(received.as_mut_slice()) {
+                                    disconnect_reason = Self::session_error(&metrics, &receiver_info_lock.user, err);
+                                }
+                            }
+                            sleep(Duration::from_millis(value.resyncer_timeout_ms as u64));
+                            let packets = match receiver.get_data() {
+                                Ok(packets) => packets,
+                                Err(err) => {
+                                    disconnect_reason = disconnect_reason.or(Self::session_error(&metrics, &receiver_info_lock.user, err));
+                                    Vec::new()
+                                }
+                            };
+                            if !packets.is_empty() && disconnect_reason.is_none() {
+                                receiver_info_lock.bytes_out += packets.len() as u64;
+                                metrics.bytes_sent.add(packets.len() as u64);
+                                metrics.frames_sent.inc();
+                                metrics.session_traffic(&receiver_info_lock.user, receiver_info_lock.ipv4addr, 0, packets.len() as u64);
+                                let sent = stream_ref.lock().unwrap().send(Message::Binary(Bytes::from(packets)));
+                                match sent {
+                                    Ok(()) => {}
+                                    // queued in the write buffer, flushed by a later send
+                                    Err(err) if Self::is_would_block(&err) => {}
+                                    Err(err) => disconnect_reason = Some(format!("send failed: {}", err)),
+                                }
+                            }
+                        }
+                        receiver_info_lock.disconnect_reason = disconnect_reason;
                         in_handle_ref.lock().unwrap().store(false, Relaxed);
                     });
                 }
             });
-            router_ref.lock().unwrap().write_packets();
+            for (key, reason) in disconnected {
+                Self::teardown(
+                    &streams_ref,
+                    &router_ref,
+                    &authorized_ref,
+                    &leases_ref,
+                    &metrics_ref,
+                    &key,
+                    CloseCode::Normal,
+                    &reason,
+                );
+            }
+            if let Err(err) = router_ref.lock().unwrap().write_packets() {
+                warn!("Failed to write to the TUN device: {}", err);
+            }
             router_ref.lock().unwrap().receive_packets();
         });
     }
+
+    /// Stops the pump, lets the jobs it handed out finish and closes every session with a
+    /// Close frame, releasing its addresses and renewing its lease on the way. The worker
+    /// pool is dropped last, unless a job is still stuck when the timeout runs out.
+    pub fn stop(self_ref: &Arc<Mutex<Self>>) {
+        // the server itself stays unlocked while waiting, the admin API and handlers use it too
+        let (running, stopped, workgroup, streams, router, authorized_clients, leases, metrics) = {
+            let this = self_ref.lock().unwrap();
+            (
+                this.running.clone(),
+                this.stopped.clone(),
+                this.workgroup.clone(),
+                this.streams_in_handle.clone(),
+                this.router.clone(),
+                this.authorized_clients.clone(),
+                this.leases.clone(),
+                this.metrics.clone(),
+            )
+        };
+        running.lock().unwrap().store(false, Relaxed);
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while !stopped.lock().unwrap().load(Relaxed) && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+        let busy = || {
+            streams
+                .lock()
+                .unwrap()
+                .values()
+                .any(|entry| entry.1.lock().unwrap().load(Relaxed))
+        };
+        while busy() && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+        let finished = stopped.lock().unwrap().load(Relaxed) && !busy();
+        let keys: Vec<AddressTuple> = streams.lock().unwrap().keys().cloned().collect();
+        let sessions = keys.len();
+        for key in keys {
+            Self::teardown(
+                &streams,
+                &router,
+                &authorized_clients,
+                &leases,
+                &metrics,
+                &key,
+                CloseCode::Away,
+                "server shutting down",
+            );
+        }
+        info!("Closed {} sessions", sessions);
+        if finished {
+            // nothing hands it jobs anymore, dropping it ends the workers
+            let pool = workgroup.lock().unwrap().take();
+            drop(pool);
+        } else {
+            warn!("Proxy workers still busy after {}s, leaving them behind", STOP_TIMEOUT.as_secs());
+        }
+    }
 }
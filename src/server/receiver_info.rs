@@ -1,12 +1,18 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
-use tfserver::util::data_cipher::DataCipher;
+use std::time::Instant;
 use crate::operational::packet_router::PacketReceiver;
 
 pub struct ReceiverInfo{
-    pub cipher: DataCipher,
     pub ipv4addr: Ipv4Addr,
     pub ipv6addr: Ipv6Addr,
-    pub iv: String,
+    pub user: String,
     pub receiver_handle: Arc<Mutex<dyn PacketReceiver>>,
+    pub peer: SocketAddr,
+    pub connected_at: Instant,
+    pub last_seen: Instant,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Set by the worker that noticed the connection is gone, the session is torn down on the next pass.
+    pub disconnect_reason: Option<String>,
 }
\ No newline at end of file
@@ -0,0 +1,294 @@
+use log::warn;
+use crate::operational::address_pool::AddressPool;
+use crate::util::handshake::{derive_user_key, generate_salt};
+use cfg_if::cfg_if;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEntry {
+    pub name: String,
+    pub salt: String,
+    /// `derive_user_key(password, salt)`, the password itself is never stored.
+    pub key: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Address always assigned to this user and never to anyone else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_ipv4: Option<Ipv4Addr>,
+    /// Overrides the IPv6 address otherwise derived from `static_ipv4`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_ipv6: Option<Ipv6Addr>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct UserFile {
+    #[serde(default)]
+    users: Vec<UserEntry>,
+}
+
+/// Credentials backed by a TOML file that is re-read whenever it changes on disk,
+/// so users can be added, disabled or removed while the server keeps running.
+pub struct UserStore {
+    path: PathBuf,
+    users: HashMap<String, UserEntry>,
+    modified: Option<SystemTime>,
+}
+
+impl UserStore {
+    pub fn open(path: &Path) -> Result<UserStore, Box<dyn std::error::Error>> {
+        let mut store = Self {
+            path: path.to_path_buf(),
+            users: HashMap::new(),
+            modified: None,
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Returns the user if it exists and is enabled.
+    pub fn find_active(&mut self, name: &str) -> Option<UserEntry> {
+        if let Err(err) = self.reload_if_changed() {
+            warn!("Failed to reload users from {}: {}", self.path.display(), err);
+        }
+        self.users.get(name).filter(|user| user.enabled).cloned()
+    }
+
+    /// Static IPv4 reservations of all users, enabled or not.
+    pub fn reservations(&mut self) -> Vec<Ipv4Addr> {
+        if let Err(err) = self.reload_if_changed() {
+            warn!("Failed to reload users from {}: {}", self.path.display(), err);
+        }
+        self.users.values().filter_map(|user| user.static_ipv4).collect()
+    }
+
+    pub fn add_user(&mut self, name: &str, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.reload()?;
+        let salt = generate_salt();
+        let key = derive_user_key(password, &salt)?;
+        let previous = self.users.get(name);
+        let static_ipv4 = previous.and_then(|user| user.static_ipv4);
+        let static_ipv6 = previous.and_then(|user| user.static_ipv6);
+        self.users.insert(
+            name.to_string(),
+            UserEntry {
+                name: name.to_string(),
+                salt,
+                key,
+                enabled: true,
+                static_ipv4,
+                static_ipv6,
+            },
+        );
+        self.save()
+    }
+
+    pub fn set_reservation(&mut self, name: &str, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Result<(), Box<dyn std::error::Error>> {
+        self.reload()?;
+        if let Some(ipv4) = ipv4 {
+            if let Some(owner) = self.users.values().find(|user| user.name != name && user.static_ipv4 == Some(ipv4)) {
+                return Err(format!("{} is already reserved for {}", ipv4, owner.name).into());
+            }
+        }
+        if let Some(ipv6) = ipv6 {
+            if let Some(owner) = self.users.values().find(|user| user.name != name && user.static_ipv6 == Some(ipv6)) {
+                return Err(format!("{} is already reserved for {}", ipv6, owner.name).into());
+            }
+        }
+        match self.users.get_mut(name) {
+            Some(user) => {
+                user.static_ipv4 = ipv4;
+                user.static_ipv6 = ipv6;
+            }
+            None => return Err(format!("no such user: {}", name).into()),
+        }
+        self.save()
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.reload()?;
+        match self.users.get_mut(name) {
+            Some(user) => user.enabled = enabled,
+            None => return Err(format!("no such user: {}", name).into()),
+        }
+        self.save()
+    }
+
+    pub fn remove_user(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.reload()?;
+        if self.users.remove(name).is_none() {
+            return Err(format!("no such user: {}", name).into());
+        }
+        self.save()
+    }
+
+    fn reload_if_changed(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let modified = fs::metadata(&self.path).and_then(|meta| meta.modified()).ok();
+        if modified != self.modified {
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.path.exists() {
+            self.users.clear();
+            self.modified = None;
+            return Ok(());
+        }
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        let file: UserFile = toml::from_str(&fs::read_to_string(&self.path)?)?;
+        self.users = file
+            .users
+            .into_iter()
+            .map(|user| (user.name.clone(), user))
+            .collect();
+        self.modified = modified;
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut users: Vec<UserEntry> = self.users.values().cloned().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        // write next to the target and rename so a running server never reads a half-written file
+        let tmp_path = self.path.with_extension("tmp");
+        // the mode only applies to a file being created, so not to one left by a crashed save
+        let _ = fs::remove_file(&tmp_path);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // the stored keys are as good as the passwords, never readable by other local users
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&tmp_path)?.write_all(toml::to_string(&UserFile { users })?.as_bytes())?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.modified = fs::metadata(&self.path)?.modified().ok();
+        Ok(())
+    }
+}
+
+/// Handles `user add <name>` with the password read by [`read_password`], `user enable|disable|remove <name>`,
+/// `user reserve <name> <ipv4> [<ipv6>]` and `user unreserve <name>`. Reservations are checked
+/// against `pool`, the one the server hands addresses out of.
+pub fn run_user_command(path: &Path, pool: &AddressPool, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = UserStore::open(path)?;
+    match args {
+        [cmd, name] if cmd == "add" => store.add_user(name, &read_password()?),
+        [cmd, name] if cmd == "enable" => store.set_enabled(name, true),
+        [cmd, name] if cmd == "disable" => store.set_enabled(name, false),
+        [cmd, name] if cmd == "remove" => store.remove_user(name),
+        [cmd, name, ipv4] if cmd == "reserve" => reserve(&mut store, pool, name, ipv4.parse()?, None),
+        [cmd, name, ipv4, ipv6] if cmd == "reserve" => reserve(&mut store, pool, name, ipv4.parse()?, Some(ipv6.parse()?)),
+        [cmd, name] if cmd == "unreserve" => store.set_reservation(name, None, None),
+        _ => Err("usage: user add <name> | user enable|disable|remove|unreserve <name> | user reserve <name> <ipv4> [<ipv6>]".into()),
+    }
+}
+
+fn reserve(
+    store: &mut UserStore,
+    pool: &AddressPool,
+    name: &str,
+    ipv4: Ipv4Addr,
+    ipv6: Option<Ipv6Addr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    pool.check_reservation(ipv4, ipv6)?;
+    store.set_reservation(name, Some(ipv4), ipv6)
+}
+
+/// Reads the password for `user add` from stdin, keeping it out of argv and the shell history.
+/// A terminal is asked twice without echo, otherwise the first line is taken, so it can be piped in.
+fn read_password() -> Result<String, Box<dyn std::error::Error>> {
+    let password = if io::stdin().is_terminal() {
+        let password = prompt_hidden("Password: ")?;
+        if prompt_hidden("Repeat password: ")? != password {
+            return Err("passwords do not match".into());
+        }
+        password
+    } else {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if password.is_empty() {
+        return Err("empty password".into());
+    }
+    Ok(password)
+}
+
+fn prompt_hidden(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let stdin = io::stdin();
+    let mut line = String::new();
+    cfg_if! {
+        if #[cfg(unix)] {
+            use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+            let saved = tcgetattr(&stdin)?;
+            let mut hidden = saved.clone();
+            hidden.local_flags.remove(LocalFlags::ECHO);
+            tcsetattr(&stdin, SetArg::TCSANOW, &hidden)?;
+            let read = stdin.lock().read_line(&mut line);
+            tcsetattr(&stdin, SetArg::TCSANOW, &saved)?;
+            eprintln!();
+            read?;
+        } else {
+            stdin.lock().read_line(&mut line)?;
+        }
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::TempPath;
+
+    fn users_path(name: &str) -> TempPath {
+        TempPath::file(&format!("users-{}.toml", name))
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn reservations_are_checked_against_the_pool() {
+        let path = users_path("reserve");
+        let pool = AddressPool::new("10.0.8.1/24".parse().unwrap(), "fd00:8::1/64".parse().unwrap());
+        let mut store = UserStore::open(&path).unwrap();
+        store.add_user("alice", "secret").unwrap();
+        store.add_user("bob", "secret").unwrap();
+
+        assert!(run_user_command(&path, &pool, &args(&["reserve", "alice", "10.0.9.5"])).is_err());
+        assert!(run_user_command(&path, &pool, &args(&["reserve", "alice", "10.0.8.5", "fd00:8::a00:806"])).is_err());
+        run_user_command(&path, &pool, &args(&["reserve", "alice", "10.0.8.5", "fd00:8::5"])).unwrap();
+        assert!(run_user_command(&path, &pool, &args(&["reserve", "bob", "10.0.8.6", "fd00:8::5"])).is_err());
+
+        let alice = UserStore::open(&path).unwrap().find_active("alice").unwrap();
+        assert_eq!(alice.static_ipv4, Some(Ipv4Addr::new(10, 0, 8, 5)));
+        assert_eq!(alice.static_ipv6, Some("fd00:8::5".parse().unwrap()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn users_file_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = users_path("mode");
+        fs::write(path.with_extension("tmp"), "left behind by a crash").unwrap();
+        UserStore::open(&path).unwrap().add_user("alice", "secret").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
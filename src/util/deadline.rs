@@ -0,0 +1,83 @@
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Streams whose blocking reads can be given a timeout.
+pub trait SetReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl SetReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl SetReadTimeout for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Reads from a blocking stream until a deadline, failing with `TimedOut` after it. Unlike
+/// a plain read timeout it bounds the whole exchange, so a client sending a byte at a time
+/// cannot stretch it out.
+pub struct DeadlineReader<S> {
+    stream: S,
+    deadline: Instant,
+}
+
+impl<S> DeadlineReader<S> {
+    pub fn new(stream: S, deadline: Instant) -> Self {
+        Self { stream, deadline }
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+    }
+}
+
+impl<S: Read + SetReadTimeout> Read for DeadlineReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "request not complete in time"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{sleep, spawn};
+
+    #[test]
+    fn slow_clients_run_into_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let dripping = spawn(move || {
+            for _ in 0..20 {
+                if client.write_all(b"x").is_err() {
+                    break;
+                }
+                sleep(Duration::from_millis(50));
+            }
+        });
+
+        let started = Instant::now();
+        let mut reader = BufReader::new(DeadlineReader::new(server, started + Duration::from_millis(300)));
+        let mut line = String::new();
+        let err = reader.read_line(&mut line).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock), "{}", err);
+        assert!(started.elapsed() < Duration::from_millis(900));
+        drop(reader);
+        dripping.join().unwrap();
+    }
+}
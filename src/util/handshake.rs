@@ -0,0 +1,243 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand::Rng;
+use tfserver::openssl::error::ErrorStack;
+use tfserver::openssl::hash::MessageDigest;
+use tfserver::openssl::md::Md;
+use tfserver::openssl::memcmp;
+use tfserver::openssl::pkcs5::pbkdf2_hmac;
+use tfserver::openssl::pkey::{Id, PKey, Private};
+use tfserver::openssl::pkey_ctx::PkeyCtx;
+use tfserver::openssl::sign::{Signer, Verifier};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+pub const USER_KEY_ITERATIONS: usize = 100_000;
+const HANDSHAKE_LABEL: &[u8] = b"actor handshake v1";
+const REKEY_LABEL: &[u8] = b"actor rekey v1";
+
+pub fn generate_salt() -> String {
+    let salt: [u8; 16] = rand::rng().random();
+    general_purpose::STANDARD.encode(salt)
+}
+
+/// Salt answered for names that are not in the users file. It is keyed by a secret of this server
+/// and stable per name, so asking about the same name twice looks like asking about a real user.
+pub fn decoy_salt(secret: &[u8], username: &str) -> Result<String, ErrorStack> {
+    let mac = hmac_sha256(secret, username.as_bytes())?;
+    Ok(general_purpose::STANDARD.encode(&mac[..16]))
+}
+
+/// Stretches a user's password with their salt into the pre-shared key authenticating the handshake.
+pub fn derive_user_key(password: &str, salt_b64: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let salt = general_purpose::STANDARD.decode(salt_b64)?;
+    let mut key = [0u8; 32];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        &salt,
+        USER_KEY_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )?;
+    Ok(general_purpose::STANDARD.encode(key))
+}
+
+/// Key and IV protecting one direction of the tunnel.
+#[derive(Clone)]
+pub struct DirectionKeys {
+    pub key: String,
+    pub iv: String,
+}
+
+/// Traffic keys of one session, never reused across handshakes.
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub client_to_server: DirectionKeys,
+    pub server_to_client: DirectionKeys,
+}
+
+/// Everything both sides derive from the exchange: the traffic keys and
+/// the keys each side proves possession of the shared secret with.
+pub struct HandshakeSecrets {
+    pub client_confirm: Vec<u8>,
+    pub server_confirm: Vec<u8>,
+    pub keys: SessionKeys,
+}
+
+pub struct EphemeralKey {
+    private: PKey<Private>,
+    pub public_b64: String,
+}
+
+impl EphemeralKey {
+    pub fn generate() -> Result<EphemeralKey, ErrorStack> {
+        let private = PKey::generate_x25519()?;
+        let public_b64 = general_purpose::STANDARD.encode(private.raw_public_key()?);
+        Ok(Self { private, public_b64 })
+    }
+
+    pub fn diffie_hellman(&self, peer_public_b64: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let peer_raw = general_purpose::STANDARD.decode(peer_public_b64)?;
+        let peer = PKey::public_key_from_raw_bytes(&peer_raw, Id::X25519)?;
+        let mut deriver = tfserver::openssl::derive::Deriver::new(&self.private)?;
+        deriver.set_peer(&peer)?;
+        let shared = deriver.derive_to_vec()?;
+        // a low order peer point yields an all zero secret
+        if shared.iter().all(|&b| b == 0) {
+            return Err("degenerate x25519 shared secret".into());
+        }
+        Ok(shared)
+    }
+}
+
+/// Binds every value exchanged in the clear, so neither side can be fed a spliced handshake.
+pub fn transcript(username: &str, client_public_b64: &str, server_public_b64: &str, salt_b64: &str) -> Vec<u8> {
+    let mut res = Vec::new();
+    for field in [username, client_public_b64, server_public_b64, salt_b64] {
+        res.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        res.extend_from_slice(field.as_bytes());
+    }
+    res
+}
+
+pub fn derive_secrets(user_key_b64: &str, shared: &[u8], transcript: &[u8]) -> Result<HandshakeSecrets, Box<dyn std::error::Error>> {
+    let psk = general_purpose::STANDARD.decode(user_key_b64)?;
+    let mut info = HANDSHAKE_LABEL.to_vec();
+    info.extend_from_slice(transcript);
+    let okm = hkdf_sha256(&psk, shared, &info, 32 + 32 + 2 * (32 + 16))?;
+    let encode = |bytes: &[u8]| general_purpose::STANDARD.encode(bytes);
+    Ok(HandshakeSecrets {
+        client_confirm: okm[0..32].to_vec(),
+        server_confirm: okm[32..64].to_vec(),
+        keys: SessionKeys {
+            client_to_server: DirectionKeys {
+                key: encode(&okm[64..96]),
+                iv: encode(&okm[96..112]),
+            },
+            server_to_client: DirectionKeys {
+                key: encode(&okm[112..144]),
+                iv: encode(&okm[144..160]),
+            },
+        },
+    })
+}
+
+/// Next generation of traffic keys: a fresh exchange mixed with the keys being replaced,
+/// so a rekey is only as weak as both the old keys and the new ephemeral secret together.
+pub fn derive_rekey(current: &SessionKeys, shared: &[u8], epoch: u32) -> Result<SessionKeys, Box<dyn std::error::Error>> {
+    let mut chaining = general_purpose::STANDARD.decode(&current.client_to_server.key)?;
+    chaining.extend_from_slice(&general_purpose::STANDARD.decode(&current.server_to_client.key)?);
+    let mut info = REKEY_LABEL.to_vec();
+    info.extend_from_slice(&epoch.to_be_bytes());
+    let okm = hkdf_sha256(&chaining, shared, &info, 2 * (32 + 16))?;
+    let encode = |bytes: &[u8]| general_purpose::STANDARD.encode(bytes);
+    Ok(SessionKeys {
+        client_to_server: DirectionKeys {
+            key: encode(&okm[0..32]),
+            iv: encode(&okm[32..48]),
+        },
+        server_to_client: DirectionKeys {
+            key: encode(&okm[48..80]),
+            iv: encode(&okm[80..96]),
+        },
+    })
+}
+
+pub fn prove(confirm_key: &[u8], transcript: &[u8]) -> Result<String, ErrorStack> {
+    Ok(general_purpose::STANDARD.encode(hmac_sha256(confirm_key, transcript)?))
+}
+
+pub fn verify_proof(confirm_key: &[u8], transcript: &[u8], proof_b64: &str) -> bool {
+    let expected = match hmac_sha256(confirm_key, transcript) {
+        Ok(expected) => expected,
+        Err(_) => return false,
+    };
+    match general_purpose::STANDARD.decode(proof_b64) {
+        Ok(proof) => proof.len() == expected.len() && memcmp::eq(&proof, &expected),
+        Err(_) => false,
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+pub(crate) fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, ErrorStack> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(ikm)?;
+    ctx.set_hkdf_salt(salt)?;
+    ctx.add_hkdf_info(info)?;
+    let mut out = vec![0u8; len];
+    ctx.derive(Some(&mut out))?;
+    Ok(out)
+}
+
+/// Long-term Ed25519 key the server signs every handshake with, so clients that pinned
+/// its public half can tell the real endpoint from anyone who merely knows a user key.
+pub struct ServerIdentity {
+    key: PKey<Private>,
+    pub public_b64: String,
+}
+
+impl ServerIdentity {
+    /// Loads the PEM key at `path`, generating and storing a new one on first start.
+    pub fn load_or_generate(path: &Path) -> Result<ServerIdentity, Box<dyn std::error::Error>> {
+        let key = if path.exists() {
+            PKey::private_key_from_pem(&fs::read(path)?)?
+        } else {
+            let key = PKey::generate_ed25519()?;
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            // created private, never readable by others even for a moment
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options.open(path)?.write_all(&key.private_key_to_pem_pkcs8()?)?;
+            key
+        };
+        if key.id() != Id::ED25519 {
+            return Err(format!("{} is not an Ed25519 key", path.display()).into());
+        }
+        let public_b64 = general_purpose::STANDARD.encode(key.raw_public_key()?);
+        Ok(Self { key, public_b64 })
+    }
+
+    pub fn sign(&self, transcript: &[u8]) -> Result<String, ErrorStack> {
+        let mut signer = Signer::new_without_digest(&self.key)?;
+        Ok(general_purpose::STANDARD.encode(signer.sign_oneshot_to_vec(transcript)?))
+    }
+}
+
+pub fn verify_identity(public_b64: &str, transcript: &[u8], signature_b64: &str) -> bool {
+    let verify = || -> Result<bool, Box<dyn std::error::Error>> {
+        let public = general_purpose::STANDARD.decode(public_b64)?;
+        let signature = general_purpose::STANDARD.decode(signature_b64)?;
+        let public = PKey::public_key_from_raw_bytes(&public, Id::ED25519)?;
+        let mut verifier = Verifier::new_without_digest(&public)?;
+        Ok(verifier.verify_oneshot(&signature, transcript)?)
+    };
+    verify().unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoy_salts_look_like_real_ones() {
+        let secret = [7u8; 32];
+        let salt = decoy_salt(&secret, "mallory").unwrap();
+        assert_eq!(salt, decoy_salt(&secret, "mallory").unwrap());
+        assert_ne!(salt, decoy_salt(&secret, "eve").unwrap());
+        assert_ne!(salt, decoy_salt(&[8u8; 32], "mallory").unwrap());
+        assert_eq!(salt.len(), generate_salt().len());
+    }
+}
@@ -1,3 +1,8 @@
-pub mod challenge_util;
+pub mod deadline;
+pub mod handshake;
+pub mod poll;
 pub mod rand_utils;
 pub mod semaphore;
+pub mod shutdown;
+#[cfg(test)]
+pub mod testing;
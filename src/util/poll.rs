@@ -0,0 +1,24 @@
+use cfg_if::cfg_if;
+use std::os::fd::RawFd;
+
+cfg_if! {
+    if #[cfg(unix)] {
+        /// Blocks until `fd` has data to read or `timeout_ms` passes, returns whether it is readable.
+        pub fn wait_readable(fd: RawFd, timeout_ms: u16) -> bool {
+            use nix::poll::{poll, PollFd, PollFlags};
+            use std::os::fd::BorrowedFd;
+            // the caller keeps the descriptor open for the duration of the call
+            let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            match poll(&mut fds, timeout_ms) {
+                Ok(ready) => ready > 0,
+                Err(_) => false,
+            }
+        }
+    } else {
+        pub fn wait_readable(_fd: RawFd, timeout_ms: u16) -> bool {
+            std::thread::sleep(std::time::Duration::from_millis(timeout_ms as u64));
+            true
+        }
+    }
+}
@@ -39,6 +39,13 @@ impl Semaphore {
         }
     }
 
+    /// Takes a permit if one is free, without waiting for it.
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| current.checked_sub(1))
+            .is_ok()
+    }
+
     pub fn release(&self) {
         self.permits.fetch_add(1, Ordering::Release);
         // Wake one thread (if any) blocked in acquire
@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop_signal(_signal: i32) {
+    // only async-signal-safe work here, the main thread polls the flag
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_reload_signal(_signal: i32) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Routes SIGINT (Ctrl-C) and SIGTERM to [`stop_requested`] instead of killing the process
+/// outright, and SIGHUP to [`take_reload_request`].
+pub fn install_handlers() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{signal, SigHandler, Signal};
+        unsafe {
+            signal(Signal::SIGINT, SigHandler::Handler(on_stop_signal))?;
+            signal(Signal::SIGTERM, SigHandler::Handler(on_stop_signal))?;
+            signal(Signal::SIGHUP, SigHandler::Handler(on_reload_signal))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Whether SIGHUP arrived since the last call, clearing the request.
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,60 @@
+//! Helpers shared by the unit tests.
+
+use crate::vpn_config::{ConfigRole, VpnConfig};
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A path under the temp dir, unique to the test and the process, removed together with
+/// anything below it when dropped.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// A path nothing exists at yet, for a file or socket the test creates.
+    pub fn file(name: &str) -> TempPath {
+        let path = TempPath(std::env::temp_dir().join(format!("actor-{}-{}", std::process::id(), name)));
+        path.remove();
+        path
+    }
+
+    /// An empty directory.
+    pub fn dir(name: &str) -> TempPath {
+        let path = Self::file(name);
+        fs::create_dir_all(&path.0).unwrap();
+        path
+    }
+
+    fn remove(&self) {
+        if self.0.is_dir() {
+            let _ = fs::remove_dir_all(&self.0);
+        } else {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// `config/server.example.toml`, loaded and validated as a server config.
+pub fn example_config() -> VpnConfig {
+    let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/config/server.example.toml"));
+    VpnConfig::load(path, ConfigRole::Server).unwrap()
+}
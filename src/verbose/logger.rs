@@ -1,19 +1,281 @@
-use chrono::{Local};
+use crate::vpn_config::{LogConfig, LogFormat};
+use chrono::{Local, SecondsFormat};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::borrow::Cow;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, RwLock};
 
-pub struct Logger{
+static LOGGER: OnceLock<Logger> = OnceLock::new();
 
+/// Shortest run of base64 or hex characters taken for key material. Keys, proofs and
+/// ephemerals are all longer, addresses and identifiers shorter.
+const SECRET_MIN_LEN: usize = 32;
+
+struct Filter {
+    default: LevelFilter,
+    /// Longest prefix first so the most specific entry wins.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn new(config: &LogConfig) -> Self {
+        // validated with the rest of the config
+        let parse = |level: &str| level.parse().unwrap_or(LevelFilter::Info);
+        let mut modules: Vec<(String, LevelFilter)> = config
+            .modules
+            .iter()
+            .map(|(module, level)| (module.clone(), parse(level)))
+            .collect();
+        modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Self {
+            default: parse(&config.level),
+            modules,
+        }
+    }
+
+    fn level_for(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(prefix, _)| {
+                module
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .chain(std::iter::once(self.default))
+            .max()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Appends to `path`, moving it to `path.1` (and `path.1` to `path.2`, ...) once it would
+/// grow past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: u32) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                match fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1)) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+enum Sink {
+    Stderr,
+    File(RotatingFile),
+}
+
+/// Backend of the `log` macros used across the crate: filters by level per module, writes
+/// text or JSON lines to stderr or a rotating file and masks anything that looks like key
+/// material before it leaves the process.
+pub struct Logger {
+    filter: RwLock<Filter>,
+    format: LogFormat,
+    sink: Mutex<Sink>,
 }
 
 impl Logger {
-    pub fn log_message(message: &str, m_type: &str, source: &str){
-        let date = Local::now();
-        let message = format!("{} {} [{}] {}", date, source, m_type, message);
-        println!("{}", message);
+    /// Installs the logger, records logged before this are dropped.
+    pub fn init(config: &LogConfig) -> io::Result<()> {
+        let sink = match &config.file {
+            Some(path) => Sink::File(RotatingFile::open(path, config.max_file_bytes, config.max_files)?),
+            None => Sink::Stderr,
+        };
+        let filter = Filter::new(config);
+        let max_level = filter.max_level();
+        let logger = LOGGER.get_or_init(|| Logger {
+            filter: RwLock::new(filter),
+            format: config.format,
+            sink: Mutex::new(sink),
+        });
+        log::set_logger(logger).map_err(|err| io::Error::new(ErrorKind::AlreadyExists, err.to_string()))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    /// Applies new levels on reload, format and file stay as they were opened.
+    pub fn reconfigure(config: &LogConfig) {
+        if let Some(logger) = LOGGER.get() {
+            let filter = Filter::new(config);
+            log::set_max_level(filter.max_level());
+            *logger.filter.write().unwrap() = filter;
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.read().unwrap().level_for(module_of(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let module = module_of(record.target());
+        let message = record.args().to_string();
+        let message = redact(&message);
+        let time = Local::now().to_rfc3339_opts(SecondsFormat::Millis, false);
+        let line = match self.format {
+            LogFormat::Text => format!("{} {:<5} {}: {}\n", time, record.level(), module, message),
+            LogFormat::Json => format!(
+                "{}\n",
+                json!({ "ts": time, "level": record.level().as_str(), "module": module, "message": message })
+            ),
+        };
+        match &mut *self.sink.lock().unwrap() {
+            Sink::Stderr => {
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+            Sink::File(file) => {
+                if let Err(err) = file.write_line(&line) {
+                    let _ = writeln!(io::stderr(), "Failed to write to {}: {}", file.path.display(), err);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Sink::File(file) = &mut *self.sink.lock().unwrap() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// Module path without the binary's crate name, e.g. `server::admin`, as used in `log.modules`.
+fn module_of(target: &str) -> &str {
+    let krate = module_path!().split("::").next().unwrap_or_default();
+    if target == krate {
+        return "main";
     }
+    target
+        .strip_prefix(krate)
+        .and_then(|rest| rest.strip_prefix("::"))
+        .unwrap_or(target)
+}
 
-    pub fn log_error(message: &str, source: &str){
-        let date = Local::now();
-        let message = format!("{} {} [ERROR] {}", date, source, message);
-        eprintln!("{}", message);
+/// Replaces runs of base64 (mixed case and digits) or hex characters of key length with
+/// `<redacted>`, so a key that ends up in a message by mistake is not written out.
+pub fn redact(message: &str) -> Cow<'_, str> {
+    let is_token = |c: char| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=';
+    let is_secret = |run: &str| {
+        run.len() >= SECRET_MIN_LEN
+            && (run.chars().all(|c| c.is_ascii_hexdigit())
+                || (run.chars().any(|c| c.is_ascii_uppercase())
+                    && run.chars().any(|c| c.is_ascii_lowercase())
+                    && run.chars().any(|c| c.is_ascii_digit())))
+    };
+    let mut result = String::new();
+    let mut copied = 0;
+    let mut start = None;
+    for (index, c) in message.char_indices().chain(std::iter::once((message.len(), ' '))) {
+        match (is_token(c), start) {
+            (true, None) => start = Some(index),
+            (false, Some(from)) => {
+                if is_secret(&message[from..index]) {
+                    result.push_str(&message[copied..from]);
+                    result.push_str("<redacted>");
+                    copied = index;
+                }
+                start = None;
+            }
+            _ => {}
+        }
     }
-}
\ No newline at end of file
+    if copied == 0 {
+        return Cow::Borrowed(message);
+    }
+    result.push_str(&message[copied..]);
+    Cow::Owned(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn redacts_keys_but_keeps_paths_and_addresses() {
+        let message = "key Zm9vYmFyYmF6cXV4MTIzNDU2Nzg5MGFiY2RlZg+/= for alice at 10.0.8.2 in /var/lib/actor/users.toml";
+        assert_eq!(
+            redact(message),
+            "key <redacted> for alice at 10.0.8.2 in /var/lib/actor/users.toml"
+        );
+        let hex = "proof 0123456789abcdef0123456789abcdef";
+        assert_eq!(redact(hex), "proof <redacted>");
+        assert!(matches!(redact("Session closed: user alice"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn most_specific_module_wins() {
+        let config = LogConfig {
+            level: "warn".to_string(),
+            modules: BTreeMap::from([
+                ("server".to_string(), "info".to_string()),
+                ("server::admin".to_string(), "trace".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let filter = Filter::new(&config);
+        assert_eq!(filter.level_for("server::admin"), LevelFilter::Trace);
+        assert_eq!(filter.level_for("server::lease_store"), LevelFilter::Info);
+        assert_eq!(filter.level_for("server_extra"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("operational::packet_router"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+}
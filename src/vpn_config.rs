@@ -1,8 +1,18 @@
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use crate::handlers::actor_structure_type::is_search_domain;
+use crate::operational::data_pack;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use tfserver::util::data_cipher::EncryptionType;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VpnConfig {
+    #[serde(default)]
     pub key: String,
     pub hostname: String,
     pub encryption_type: EncryptionType,
@@ -15,4 +25,696 @@ pub struct VpnConfig {
     pub mtu_min: u32,
     pub mtu_max: u32,
     pub resyncer_timeout_ms: u32,
+    /// Largest decrypted frame accepted from the peer. Frames are packed to the smaller of
+    /// this and the peer's, exchanged on registration.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    #[serde(default)]
+    pub rekey: RekeyConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub client: ClientConfig,
+}
+
+fn default_max_frame_size() -> usize {
+    1 << 20
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigRole {
+    Server,
+    Client,
+}
+
+/// When the client rotates the session traffic keys, whichever limit is hit first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RekeyConfig {
+    pub after_bytes: u64,
+    pub after_secs: u64,
+    /// How long frames sealed with the previous keys are still accepted after a rotation.
+    pub overlap_ms: u64,
+}
+
+impl Default for RekeyConfig {
+    fn default() -> Self {
+        Self {
+            after_bytes: 1 << 30,
+            after_secs: 3600,
+            overlap_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line with `ts`, `level`, `module` and `message`.
+    Json,
+}
+
+/// Where log records go and which of them are kept. Levels are `error`, `warn`, `info`,
+/// `debug`, `trace` or `off`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: String,
+    /// Levels for single modules, overriding `level`, e.g. `"server::admin" = "debug"`.
+    pub modules: BTreeMap<String, String>,
+    pub format: LogFormat,
+    /// Appends to this file instead of writing to stderr.
+    pub file: Option<PathBuf>,
+    /// Size at which the file is rotated to `<file>.1`, shifting older ones up.
+    pub max_file_bytes: u64,
+    /// Rotated files kept besides the current one.
+    pub max_files: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            format: LogFormat::Text,
+            file: None,
+            max_file_bytes: 10 << 20,
+            max_files: 5,
+        }
+    }
+}
+
+/// Prometheus exporter, off unless `listen` is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Local address serving `/metrics`, e.g. `127.0.0.1:9100`.
+    pub listen: Option<String>,
+    /// Adds byte counters labelled with user and address for every session, one series per
+    /// session, so leave it off on servers with many clients.
+    pub per_session: bool,
+}
+
+/// Settings only `actor-server` reads, so several instances can share one host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Addresses the WebSocket listener binds to, `hostname:port` when empty.
+    pub bind_addresses: Vec<String>,
+    pub tun_name: String,
+    /// Server address on the TUN device together with the client subnet, e.g. `10.0.8.1/24`.
+    pub ipv4_cidr: Ipv4Net,
+    /// Server address inside the /64 handed out to clients.
+    pub ipv6_prefix: Ipv6Net,
+    /// Size of the pool serving handshakes in `TcpServer`.
+    pub handshake_workers: usize,
+    /// Size of the pool pumping client streams in `ProxyServerInternal`.
+    pub proxy_workers: usize,
+    /// Credentials database managed with `actor-server user ...`, also holding static address reservations.
+    pub users_file: PathBuf,
+    /// Where dynamic address leases are kept across restarts.
+    pub leases_file: PathBuf,
+    /// How long after a session ends its addresses stay reserved for the same user.
+    pub lease_expiry_secs: u64,
+    /// Ed25519 key signing handshakes, generated on first start. Clients pin its public half.
+    pub identity_key_file: Option<PathBuf>,
+    /// Sessions that sent nothing for this long are considered dead and torn down.
+    pub idle_timeout_secs: u64,
+    /// Whether clients may reach each other's tunnel addresses.
+    pub client_to_client: bool,
+    /// Interface client traffic leaves through, the one of the IPv4 default route when unset.
+    pub egress_interface: Option<String>,
+    pub firewall: FirewallBackend,
+    /// Unix socket of the JSON admin API, only accessible to the server's user. Disabled when unset.
+    pub admin_socket: Option<PathBuf>,
+    /// Records firewall, address and sysctl changes so they are reverted on shutdown or by `cleanup`.
+    pub journal_file: PathBuf,
+    pub push: PushConfig,
+}
+
+/// Network settings sent to every client on registration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    pub dns_servers: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+    /// MTU clients set on their TUN device.
+    pub mtu: u32,
+    /// Networks clients route through the tunnel.
+    pub routes: Vec<IpNet>,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            dns_servers: Vec::new(),
+            search_domains: Vec::new(),
+            mtu: 1400,
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// Settings only `actor-client` reads, `key` holds the password of this user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub username: String,
+    /// Base64 Ed25519 public key the server must sign the handshake with, as printed by `actor-server`.
+    pub server_public_key: Option<String>,
+    /// First delay before reconnecting after the connection drops, doubled on every failed attempt.
+    pub reconnect_min_ms: u64,
+    pub reconnect_max_ms: u64,
+    /// How long a connection attempt may take to finish the handshake before it counts as failed.
+    pub handshake_timeout_ms: u64,
+    pub routing: RoutingConfig,
+    /// Records route and resolver changes so they are reverted on shutdown or by `cleanup`.
+    pub journal_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelMode {
+    /// Everything goes through the tunnel.
+    Full,
+    /// Only `include` and, if enabled, the routes pushed by the server.
+    Split,
+}
+
+/// What the server installs its forwarding and NAT rules with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallBackend {
+    /// Rules appended to the FORWARD and nat POSTROUTING chains.
+    Iptables,
+    /// A dedicated `inet actor` table, replaced as a whole on start and deleted on shutdown.
+    Nftables,
+}
+
+/// Which traffic the client sends through the tunnel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoutingConfig {
+    pub mode: TunnelMode,
+    pub include: Vec<IpNet>,
+    /// Networks that never go through the tunnel, e.g. the office LAN or local Docker bridges.
+    pub exclude: Vec<IpNet>,
+    pub use_pushed_routes: bool,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            mode: TunnelMode::Full,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            use_pushed_routes: true,
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            username: String::new(),
+            server_public_key: None,
+            reconnect_min_ms: 1000,
+            reconnect_max_ms: 60000,
+            handshake_timeout_ms: 10000,
+            routing: RoutingConfig::default(),
+            journal_file: PathBuf::from("client-journal.toml"),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addresses: Vec::new(),
+            tun_name: "tun0".to_string(),
+            ipv4_cidr: Ipv4Net::new(Ipv4Addr::new(10, 0, 8, 1), 24).unwrap(),
+            ipv6_prefix: Ipv6Net::new(Ipv6Addr::new(0x2001, 0xdb8, 0x3333, 0x4444, 0, 0, 0, 1), 64).unwrap(),
+            handshake_workers: 5,
+            proxy_workers: 15,
+            users_file: PathBuf::from("users.toml"),
+            leases_file: PathBuf::from("leases.toml"),
+            lease_expiry_secs: 86400,
+            identity_key_file: None,
+            idle_timeout_secs: 60,
+            client_to_client: true,
+            egress_interface: None,
+            firewall: FirewallBackend::Iptables,
+            admin_socket: Some(PathBuf::from("admin.sock")),
+            journal_file: PathBuf::from("server-journal.toml"),
+            push: PushConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigIssue {
+    pub field: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Invalid(Vec<ConfigIssue>),
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "failed to read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "failed to parse {}: {}", path.display(), err),
+            ConfigError::Invalid(issues) => {
+                write!(f, "invalid configuration:")?;
+                for issue in issues {
+                    write!(f, "\n  {}", issue)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl VpnConfig {
+    /// Reads the config from `path`, picking JSON for `.json` files and TOML for `.toml` ones
+    /// or those without an extension, and runs [`VpnConfig::validate`] on the result.
+    pub fn load(path: &Path, role: ConfigRole) -> Result<VpnConfig, ConfigError> {
+        let is_json = match path.extension().and_then(|ext| ext.to_str()) {
+            None => false,
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => false,
+            Some(ext) if ext.eq_ignore_ascii_case("json") => true,
+            Some(ext) => {
+                let message = format!("unsupported extension .{}, expected .toml or .json", ext);
+                return Err(ConfigError::Parse(path.to_path_buf(), message));
+            }
+        };
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let config: VpnConfig = if is_json {
+            serde_json::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e.to_string()))?
+        } else {
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e.to_string()))?
+        };
+        config.validate(role)?;
+        Ok(config)
+    }
+
+    /// Takes a config read again on SIGHUP, returning it with the settings a running server
+    /// cannot change put back to their current values, along with the names of those that
+    /// differed. They are baked into the listener, the TUN device, the address pool, the
+    /// lease store or the firewall and wait for a restart.
+    pub fn server_reload(&self, mut reloaded: VpnConfig) -> (VpnConfig, Vec<&'static str>) {
+        let mut pending = Vec::new();
+        macro_rules! keep {
+            ($name:literal, $($field:ident).+) => {
+                if reloaded.$($field).+ != self.$($field).+ {
+                    pending.push($name);
+                    reloaded.$($field).+ = self.$($field).+.clone();
+                }
+            };
+        }
+        keep!("hostname", hostname);
+        keep!("port", port);
+        keep!("max_packets_in_flight", max_packets_in_flight);
+        keep!("server.bind_addresses", server.bind_addresses);
+        keep!("server.tun_name", server.tun_name);
+        keep!("server.ipv4_cidr", server.ipv4_cidr);
+        keep!("server.ipv6_prefix", server.ipv6_prefix);
+        keep!("server.handshake_workers", server.handshake_workers);
+        keep!("server.proxy_workers", server.proxy_workers);
+        keep!("server.users_file", server.users_file);
+        keep!("server.leases_file", server.leases_file);
+        keep!("server.lease_expiry_secs", server.lease_expiry_secs);
+        keep!("server.identity_key_file", server.identity_key_file);
+        keep!("server.egress_interface", server.egress_interface);
+        keep!("server.firewall", server.firewall);
+        keep!("server.journal_file", server.journal_file);
+        keep!("server.admin_socket", server.admin_socket);
+        keep!("metrics.listen", metrics.listen);
+        keep!("metrics.per_session", metrics.per_session);
+        keep!("log.format", log.format);
+        keep!("log.file", log.file);
+        keep!("log.max_file_bytes", log.max_file_bytes);
+        keep!("log.max_files", log.max_files);
+        (reloaded, pending)
+    }
+
+    /// Checks values that would otherwise blow up later at runtime, reporting every problem at once.
+    pub fn validate(&self, role: ConfigRole) -> Result<(), ConfigError> {
+        let mut issues = Vec::new();
+        let mut issue = |field: &'static str, message: String| issues.push(ConfigIssue { field, message });
+
+        if self.hostname.is_empty() {
+            issue("hostname", "must not be empty".to_string());
+        }
+        if self.port == 0 {
+            issue("port", "must not be 0".to_string());
+        }
+        // DataPack::post_process_data draws from half-open ranges, an empty one panics
+        if self.garbage_packet_min_size >= self.garbage_packet_max_size {
+            issue(
+                "garbage_packet_min_size",
+                format!(
+                    "must be lower than garbage_packet_max_size ({} >= {})",
+                    self.garbage_packet_min_size, self.garbage_packet_max_size
+                ),
+            );
+        }
+        if self.min_garbage_packets_amount >= self.max_garbage_packets_amount {
+            issue(
+                "min_garbage_packets_amount",
+                format!(
+                    "must be lower than max_garbage_packets_amount ({} >= {})",
+                    self.min_garbage_packets_amount, self.max_garbage_packets_amount
+                ),
+            );
+        }
+        let min_frame_size = data_pack::min_frame_size(self);
+        if self.max_frame_size < min_frame_size {
+            issue(
+                "max_frame_size",
+                format!(
+                    "must leave room for the largest packet next to the garbage packets, at least {}",
+                    min_frame_size
+                ),
+            );
+        }
+        if self.max_packets_in_flight == 0 {
+            issue("max_packets_in_flight", "must be greater than 0".to_string());
+        }
+        if self.mtu_min == 0 {
+            issue("mtu_min", "must be greater than 0".to_string());
+        }
+        if self.mtu_min > self.mtu_max {
+            issue(
+                "mtu_min",
+                format!("must not exceed mtu_max ({} > {})", self.mtu_min, self.mtu_max),
+            );
+        }
+
+        if self.rekey.after_bytes == 0 {
+            issue("rekey.after_bytes", "must be greater than 0".to_string());
+        }
+        if self.rekey.after_secs == 0 {
+            issue("rekey.after_secs", "must be greater than 0".to_string());
+        }
+        for level in std::iter::once(&self.log.level).chain(self.log.modules.values()) {
+            if level.parse::<LevelFilter>().is_err() {
+                issue("log", format!("unknown level {:?}", level));
+            }
+        }
+        if self.log.file.is_some() && self.log.max_file_bytes == 0 {
+            issue("log.max_file_bytes", "must be greater than 0".to_string());
+        }
+
+        match role {
+            ConfigRole::Server => self.server.validate(&mut issues),
+            ConfigRole::Client => self.validate_client(&mut issues),
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(issues))
+        }
+    }
+
+    fn validate_client(&self, issues: &mut Vec<ConfigIssue>) {
+        if self.key.is_empty() {
+            issues.push(ConfigIssue { field: "key", message: "must not be empty".to_string() });
+        }
+        if self.client.username.is_empty() {
+            issues.push(ConfigIssue { field: "client.username", message: "must not be empty".to_string() });
+        }
+        if self.client.reconnect_min_ms == 0 {
+            issues.push(ConfigIssue { field: "client.reconnect_min_ms", message: "must be greater than 0".to_string() });
+        }
+        if self.client.reconnect_min_ms > self.client.reconnect_max_ms {
+            issues.push(ConfigIssue {
+                field: "client.reconnect_min_ms",
+                message: format!(
+                    "must not exceed client.reconnect_max_ms ({} > {})",
+                    self.client.reconnect_min_ms, self.client.reconnect_max_ms
+                ),
+            });
+        }
+        if self.client.handshake_timeout_ms == 0 {
+            issues.push(ConfigIssue { field: "client.handshake_timeout_ms", message: "must be greater than 0".to_string() });
+        }
+        let routing = &self.client.routing;
+        if routing.mode == TunnelMode::Split && routing.include.is_empty() && !routing.use_pushed_routes {
+            issues.push(ConfigIssue {
+                field: "client.routing.include",
+                message: "split mode needs include networks or use_pushed_routes".to_string(),
+            });
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn bind_addresses(&self, config: &VpnConfig) -> Vec<String> {
+        if self.bind_addresses.is_empty() {
+            vec![format!("{}:{}", config.hostname, config.port)]
+        } else {
+            self.bind_addresses.clone()
+        }
+    }
+
+    fn validate(&self, issues: &mut Vec<ConfigIssue>) {
+        let mut issue = |field: &'static str, message: String| issues.push(ConfigIssue { field, message });
+
+        // IFNAMSIZ is 16 including the trailing NUL
+        if self.tun_name.is_empty() || self.tun_name.len() > 15 {
+            issue("server.tun_name", format!("must be 1 to 15 characters long, got {:?}", self.tun_name));
+        }
+        if self.ipv4_cidr.prefix_len() > 30 {
+            issue(
+                "server.ipv4_cidr",
+                format!("prefix /{} leaves no room for clients", self.ipv4_cidr.prefix_len()),
+            );
+        } else if self.ipv4_cidr.addr() == self.ipv4_cidr.network()
+            || self.ipv4_cidr.addr() == self.ipv4_cidr.broadcast()
+        {
+            issue(
+                "server.ipv4_cidr",
+                format!("{} is not a host address of its subnet", self.ipv4_cidr.addr()),
+            );
+        }
+        if self.ipv6_prefix.prefix_len() != 64 {
+            issue(
+                "server.ipv6_prefix",
+                format!("must be a /64, got /{}", self.ipv6_prefix.prefix_len()),
+            );
+        }
+        if self.handshake_workers == 0 {
+            issue("server.handshake_workers", "must be greater than 0".to_string());
+        }
+        if self.proxy_workers == 0 {
+            issue("server.proxy_workers", "must be greater than 0".to_string());
+        }
+        if self.idle_timeout_secs == 0 {
+            issue("server.idle_timeout_secs", "must be greater than 0".to_string());
+        }
+        // IPv6 needs at least 1280 on every link
+        if self.push.mtu < 1280 || self.push.mtu > 65535 {
+            issue("server.push.mtu", format!("must be between 1280 and 65535, got {}", self.push.mtu));
+        }
+        for domain in self.push.search_domains.iter().filter(|domain| !is_search_domain(domain)) {
+            issue("server.push.search_domains", format!("{:?} is not a hostname", domain));
+        }
+    }
+}
+
+/// Returns the value following `--config` (or given as `--config=<path>`) in `args`.
+pub fn config_path_from_args(args: &[String]) -> Option<PathBuf> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            return iter.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// The command and its arguments, everything in `args` after the program name but `--config`
+/// and its path, e.g. `["user", "add", "alice"]`. Taken by position so a config path that
+/// happens to be called `user` is not mistaken for the command.
+pub fn command_from_args(args: &[String]) -> Vec<String> {
+    let mut command = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            iter.next();
+        } else if !arg.starts_with("--config=") {
+            command.push(arg.clone());
+        }
+    }
+    command
+}
+
+/// Reads the config named by `--config` again, for reloading on SIGHUP.
+pub fn reload_from_args(role: ConfigRole) -> Result<VpnConfig, ConfigError> {
+    let args: Vec<String> = std::env::args().collect();
+    // the process only got this far because the path was there at startup
+    let path = config_path_from_args(&args).unwrap_or_default();
+    VpnConfig::load(&path, role)
+}
+
+/// Loads the config named by `--config` on the command line, exiting with a report on failure.
+pub fn load_from_args_or_exit(binary_name: &str, role: ConfigRole) -> VpnConfig {
+    let args: Vec<String> = std::env::args().collect();
+    let path = match config_path_from_args(&args) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: {} --config <path>", binary_name);
+            std::process::exit(2);
+        }
+    };
+    match VpnConfig::load(&path, role) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::{example_config, TempPath};
+
+    /// The fields `validate` complains about, failing the test if it accepts the config.
+    fn rejected(config: &VpnConfig) -> Vec<&'static str> {
+        match config.validate(ConfigRole::Server) {
+            Err(ConfigError::Invalid(issues)) => issues.iter().map(|issue| issue.field).collect(),
+            other => panic!("expected the config to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_a_missing_file() {
+        let path = TempPath::file("missing.toml");
+        assert!(matches!(VpnConfig::load(&path, ConfigRole::Server), Err(ConfigError::Io(..))));
+    }
+
+    #[test]
+    fn rejects_an_unknown_extension() {
+        let path = TempPath::file("config.yaml");
+        fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/config/server.example.toml"), &path).unwrap();
+        match VpnConfig::load(&path, ConfigRole::Server) {
+            Err(ConfigError::Parse(_, message)) => assert!(message.contains(".yaml"), "{}", message),
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_subnets_without_room_for_clients() {
+        let mut config = example_config();
+        config.server.ipv4_cidr = "10.8.0.1/31".parse().unwrap();
+        assert_eq!(rejected(&config), ["server.ipv4_cidr"]);
+        config.server.ipv4_cidr = "10.8.0.0/24".parse().unwrap();
+        assert_eq!(rejected(&config), ["server.ipv4_cidr"]);
+        config.server.ipv4_cidr = "10.8.0.1/24".parse().unwrap();
+        config.server.ipv6_prefix = "fd00:8::1/48".parse().unwrap();
+        assert_eq!(rejected(&config), ["server.ipv6_prefix"]);
+    }
+
+    #[test]
+    fn rejects_empty_worker_pools() {
+        let mut config = example_config();
+        config.server.handshake_workers = 0;
+        config.server.proxy_workers = 0;
+        assert_eq!(rejected(&config), ["server.handshake_workers", "server.proxy_workers"]);
+    }
+
+    #[test]
+    fn rejects_unknown_log_levels() {
+        let mut config = example_config();
+        config.log.level = "loud".to_string();
+        assert_eq!(rejected(&config), ["log"]);
+        config.log.level = "info".to_string();
+        config.log.modules.insert("actor::server".to_string(), "chatty".to_string());
+        assert_eq!(rejected(&config), ["log"]);
+    }
+
+    #[test]
+    fn rejects_search_domains_with_spaces() {
+        let mut config = example_config();
+        config.server.push.search_domains = vec!["corp.example".to_string(), "not a domain".to_string()];
+        assert_eq!(rejected(&config), ["server.push.search_domains"]);
+    }
+
+    #[test]
+    fn search_domains_follow_the_clients_rule() {
+        let mut config = example_config();
+        config.server.push.search_domains = vec![
+            "corp.example".to_string(),
+            "~corp.example".to_string(),
+            "corp.example.".to_string(),
+        ];
+        config.validate(ConfigRole::Server).unwrap();
+        for domain in ["-corp.example", "corp..example", "corp_example", "corp-.example", "~", ""] {
+            config.server.push.search_domains = vec![domain.to_string()];
+            assert_eq!(rejected(&config), ["server.push.search_domains"], "{:?}", domain);
+        }
+    }
+
+    #[test]
+    fn reload_keeps_what_needs_a_restart() {
+        let current = example_config();
+        let mut reloaded = current.clone();
+        reloaded.port += 1;
+        reloaded.server.ipv4_cidr = "10.9.0.1/24".parse().unwrap();
+        reloaded.server.idle_timeout_secs += 60;
+        reloaded.server.push.mtu = 1300;
+
+        let (applied, pending) = current.server_reload(reloaded);
+        assert_eq!(pending, ["port", "server.ipv4_cidr"]);
+        assert_eq!(applied.port, current.port);
+        assert_eq!(applied.server.ipv4_cidr, current.server.ipv4_cidr);
+        assert_eq!(applied.server.idle_timeout_secs, current.server.idle_timeout_secs + 60);
+        assert_eq!(applied.server.push.mtu, 1300);
+    }
+
+    #[test]
+    fn command_is_taken_by_position() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        assert_eq!(command_from_args(&args(&["actor-server", "--config", "user"])), Vec::<String>::new());
+        assert_eq!(command_from_args(&args(&["actor-server", "--config=cleanup"])), Vec::<String>::new());
+        assert_eq!(
+            command_from_args(&args(&["actor-server", "--config", "user", "user", "add", "cleanup"])),
+            args(&["user", "add", "cleanup"])
+        );
+        assert_eq!(command_from_args(&args(&["actor-client", "cleanup", "--config", "c.toml"])), args(&["cleanup"]));
+    }
+
+    #[test]
+    fn reload_of_the_same_config_changes_nothing() {
+        let current = example_config();
+        let (applied, pending) = current.server_reload(current.clone());
+        assert!(pending.is_empty());
+        assert_eq!(applied.server.bind_addresses, current.server.bind_addresses);
+    }
 }